@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+struct Config {
+    filter: Option<String>,
+    runs: usize,
+    baseline: Option<PathBuf>,
+    save: Option<PathBuf>,
+    /// How many percent slower than the baseline a benchmark may get before
+    /// it counts as a regression.
+    threshold: f64,
+}
+
+struct Benchmark {
+    /// The file name without `.lox`, which is also its key in a baseline.
+    name: String,
+    path: PathBuf,
+}
+
+impl Benchmark {
+    /// Runs the benchmark `runs` times and returns the fastest wall time,
+    /// which is the one least disturbed by whatever else the machine is
+    /// doing. The time includes starting the interpreter.
+    fn run(&self, interpreter: &Path, runs: usize) -> Result<Duration, String> {
+        (0..runs).try_fold(Duration::MAX, |best, _| {
+            let start = Instant::now();
+            let output = Command::new(interpreter)
+                .arg(&self.path)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .output()
+                .map_err(|e| format!("Failed to execute interpreter: {}", e))?;
+            let elapsed = start.elapsed();
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!(
+                    "Exited with code {}: {}",
+                    output.status.code().unwrap_or(-1),
+                    stderr.lines().next().unwrap_or("")
+                ));
+            }
+            Ok(best.min(elapsed))
+        })
+    }
+}
+
+fn find_benchmarks(dir: &Path) -> Vec<Benchmark> {
+    let mut benchmarks: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|s| s.to_str()) == Some("lox"))
+        .filter_map(|path| {
+            let name = path.file_stem()?.to_str()?.to_string();
+            Some(Benchmark { name, path })
+        })
+        .collect();
+
+    benchmarks.sort_by(|a, b| a.name.cmp(&b.name));
+    benchmarks
+}
+
+/// Reads a baseline written by `save_baseline`: one JSON object mapping each
+/// benchmark's name to its time in seconds.
+fn load_baseline(path: &Path) -> Result<HashMap<String, f64>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read baseline '{}': {}", path.display(), e))?;
+    parse_baseline(&text).ok_or_else(|| {
+        format!(
+            "Baseline '{}' is not a JSON object of times.",
+            path.display()
+        )
+    })
+}
+
+fn parse_baseline(text: &str) -> Option<HashMap<String, f64>> {
+    let mut times = HashMap::new();
+    let mut rest = text.trim().strip_prefix('{')?.trim_start();
+
+    if let Some(after) = rest.strip_prefix('}') {
+        return after.trim().is_empty().then_some(times);
+    }
+
+    loop {
+        let (name, after_name) = parse_json_string(rest)?;
+        rest = after_name.trim_start().strip_prefix(':')?.trim_start();
+
+        let end = rest
+            .find(|c: char| c == ',' || c == '}' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        times.insert(name, rest[..end].parse().ok()?);
+        rest = rest[end..].trim_start();
+
+        match rest.chars().next()? {
+            ',' => rest = rest[1..].trim_start(),
+            '}' => return rest[1..].trim().is_empty().then_some(times),
+            _ => return None,
+        }
+    }
+}
+
+/// Parses the string literal at the start of `text`, returning its value
+/// and what follows it.
+fn parse_json_string(text: &str) -> Option<(String, &str)> {
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    let mut value = String::new();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &text[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                escaped => value.push(escaped),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+fn save_baseline(
+    path: &Path,
+    results: &[(&Benchmark, Result<Duration, String>)],
+) -> Result<(), String> {
+    let entries: Vec<String> = results
+        .iter()
+        .filter_map(|(benchmark, result)| {
+            let time = result.as_ref().ok()?;
+            Some(format!(
+                "  {}: {:.6}",
+                json_string(&benchmark.name),
+                time.as_secs_f64()
+            ))
+        })
+        .collect();
+
+    fs::write(path, format!("{{\n{}\n}}\n", entries.join(",\n")))
+        .map_err(|e| format!("Could not write baseline '{}': {}", path.display(), e))
+}
+
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn print_usage() {
+    eprintln!("Lox Benchmark Runner");
+    eprintln!();
+    eprintln!("Usage: benchmark_runner [OPTIONS] <interpreter_path> <benchmark_directory>");
+    eprintln!();
+    eprintln!("Arguments:");
+    eprintln!("  <interpreter_path>     Path to the Lox interpreter executable");
+    eprintln!("  <benchmark_directory>  Directory of .lox benchmarks, such as test/benchmark");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  -f, --filter <text>    Only run benchmarks matching filter");
+    eprintln!("  -r, --runs <n>         Keep the best of n runs (default: 1)");
+    eprintln!("  --baseline <file>      Compare against times saved with --save");
+    eprintln!("  --save <file>          Save the times as a baseline");
+    eprintln!("  --threshold <percent>  Slowdown that counts as a regression (default: 10)");
+    eprintln!("  -h, --help             Show this help message");
+    eprintln!();
+    eprintln!("Examples:");
+    eprintln!("  benchmark_runner --save baseline.json rlox test/benchmark");
+    eprintln!("  benchmark_runner --baseline baseline.json rlox test/benchmark");
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let mut config = Config {
+        filter: None,
+        runs: 1,
+        baseline: None,
+        save: None,
+        threshold: 10.0,
+    };
+
+    let mut interpreter_path = None;
+    let mut benchmark_dir_path = None;
+    let mut i = 1;
+
+    while i < args.len() {
+        match args[i].as_str() {
+            "-h" | "--help" => {
+                print_usage();
+                std::process::exit(0);
+            }
+            "-f" | "--filter" => {
+                i += 1;
+                if i >= args.len() {
+                    eprintln!("Error: --filter requires an argument");
+                    std::process::exit(1);
+                }
+                config.filter = Some(args[i].clone());
+            }
+            "-r" | "--runs" => {
+                i += 1;
+                match args.get(i).and_then(|arg| arg.parse().ok()) {
+                    Some(runs) if runs > 0 => config.runs = runs,
+                    _ => {
+                        eprintln!("Error: --runs requires a positive number");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--baseline" | "--save" => {
+                let option = args[i].clone();
+                i += 1;
+                let Some(path) = args.get(i) else {
+                    eprintln!("Error: {} requires a file", option);
+                    std::process::exit(1);
+                };
+                if option == "--baseline" {
+                    config.baseline = Some(PathBuf::from(path));
+                } else {
+                    config.save = Some(PathBuf::from(path));
+                }
+            }
+            "--threshold" => {
+                i += 1;
+                match args.get(i).and_then(|arg| arg.parse().ok()) {
+                    Some(threshold) if threshold >= 0.0 => config.threshold = threshold,
+                    _ => {
+                        eprintln!("Error: --threshold requires a non-negative number");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            arg => {
+                if interpreter_path.is_none() {
+                    interpreter_path = Some(arg.to_string());
+                } else if benchmark_dir_path.is_none() {
+                    benchmark_dir_path = Some(arg.to_string());
+                } else {
+                    eprintln!("Error: Unexpected argument '{}'", arg);
+                    print_usage();
+                    std::process::exit(1);
+                }
+            }
+        }
+        i += 1;
+    }
+
+    let (Some(interpreter), Some(benchmark_dir)) = (interpreter_path, benchmark_dir_path) else {
+        eprintln!("Error: Missing interpreter path or benchmark directory");
+        print_usage();
+        std::process::exit(1);
+    };
+    let interpreter = PathBuf::from(interpreter);
+    let benchmark_dir = PathBuf::from(benchmark_dir);
+
+    if !interpreter.exists() {
+        eprintln!("Error: Interpreter '{}' not found", interpreter.display());
+        std::process::exit(1);
+    }
+
+    if !benchmark_dir.is_dir() {
+        eprintln!(
+            "Error: Benchmark directory '{}' not found",
+            benchmark_dir.display()
+        );
+        std::process::exit(1);
+    }
+
+    // Load the baseline first so a bad file fails before anything runs.
+    let baseline = match config.baseline.as_deref().map(load_baseline).transpose() {
+        Ok(baseline) => baseline,
+        Err(message) => {
+            eprintln!("Error: {}", message);
+            std::process::exit(1);
+        }
+    };
+
+    let mut benchmarks = find_benchmarks(&benchmark_dir);
+    if let Some(ref filter) = config.filter {
+        benchmarks.retain(|benchmark| benchmark.name.contains(filter.as_str()));
+    }
+
+    println!("⏱  Lox Benchmarks");
+    println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    println!("Interpreter: {}", interpreter.display());
+    println!("Benchmark directory: {}", benchmark_dir.display());
+    if let Some(ref path) = config.baseline {
+        println!(
+            "Baseline: {} (regression threshold {}%)",
+            path.display(),
+            config.threshold
+        );
+    }
+    println!();
+    println!(
+        "{:<20} {:>10} {:>10} {:>9}",
+        "Benchmark", "Time", "Baseline", "Change"
+    );
+
+    let mut failed = 0;
+    let mut regressions = 0;
+    // Benchmarks run one at a time so they don't compete for the CPU.
+    let results: Vec<_> = benchmarks
+        .iter()
+        .map(|benchmark| {
+            let result = benchmark.run(&interpreter, config.runs);
+            let time = match &result {
+                Ok(time) => time.as_secs_f64(),
+                Err(reason) => {
+                    failed += 1;
+                    println!("{:<20} ✗ {}", benchmark.name, reason);
+                    return (benchmark, result);
+                }
+            };
+
+            let base = baseline
+                .as_ref()
+                .and_then(|baseline| baseline.get(&benchmark.name));
+            let (base_column, change_column, verdict) = match base {
+                Some(&base) if base > 0.0 => {
+                    let change = (time - base) / base * 100.0;
+                    let verdict = if change > config.threshold {
+                        regressions += 1;
+                        "  ✗ slower"
+                    } else if change < -config.threshold {
+                        "  ✓ faster"
+                    } else {
+                        ""
+                    };
+                    (format!("{:.3}s", base), format!("{:+.1}%", change), verdict)
+                }
+                _ => ("-".to_string(), "-".to_string(), ""),
+            };
+            println!(
+                "{:<20} {:>10} {:>10} {:>9}{}",
+                benchmark.name,
+                format!("{:.3}s", time),
+                base_column,
+                change_column,
+                verdict
+            );
+            (benchmark, result)
+        })
+        .collect();
+
+    println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    println!("Benchmarks: {}", results.len());
+    if failed > 0 {
+        println!("✗ Failed: {}", failed);
+    }
+    if baseline.is_some() {
+        println!("Regressions beyond {}%: {}", config.threshold, regressions);
+    }
+
+    if let Some(ref path) = config.save {
+        if let Err(message) = save_baseline(path, &results) {
+            eprintln!("Error: {}", message);
+            std::process::exit(1);
+        }
+        println!("Saved baseline to {}", path.display());
+    }
+
+    if failed > 0 || regressions > 0 {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::value::{List, ListIterator, Map, Obj, StringInterner, Value};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+/// A method implemented in Rust on a built-in type, which has no class to
+/// look methods up in: its name, arity and implementation. The
+/// implementation is only called with `arity` arguments, and gets the
+/// interner for any strings it returns.
+type Method<T> = (
+    &'static str,
+    usize,
+    fn(&T, &[Value], &mut StringInterner) -> Result<Value, String>,
+);
+
+const LIST_METHODS: &[Method<Rc<List>>] = &[
+    ("push", 1, list_push),
+    ("pop", 0, list_pop),
+    ("get", 1, list_get),
+    ("set", 2, list_set),
+    ("length", 0, list_length),
+    ("iter", 0, list_iter),
+];
+
+const MAP_METHODS: &[Method<Map>] = &[("iter", 0, map_iter)];
+
+/// The iterator protocol `for (x in ...)` uses, as a user class would
+/// implement it: `hasNext()` says whether `next()` has an element to return.
+const ITERATOR_METHODS: &[Method<ListIterator>] = &[
+    ("hasNext", 0, iterator_has_next),
+    ("next", 0, iterator_next),
+];
+
+/// Methods of integers and floating-point numbers alike. Each is called
+/// with a `Value::Int` or `Value::Number` receiver.
+const NUMBER_METHODS: &[Method<Value>] = &[
+    ("floor", 0, number_floor),
+    ("ceil", 0, number_ceil),
+    ("round", 0, number_round),
+    ("abs", 0, number_abs),
+    ("toString", 0, number_to_string),
+];
+
+/// Calls the built-in method `name` of `receiver` with `args`. Returns
+/// `None` when `receiver`'s type has no built-in methods at all, so the
+/// caller can fall back to its usual lookup.
+pub fn builtin_method(
+    receiver: &Value,
+    name: &str,
+    args: &[Value],
+    interner: &mut StringInterner,
+) -> Option<Result<Value, String>> {
+    match receiver {
+        Value::Int(_) | Value::Number(_) => Some(dispatch(
+            NUMBER_METHODS,
+            "number",
+            receiver,
+            name,
+            args,
+            interner,
+        )),
+        Value::Obj(obj) => match &**obj {
+            Obj::List(list) => Some(dispatch(LIST_METHODS, "list", list, name, args, interner)),
+            Obj::Map(map) => Some(dispatch(MAP_METHODS, "map", map, name, args, interner)),
+            Obj::ListIterator(iterator) => Some(dispatch(
+                ITERATOR_METHODS,
+                "iterator",
+                iterator,
+                name,
+                args,
+                interner,
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `receiver` is of a type that `builtin_method` dispatches on.
+pub fn has_builtin_methods(receiver: &Value) -> bool {
+    match receiver {
+        Value::Int(_) | Value::Number(_) => true,
+        Value::Obj(obj) => matches!(&**obj, Obj::List(_) | Obj::Map(_) | Obj::ListIterator(_)),
+        _ => false,
+    }
+}
+
+/// The error for reading `name` off a receiver with built-in methods as a
+/// property. Built-in methods can only be called.
+pub fn builtin_property_error(receiver: &Value, name: &str) -> String {
+    match receiver {
+        Value::Int(_) | Value::Number(_) => property_error(NUMBER_METHODS, "number", name),
+        Value::Obj(obj) => match &**obj {
+            Obj::List(_) => property_error(LIST_METHODS, "list", name),
+            Obj::Map(_) => property_error(MAP_METHODS, "map", name),
+            Obj::ListIterator(_) => property_error(ITERATOR_METHODS, "iterator", name),
+            _ => "Only instances have properties.".to_string(),
+        },
+        _ => "Only instances have properties.".to_string(),
+    }
+}
+
+fn dispatch<T>(
+    methods: &[Method<T>],
+    type_name: &str,
+    receiver: &T,
+    name: &str,
+    args: &[Value],
+    interner: &mut StringInterner,
+) -> Result<Value, String> {
+    let Some(&(_, arity, function)) = methods.iter().find(|method| method.0 == name) else {
+        return Err(undefined(methods, type_name, name));
+    };
+    if args.len() != arity {
+        return Err(format!(
+            "Expected {} arguments but got {}.",
+            arity,
+            args.len()
+        ));
+    }
+    function(receiver, args, interner)
+}
+
+fn property_error<T>(methods: &[Method<T>], type_name: &str, name: &str) -> String {
+    if methods.iter().any(|method| method.0 == name) {
+        format!("Method '{}' of a {} must be called.", name, type_name)
+    } else {
+        undefined(methods, type_name, name)
+    }
+}
+
+fn undefined<T>(methods: &[Method<T>], type_name: &str, name: &str) -> String {
+    let message = format!("Undefined method '{}' on {}.", name, type_name);
+    with_suggestion(message, name, methods.iter().map(|method| method.0))
+}
+
+/// Appends "Did you mean ...?" to an error about the missing `name` when
+/// one of `candidates` is close to it: one edit away for every three
+/// characters of `name`, at least one and at most two, and fewer edits
+/// than `name` has characters, so `foo` doesn't suggest `floor` nor `x`
+/// suggest `y`. Of equally close candidates, the alphabetically first wins.
+pub fn with_suggestion<'a>(
+    message: String,
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> String {
+    let length = name.chars().count();
+    let limit = (length / 3).clamp(1, 2).min(length.saturating_sub(1));
+    let closest = candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= limit)
+        .min();
+    match closest {
+        Some((_, candidate)) => format!("{} Did you mean '{}'?", message, candidate),
+        None => message,
+    }
+}
+
+/// The Levenshtein distance between `a` and `b`: how many characters must
+/// be inserted, deleted or replaced to turn one into the other. Swapping
+/// two neighbouring characters, as in `lenght`, counts as a single edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // distances[i][j] is the distance between the first i characters of a
+    // and the first j of b.
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let replace = distances[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = replace
+                .min(distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// Reads `list[index]`, as `m[i]` does on a list.
+pub fn list_index(list: &List, index: &Value) -> Result<Value, String> {
+    let items = list.items.borrow();
+    let slot = element(items.len(), index)?;
+    Ok(items[slot].clone())
+}
+
+/// Stores `value` at `list[index]` and returns it, as `m[i] = v` does.
+pub fn list_index_set(list: &List, index: &Value, value: Value) -> Result<Value, String> {
+    let mut items = list.items.borrow_mut();
+    let slot = element(items.len(), index)?;
+    items[slot] = value.clone();
+    Ok(value)
+}
+
+/// Checks that `index` is an integer naming one of `len` elements.
+fn element(len: usize, index: &Value) -> Result<usize, String> {
+    let index = match index {
+        Value::Int(i) => *i,
+        Value::Number(n) if n.fract() == 0.0 => *n as i64,
+        _ => return Err("List index must be an integer.".to_string()),
+    };
+    usize::try_from(index)
+        .ok()
+        .filter(|&slot| slot < len)
+        .ok_or_else(|| "List index out of range.".to_string())
+}
+
+fn list_push(list: &Rc<List>, args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    list.items.borrow_mut().push(args[0].clone());
+    Ok(Value::Nil)
+}
+
+fn list_pop(list: &Rc<List>, _args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    list.items
+        .borrow_mut()
+        .pop()
+        .ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+fn list_get(list: &Rc<List>, args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    list_index(list, &args[0])
+}
+
+fn list_set(list: &Rc<List>, args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    list_index_set(list, &args[0], args[1].clone())
+}
+
+fn list_length(list: &Rc<List>, _args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    Ok(Value::Int(list.items.borrow().len() as i64))
+}
+
+fn list_iter(list: &Rc<List>, _args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    Ok(iterator_over(Rc::clone(list)))
+}
+
+/// Iterates over a snapshot of the map's keys, in insertion order, so
+/// changing the map doesn't affect a loop that is already running.
+fn map_iter(map: &Map, _args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    let keys = map
+        .entries
+        .borrow()
+        .keys()
+        .map(|key| key.value().clone())
+        .collect();
+    Ok(iterator_over(Rc::new(List {
+        items: RefCell::new(keys),
+    })))
+}
+
+fn iterator_over(list: Rc<List>) -> Value {
+    Value::Obj(Rc::new(Obj::ListIterator(Rc::new(ListIterator {
+        list,
+        next: Cell::new(0),
+    }))))
+}
+
+/// Checks the list's length on every call, so elements pushed while
+/// iterating are visited too.
+fn iterator_has_next(
+    iterator: &ListIterator,
+    _args: &[Value],
+    _: &mut StringInterner,
+) -> Result<Value, String> {
+    Ok(Value::Bool(
+        iterator.next.get() < iterator.list.items.borrow().len(),
+    ))
+}
+
+fn iterator_next(
+    iterator: &ListIterator,
+    _args: &[Value],
+    _: &mut StringInterner,
+) -> Result<Value, String> {
+    let index = iterator.next.get();
+    let item = iterator
+        .list
+        .items
+        .borrow()
+        .get(index)
+        .cloned()
+        .ok_or_else(|| "Iterator has no more elements.".to_string())?;
+    iterator.next.set(index + 1);
+    Ok(item)
+}
+
+fn number_floor(n: &Value, _args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    Ok(map_float(n, f64::floor))
+}
+
+fn number_ceil(n: &Value, _args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    Ok(map_float(n, f64::ceil))
+}
+
+/// Rounds half-way values away from zero, so `(2.5).round()` is `3`.
+fn number_round(n: &Value, _args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    Ok(map_float(n, f64::round))
+}
+
+/// Overflows to a float for the one integer without a positive
+/// counterpart, as integer arithmetic does.
+fn number_abs(n: &Value, _args: &[Value], _: &mut StringInterner) -> Result<Value, String> {
+    Ok(match n {
+        Value::Int(i) => i
+            .checked_abs()
+            .map_or(Value::Number((*i as f64).abs()), Value::Int),
+        _ => map_float(n, f64::abs),
+    })
+}
+
+/// The number as `print` shows it.
+fn number_to_string(
+    n: &Value,
+    _args: &[Value],
+    interner: &mut StringInterner,
+) -> Result<Value, String> {
+    Ok(Value::Obj(Rc::new(Obj::String(
+        interner.intern(&n.to_string()),
+    ))))
+}
+
+/// Applies `f` to a float, leaving integers, which are already whole,
+/// unchanged.
+fn map_float(n: &Value, f: fn(f64) -> f64) -> Value {
+    match n {
+        Value::Number(n) => Value::Number(f(*n)),
+        _ => n.clone(),
+    }
+}
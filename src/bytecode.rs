@@ -0,0 +1,477 @@
+use crate::chunk::{Chunk, OpCode};
+use crate::value::{Function, Obj, StringInterner, Value};
+use crate::vm;
+use std::rc::Rc;
+
+const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the layout below or the instruction set changes, which
+/// also means recompiling and re-patching the fixtures in test/bytecode.
+const FORMAT_VERSION: u16 = 19;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+const TAG_INT: u8 = 6;
+
+/// A compiled script as stored on disk: the top-level function plus the
+/// global names its `*_GLOBAL_SLOT` operands index into, in slot order.
+pub struct Program {
+    pub globals: Vec<Rc<str>>,
+    pub function: Function,
+}
+
+/// Encodes `function` and the global slot table it was compiled against.
+///
+/// Layout, all integers little-endian: the magic `LOXC`, a u16 format
+/// version, the global names, then the script function. A function is its
+/// optional name, optional doc comment, arity, upvalue count, code, per-byte
+/// lines, constants and property cache count. Strings are a u32 byte
+/// length followed by UTF-8.
+pub fn serialize(function: &Function, globals: &[Rc<str>]) -> Result<Vec<u8>, String> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer
+        .bytes
+        .extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+
+    writer.u32(globals.len());
+    globals.iter().for_each(|name| writer.string(name));
+
+    writer.function(function)?;
+    Ok(writer.bytes)
+}
+
+/// Decodes a file written by `serialize`, interning every string so that
+/// loaded constants compare equal to strings created at runtime.
+pub fn deserialize(bytes: &[u8], interner: &mut StringInterner) -> Result<Program, String> {
+    let mut reader = Reader {
+        bytes,
+        offset: 0,
+        interner,
+    };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a compiled Lox file.".to_string());
+    }
+
+    let version = u16::from_le_bytes([reader.u8()?, reader.u8()?]);
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "Bytecode format version {} is not supported (expected {}).",
+            version, FORMAT_VERSION
+        ));
+    }
+
+    let globals: Vec<Rc<str>> = (0..reader.u32()?)
+        .map(|_| reader.string())
+        .collect::<Result<_, _>>()?;
+    let function = reader.function()?;
+
+    if reader.offset != bytes.len() {
+        return Err("Unexpected trailing data in compiled file.".to_string());
+    }
+    if function.arity != 0 {
+        return Err("Compiled script must not take parameters.".to_string());
+    }
+    check_function(&function, globals.len())?;
+
+    Ok(Program { globals, function })
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn u32(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), String> {
+        self.optional_string(function.name.as_deref());
+        self.optional_string(function.doc.as_deref());
+        self.u32(function.arity);
+        self.u32(function.upvalue_count);
+        self.chunk(&function.chunk)
+    }
+
+    fn optional_string(&mut self, s: Option<&str>) {
+        match s {
+            Some(s) => {
+                self.u8(1);
+                self.string(s);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> Result<(), String> {
+        self.u32(chunk.code.len());
+        self.bytes.extend_from_slice(&chunk.code);
+        chunk.lines.iter().for_each(|&line| self.u32(line));
+
+        self.u32(chunk.constants.len());
+        chunk
+            .constants
+            .iter()
+            .try_for_each(|constant| self.constant(constant))?;
+
+        self.u32(chunk.property_caches.borrow().len());
+        Ok(())
+    }
+
+    fn constant(&mut self, value: &Value) -> Result<(), String> {
+        match value {
+            Value::Nil => self.u8(TAG_NIL),
+            Value::Bool(false) => self.u8(TAG_FALSE),
+            Value::Bool(true) => self.u8(TAG_TRUE),
+            Value::Number(n) => {
+                self.u8(TAG_NUMBER);
+                self.bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Int(i) => {
+                self.u8(TAG_INT);
+                self.bytes.extend_from_slice(&i.to_le_bytes());
+            }
+            Value::Obj(obj) => match &**obj {
+                Obj::String(s) => {
+                    self.u8(TAG_STRING);
+                    self.string(s);
+                }
+                Obj::Function(function) => {
+                    self.u8(TAG_FUNCTION);
+                    self.function(function)?;
+                }
+                _ => return Err(format!("Cannot serialize constant {}.", value)),
+            },
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    interner: &'a mut StringInterner,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes: &'a [u8] = self.bytes;
+        let slice = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| bytes.get(self.offset..end))
+            .ok_or_else(|| "Unexpected end of compiled file.".to_string())?;
+        self.offset += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(i64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<Rc<str>, String> {
+        let len = self.u32()?;
+        let s = std::str::from_utf8(self.take(len)?)
+            .map_err(|_| "Invalid UTF-8 in compiled string.".to_string())?;
+        Ok(self.interner.intern(s))
+    }
+
+    fn optional_string(&mut self) -> Result<Option<Rc<str>>, String> {
+        Ok(match self.u8()? {
+            0 => None,
+            _ => Some(self.string()?),
+        })
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        let name = self.optional_string()?;
+        let doc = self.optional_string()?;
+        let arity = self.u32()?;
+        let upvalue_count = self.u32()?;
+        if upvalue_count > vm::U8_COUNT {
+            return Err("Too many upvalues in compiled function.".to_string());
+        }
+        let chunk = self.chunk()?;
+
+        Ok(Function {
+            arity,
+            upvalue_count,
+            chunk,
+            name,
+            doc,
+        })
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, String> {
+        let mut chunk = Chunk::new();
+
+        let code_len = self.u32()?;
+        chunk.code = self.take(code_len)?.to_vec();
+        chunk.lines = (0..code_len)
+            .map(|_| self.u32())
+            .collect::<Result<_, _>>()?;
+        chunk.constants = (0..self.u32()?)
+            .map(|_| self.constant())
+            .collect::<Result<_, _>>()?;
+        // Every cache belongs to a `GetProperty` instruction in the code, so
+        // a larger count can only come from a corrupt file.
+        let cache_count = self.u32()?;
+        if cache_count > code_len {
+            return Err("Too many property caches in compiled file.".to_string());
+        }
+        (0..cache_count).for_each(|_| {
+            chunk.add_property_cache();
+        });
+
+        Ok(chunk)
+    }
+
+    fn constant(&mut self) -> Result<Value, String> {
+        Ok(match self.u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_FALSE => Value::Bool(false),
+            TAG_TRUE => Value::Bool(true),
+            TAG_NUMBER => Value::Number(self.f64()?),
+            TAG_INT => Value::Int(self.i64()?),
+            TAG_STRING => Value::Obj(Rc::new(Obj::String(self.string()?))),
+            TAG_FUNCTION => Value::Obj(Rc::new(Obj::Function(Rc::new(self.function()?)))),
+            tag => return Err(format!("Unknown constant tag {} in compiled file.", tag)),
+        })
+    }
+}
+
+/// Checks that each instruction of `function`, and of the functions among
+/// its constants, decodes and fits in its chunk, and that its operands
+/// index a constant, global slot, upvalue, property cache or jump target
+/// that exists. `check_stack` then follows every path through the code.
+/// The VM trusts code that passes, as it does the compiler's, so nothing
+/// here is checked again while it runs.
+fn check_function(function: &Function, global_count: usize) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let cache_count = chunk.property_caches.borrow().len();
+    let truncated = || "Truncated instruction in compiled file.".to_string();
+    let upvalue_out_of_range = || "Upvalue index out of range in compiled file.".to_string();
+
+    let mut starts = vec![false; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        starts[offset] = true;
+        let byte = |n: usize| code.get(offset + n).copied().ok_or_else(truncated);
+        let short = |n: usize| -> Result<usize, String> {
+            Ok(u16::from_be_bytes([byte(n)?, byte(n + 1)?]) as usize)
+        };
+        let constant = |n: usize| {
+            chunk
+                .constants
+                .get(byte(n)? as usize)
+                .ok_or_else(|| "Constant index out of range in compiled file.".to_string())
+        };
+
+        let op = OpCode::try_from(code[offset])
+            .map_err(|_| format!("Unknown opcode {} in compiled file.", code[offset]))?;
+        match op {
+            OpCode::Constant
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::StaticMethod
+            | OpCode::Getter
+            | OpCode::Invoke
+            | OpCode::SuperInvoke => {
+                constant(1)?;
+            }
+            OpCode::DocumentedClass => {
+                constant(1)?;
+                constant(2)?;
+            }
+            OpCode::GetProperty => {
+                constant(1)?;
+                if short(2)? >= cache_count {
+                    return Err("Property cache index out of range in compiled file.".to_string());
+                }
+            }
+            OpCode::Closure => {
+                let Value::Obj(obj) = constant(1)? else {
+                    return Err("Closure of a non-function in compiled file.".to_string());
+                };
+                let Obj::Function(closed) = &**obj else {
+                    return Err("Closure of a non-function in compiled file.".to_string());
+                };
+                // Captured locals are checked by `check_stack`, which knows
+                // how many the frame holds here.
+                for capture in 0..closed.upvalue_count {
+                    let is_local = byte(2 + capture * 3)? != 0;
+                    if !is_local && short(3 + capture * 3)? >= function.upvalue_count {
+                        return Err(upvalue_out_of_range());
+                    }
+                }
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue
+                if byte(1)? as usize >= function.upvalue_count =>
+            {
+                return Err(upvalue_out_of_range());
+            }
+            OpCode::GetGlobalSlot
+            | OpCode::DefineGlobalSlot
+            | OpCode::DefineConstGlobalSlot
+            | OpCode::SetGlobalSlot
+            | OpCode::SetGlobalSlotPop
+                if short(1)? >= global_count =>
+            {
+                return Err("Global slot out of range in compiled file.".to_string());
+            }
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfTrue
+            | OpCode::JumpIfNil
+            | OpCode::JumpIfNotNil
+            | OpCode::PushHandler
+                if offset + 3 + short(1)? > code.len() =>
+            {
+                return Err("Jump target out of range in compiled file.".to_string());
+            }
+            OpCode::Loop if short(1)? > offset + 3 => {
+                return Err("Jump target out of range in compiled file.".to_string());
+            }
+            _ => {}
+        }
+
+        offset += chunk.instruction_len(offset);
+        if offset > code.len() {
+            return Err(truncated());
+        }
+    }
+
+    check_stack(function, &starts)?;
+
+    chunk
+        .constants
+        .iter()
+        .try_for_each(|constant| match constant {
+            Value::Obj(obj) => match &**obj {
+                Obj::Function(function) => check_function(function, global_count),
+                _ => Ok(()),
+            },
+            _ => Ok(()),
+        })
+}
+
+/// Follows every path through `function`'s code, which `check_function`
+/// has already decoded into the instructions starting where `starts` is
+/// set, tracking how many values the frame holds before each instruction.
+/// Rejects code that pops values the frame doesn't have, reads a local
+/// above them, jumps into the middle of an instruction or runs past the end
+/// without returning.
+///
+/// Where paths meet with different depths the smaller is kept, since every
+/// check only needs a lower bound. An exception handler's `catch` block
+/// starts with the values there were at its `PushHandler`, plus the
+/// exception.
+fn check_stack(function: &Function, starts: &[bool]) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let local_out_of_range = || "Local slot out of range in compiled file.".to_string();
+
+    // The frame starts with the function and its arguments.
+    let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+    let mut pending = vec![(0, function.arity + 1)];
+
+    while let Some((offset, depth)) = pending.pop() {
+        if offset >= code.len() {
+            return Err("Code runs past the end of a function in compiled file.".to_string());
+        }
+        if !starts[offset] {
+            return Err("Jump into the middle of an instruction in compiled file.".to_string());
+        }
+        if depths[offset].is_some_and(|known| known <= depth) {
+            continue;
+        }
+        depths[offset] = Some(depth);
+
+        let (taken, left) = chunk.stack_effect(offset);
+        let Some(below) = depth.checked_sub(taken) else {
+            return Err("Stack underflow in compiled file.".to_string());
+        };
+        let after = below + left;
+        let byte = |n: usize| code[offset + n] as usize;
+        let short =
+            |n: usize| u16::from_be_bytes([code[offset + n], code[offset + n + 1]]) as usize;
+        let next = offset + chunk.instruction_len(offset);
+        let op = OpCode::try_from(code[offset]);
+
+        match op {
+            Ok(OpCode::GetLocal | OpCode::SetLocal) if byte(1) >= depth => {
+                return Err(local_out_of_range());
+            }
+            Ok(OpCode::GetLocalLong | OpCode::SetLocalLong) if short(1) >= depth => {
+                return Err(local_out_of_range());
+            }
+            Ok(OpCode::SetLocalPop) if byte(1) >= below => {
+                return Err(local_out_of_range());
+            }
+            Ok(OpCode::Closure) => {
+                let upvalue_count = (next - offset - 2) / 3;
+                // A local function captures itself from the slot the
+                // closure is about to be pushed into.
+                let captures_past_top = (0..upvalue_count)
+                    .any(|capture| byte(2 + capture * 3) != 0 && short(3 + capture * 3) > depth);
+                if captures_past_top {
+                    return Err(local_out_of_range());
+                }
+            }
+            _ => {}
+        }
+
+        match op {
+            Ok(OpCode::Return | OpCode::Throw) => {}
+            Ok(OpCode::Jump) => pending.push((next + short(1), after)),
+            Ok(OpCode::Loop) => pending.push((next - short(1), after)),
+            Ok(OpCode::PushHandler) => {
+                pending.push((next + short(1), after + 1));
+                pending.push((next, after));
+            }
+            Ok(
+                OpCode::JumpIfFalse | OpCode::JumpIfTrue | OpCode::JumpIfNil | OpCode::JumpIfNotNil,
+            ) => {
+                pending.push((next + short(1), after));
+                pending.push((next, after));
+            }
+            _ => pending.push((next, after)),
+        }
+    }
+
+    Ok(())
+}
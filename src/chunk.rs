@@ -1,4 +1,6 @@
-use crate::value::Value;
+use crate::value::{Class, Closure, Obj, Value};
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -10,9 +12,9 @@ pub enum OpCode {
     Pop = 4,
     GetLocal = 5,
     SetLocal = 6,
-    GetGlobal = 7,
-    DefineGlobal = 8,
-    SetGlobal = 9,
+    GetGlobalSlot = 7,
+    DefineGlobalSlot = 8,
+    SetGlobalSlot = 9,
     GetUpvalue = 10,
     SetUpvalue = 11,
     GetProperty = 12,
@@ -40,6 +42,43 @@ pub enum OpCode {
     Class = 34,
     Inherit = 35,
     Method = 36,
+    Throw = 37,
+    PushHandler = 38,
+    PopHandler = 39,
+    BitAnd = 40,
+    BitOr = 41,
+    BitXor = 42,
+    BitNot = 43,
+    ShiftLeft = 44,
+    ShiftRight = 45,
+    GetLocalLong = 46,
+    SetLocalLong = 47,
+    PopN = 48,
+    NotEqual = 49,
+    GreaterEqual = 50,
+    LessEqual = 51,
+    StaticMethod = 52,
+    Getter = 53,
+    Map = 54,
+    MapEntry = 55,
+    Index = 56,
+    IndexSet = 57,
+    Dup = 58,
+    Swap = 59,
+    Over = 60,
+    List = 61,
+    ListAppend = 62,
+    JumpIfNil = 63,
+    JumpIfTrue = 64,
+    TailCall = 65,
+    BuildString = 66,
+    IsInstance = 67,
+    ForIn = 68,
+    SetLocalPop = 69,
+    SetGlobalSlotPop = 70,
+    JumpIfNotNil = 71,
+    DocumentedClass = 72,
+    DefineConstGlobalSlot = 73,
 }
 
 impl From<OpCode> for u8 {
@@ -60,9 +99,9 @@ impl TryFrom<u8> for OpCode {
             4 => Ok(OpCode::Pop),
             5 => Ok(OpCode::GetLocal),
             6 => Ok(OpCode::SetLocal),
-            7 => Ok(OpCode::GetGlobal),
-            8 => Ok(OpCode::DefineGlobal),
-            9 => Ok(OpCode::SetGlobal),
+            7 => Ok(OpCode::GetGlobalSlot),
+            8 => Ok(OpCode::DefineGlobalSlot),
+            9 => Ok(OpCode::SetGlobalSlot),
             10 => Ok(OpCode::GetUpvalue),
             11 => Ok(OpCode::SetUpvalue),
             12 => Ok(OpCode::GetProperty),
@@ -90,16 +129,65 @@ impl TryFrom<u8> for OpCode {
             34 => Ok(OpCode::Class),
             35 => Ok(OpCode::Inherit),
             36 => Ok(OpCode::Method),
+            37 => Ok(OpCode::Throw),
+            38 => Ok(OpCode::PushHandler),
+            39 => Ok(OpCode::PopHandler),
+            40 => Ok(OpCode::BitAnd),
+            41 => Ok(OpCode::BitOr),
+            42 => Ok(OpCode::BitXor),
+            43 => Ok(OpCode::BitNot),
+            44 => Ok(OpCode::ShiftLeft),
+            45 => Ok(OpCode::ShiftRight),
+            46 => Ok(OpCode::GetLocalLong),
+            47 => Ok(OpCode::SetLocalLong),
+            48 => Ok(OpCode::PopN),
+            49 => Ok(OpCode::NotEqual),
+            50 => Ok(OpCode::GreaterEqual),
+            51 => Ok(OpCode::LessEqual),
+            52 => Ok(OpCode::StaticMethod),
+            53 => Ok(OpCode::Getter),
+            54 => Ok(OpCode::Map),
+            55 => Ok(OpCode::MapEntry),
+            56 => Ok(OpCode::Index),
+            57 => Ok(OpCode::IndexSet),
+            58 => Ok(OpCode::Dup),
+            59 => Ok(OpCode::Swap),
+            60 => Ok(OpCode::Over),
+            61 => Ok(OpCode::List),
+            62 => Ok(OpCode::ListAppend),
+            63 => Ok(OpCode::JumpIfNil),
+            64 => Ok(OpCode::JumpIfTrue),
+            65 => Ok(OpCode::TailCall),
+            66 => Ok(OpCode::BuildString),
+            67 => Ok(OpCode::IsInstance),
+            68 => Ok(OpCode::ForIn),
+            69 => Ok(OpCode::SetLocalPop),
+            70 => Ok(OpCode::SetGlobalSlotPop),
+            71 => Ok(OpCode::JumpIfNotNil),
+            72 => Ok(OpCode::DocumentedClass),
+            73 => Ok(OpCode::DefineConstGlobalSlot),
             _ => Err(()),
         }
     }
 }
 
+/// Monomorphic inline cache for one `GetProperty` site: the class of the
+/// last receiver whose property resolved to a method, and that method.
+///
+/// Fields live in a per-instance map and always shadow methods, so only the
+/// method half of the lookup can be cached.
+#[derive(Debug, Clone, Default)]
+pub struct PropertyCache {
+    pub class: Weak<Class>,
+    pub method: Option<Rc<Closure>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub lines: Vec<usize>,
     pub constants: Vec<Value>,
+    pub property_caches: RefCell<Vec<PropertyCache>>,
 }
 
 impl Chunk {
@@ -108,6 +196,7 @@ impl Chunk {
             code: Vec::new(),
             lines: Vec::new(),
             constants: Vec::new(),
+            property_caches: RefCell::new(Vec::new()),
         }
     }
 
@@ -121,9 +210,156 @@ impl Chunk {
         self.constants.len() - 1
     }
 
+    pub fn add_property_cache(&mut self) -> usize {
+        let caches = self.property_caches.get_mut();
+        caches.push(PropertyCache::default());
+        caches.len() - 1
+    }
+
     pub fn count(&self) -> usize {
         self.code.len()
     }
+
+    /// Size in bytes of the instruction at `offset`, operands included.
+    pub fn instruction_len(&self, offset: usize) -> usize {
+        match self.code[offset].try_into() {
+            Ok(
+                OpCode::Constant
+                | OpCode::GetLocal
+                | OpCode::SetLocal
+                | OpCode::SetLocalPop
+                | OpCode::GetUpvalue
+                | OpCode::SetUpvalue
+                | OpCode::SetProperty
+                | OpCode::GetSuper
+                | OpCode::Call
+                | OpCode::TailCall
+                | OpCode::BuildString
+                | OpCode::Class
+                | OpCode::Method
+                | OpCode::StaticMethod
+                | OpCode::Getter
+                | OpCode::PopN,
+            ) => 2,
+            Ok(
+                OpCode::GetGlobalSlot
+                | OpCode::DefineGlobalSlot
+                | OpCode::DefineConstGlobalSlot
+                | OpCode::SetGlobalSlot
+                | OpCode::SetGlobalSlotPop
+                | OpCode::Jump
+                | OpCode::JumpIfFalse
+                | OpCode::JumpIfNil
+                | OpCode::JumpIfNotNil
+                | OpCode::JumpIfTrue
+                | OpCode::Loop
+                | OpCode::Invoke
+                | OpCode::SuperInvoke
+                | OpCode::DocumentedClass
+                | OpCode::PushHandler
+                | OpCode::GetLocalLong
+                | OpCode::SetLocalLong,
+            ) => 3,
+            Ok(OpCode::GetProperty) => 4,
+            Ok(OpCode::Closure) => {
+                let upvalue_count = match &self.constants[self.code[offset + 1] as usize] {
+                    Value::Obj(obj) => match &**obj {
+                        Obj::Function(function) => function.upvalue_count,
+                        _ => 0,
+                    },
+                    _ => 0,
+                };
+                2 + upvalue_count * 3
+            }
+            _ => 1,
+        }
+    }
+
+    /// How many values the instruction at `offset` takes from the top of
+    /// the stack, and how many it leaves in their place once it is done.
+    /// A call counts its callee and arguments, and leaves the result.
+    pub fn stack_effect(&self, offset: usize) -> (usize, usize) {
+        let operand = |n: usize| self.code[offset + n] as usize;
+        match self.code[offset].try_into() {
+            Ok(OpCode::PopN) => (operand(1), 0),
+            Ok(OpCode::BuildString) => (operand(1), 1),
+            Ok(OpCode::Call | OpCode::TailCall) => (operand(1) + 1, 1),
+            Ok(OpCode::Invoke) => (operand(2) + 1, 1),
+            Ok(OpCode::SuperInvoke) => (operand(2) + 2, 1),
+            Ok(OpCode::MapEntry | OpCode::IndexSet) => (3, 1),
+            Ok(OpCode::Over) => (2, 3),
+            Ok(OpCode::Swap) => (2, 2),
+            Ok(OpCode::Dup) => (1, 2),
+            Ok(
+                OpCode::SetProperty
+                | OpCode::GetSuper
+                | OpCode::Equal
+                | OpCode::NotEqual
+                | OpCode::Greater
+                | OpCode::GreaterEqual
+                | OpCode::Less
+                | OpCode::LessEqual
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Inherit
+                | OpCode::IsInstance
+                | OpCode::Method
+                | OpCode::Getter
+                | OpCode::StaticMethod
+                | OpCode::Index
+                | OpCode::ListAppend
+                | OpCode::BitAnd
+                | OpCode::BitOr
+                | OpCode::BitXor
+                | OpCode::ShiftLeft
+                | OpCode::ShiftRight,
+            ) => (2, 1),
+            Ok(
+                OpCode::SetLocal
+                | OpCode::SetLocalLong
+                | OpCode::SetGlobalSlot
+                | OpCode::SetUpvalue
+                | OpCode::GetProperty
+                | OpCode::Not
+                | OpCode::Negate
+                | OpCode::BitNot
+                | OpCode::JumpIfFalse
+                | OpCode::JumpIfTrue
+                | OpCode::JumpIfNil
+                | OpCode::JumpIfNotNil
+                | OpCode::ForIn,
+            ) => (1, 1),
+            Ok(
+                OpCode::Pop
+                | OpCode::SetLocalPop
+                | OpCode::DefineGlobalSlot
+                | OpCode::DefineConstGlobalSlot
+                | OpCode::SetGlobalSlotPop
+                | OpCode::Print
+                | OpCode::CloseUpvalue
+                | OpCode::Return
+                | OpCode::Throw,
+            ) => (1, 0),
+            Ok(
+                OpCode::Constant
+                | OpCode::Nil
+                | OpCode::True
+                | OpCode::False
+                | OpCode::GetLocal
+                | OpCode::GetLocalLong
+                | OpCode::GetGlobalSlot
+                | OpCode::GetUpvalue
+                | OpCode::Closure
+                | OpCode::Class
+                | OpCode::DocumentedClass
+                | OpCode::Map
+                | OpCode::List,
+            ) => (0, 1),
+            _ => (0, 0),
+        }
+    }
 }
 
 impl Default for Chunk {
@@ -1,12 +1,16 @@
 use crate::chunk::{Chunk, OpCode};
+use crate::peephole;
 use crate::scanner::{Scanner, Token, TokenType};
-use crate::value::{Function, Obj, StringInterner, Value};
-use crate::vm;
+use crate::value::{self, Function, Obj, StringInterner, Value};
+use crate::vm::{self, Globals};
+use std::collections::HashMap;
+use std::fmt;
 use std::rc::Rc;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum FunctionType {
     Function,
+    Getter,
     Initializer,
     Method,
     Script,
@@ -20,18 +24,65 @@ struct FunctionCompiler<'a> {
     locals: Vec<Local<'a>>,
     upvalues: Vec<Upvalue>,
     scope_depth: usize,
+    /// Offset of the most recent `Constant` instruction, cleared once a jump
+    /// lands after it so constant folding never rewrites a jump target.
+    last_constant: Option<usize>,
+    /// Offset of the most recent `Call` instruction, so `return` can tell
+    /// when its value is a call in tail position.
+    last_call: Option<usize>,
+    /// Offset of the store ending the most recent variable assignment,
+    /// cleared once a jump lands after it, so a statement that discards the
+    /// assigned value can have the store pop it.
+    last_store: Option<usize>,
+    /// The loops enclosing the code being compiled, innermost last.
+    loops: Vec<Loop<'a>>,
+    /// How many `try` blocks enclose the code being compiled, each with an
+    /// exception handler that `break` and `continue` must pop on the way out.
+    try_depth: usize,
+}
+
+/// A loop that `break` and `continue` can target.
+#[derive(Debug)]
+struct Loop<'a> {
+    label: Option<&'a str>,
+    /// Where `continue` jumps back to: the increment clause of a `for`, or
+    /// the condition otherwise.
+    continue_target: usize,
+    /// Scope depth outside the body, so jumping out pops deeper locals.
+    scope_depth: usize,
+    try_depth: usize,
+    /// `Jump` operands to patch to the end of the loop.
+    breaks: Vec<usize>,
 }
 
 #[derive(Debug, Clone)]
 struct Local<'a> {
     name: &'a str,
+    line: usize,
     depth: Option<usize>,
     is_captured: bool,
+    is_read: bool,
+    /// Parameter count when this local was declared with `fun`.
+    arity: Option<usize>,
+    /// Set once the local is assigned, after which its arity can't be trusted.
+    is_reassigned: bool,
+    /// Declared with `const`, so assigning it is a compile error.
+    is_const: bool,
+    /// Calls of this local by name, checked against `arity` when it goes out
+    /// of scope and every assignment to it has been seen.
+    calls: Vec<DirectCall<'a>>,
+}
+
+/// A call whose callee is a bare variable name, as in `f(1, 2)`.
+#[derive(Debug, Clone, Copy)]
+struct DirectCall<'a> {
+    name: Token<'a>,
+    arg_count: u8,
 }
 
 #[derive(Debug, Clone, Copy)]
 struct Upvalue {
-    index: u8,
+    index: u16,
     is_local: bool,
 }
 
@@ -39,6 +90,7 @@ struct Upvalue {
 struct ClassCompiler {
     enclosing: Option<Box<ClassCompiler>>,
     has_superclass: bool,
+    in_static_method: bool,
 }
 
 pub struct Compiler<'a> {
@@ -47,16 +99,74 @@ pub struct Compiler<'a> {
     current: Option<Box<FunctionCompiler<'a>>>,
     current_class: Option<Box<ClassCompiler>>,
     interner: StringInterner,
+    globals: Globals,
+    options: CompileOptions,
+    /// Arity of each global slot declared exactly once with `fun` in this
+    /// compilation unit, or `None` once it has been declared or assigned any
+    /// other way.
+    global_arities: HashMap<usize, Option<usize>>,
+    /// Calls of global names, checked against `global_arities` at the end.
+    global_calls: Vec<(usize, DirectCall<'a>)>,
+    /// For each global slot declared in this compilation unit, whether its
+    /// latest declaration is a `const`. Other slots are looked up in
+    /// `globals`, which knows what earlier compilations defined.
+    const_globals: HashMap<usize, bool>,
+    /// The name just read by `named_variable` and the chunk length right
+    /// after the read, so `call` can tell when the callee is that variable.
+    last_variable: Option<(Token<'a>, usize)>,
+}
+
+/// Switches that change what the compiler reports, not what it emits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompileOptions {
+    /// Print non-fatal warnings, such as unused locals or chained comparisons, to stderr.
+    pub warnings: bool,
+    /// Run the peephole pass over every compiled chunk.
+    pub optimize: bool,
+}
+
+/// A compile error, with the position of the token it was found at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileDiagnostic {
+    pub line: usize,
+    /// 1-based column of the token, counted in chars.
+    pub column: usize,
+    /// Where the error was found: a quoted lexeme such as `'x'`, `end` for
+    /// the end of the source, or `None` for errors from the scanner.
+    pub at: Option<String>,
+    pub message: String,
+}
+
+/// Formats the diagnostic as the CLI reports it, for example
+/// `[line 1] Error at 'x': Expect ';' after value. (column 7)`.
+impl fmt::Display for CompileDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Error", self.line)?;
+        if let Some(at) = &self.at {
+            write!(f, " at {}", at)?;
+        }
+
+        // The column goes last so the `[line N] Error at ...: message`
+        // text that tools and tests match on stays contiguous.
+        write!(f, ": {} (column {})", self.message, self.column)
+    }
 }
 
 #[derive(Debug)]
 struct Parser<'a> {
     current: Option<Token<'a>>,
     previous: Option<Token<'a>>,
-    had_error: bool,
+    /// Every error reported so far, in the order they were found.
+    diagnostics: Vec<CompileDiagnostic>,
     panic_mode: bool,
 }
 
+impl Parser<'_> {
+    fn had_error(&self) -> bool {
+        !self.diagnostics.is_empty()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum Precedence {
     None,
@@ -65,6 +175,10 @@ enum Precedence {
     And,        // and
     Equality,   // == !=
     Comparison, // < > <= >=
+    BitOr,      // |
+    BitXor,     // ^
+    BitAnd,     // &
+    Shift,      // << >>
     Term,       // + -
     Factor,     // * /
     Unary,      // ! -
@@ -80,7 +194,11 @@ impl Precedence {
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
-            Precedence::Comparison => Precedence::Term,
+            Precedence::Comparison => Precedence::BitOr,
+            Precedence::BitOr => Precedence::BitXor,
+            Precedence::BitXor => Precedence::BitAnd,
+            Precedence::BitAnd => Precedence::Shift,
+            Precedence::Shift => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
             Precedence::Unary => Precedence::Call,
@@ -98,13 +216,45 @@ struct ParseRule<'a> {
     precedence: Precedence,
 }
 
+/// The source form of a `<`, `<=`, `>` or `>=` operator.
+fn comparison_lexeme(token_type: TokenType) -> Option<&'static str> {
+    match token_type {
+        TokenType::Less => Some("<"),
+        TokenType::LessEqual => Some("<="),
+        TokenType::Greater => Some(">"),
+        TokenType::GreaterEqual => Some(">="),
+        _ => None,
+    }
+}
+
+/// Whether `value` is a whole number from 0 to 255. These turn up in
+/// nearly every loop and index, so a chunk keeps one constant for each
+/// rather than one per literal.
+fn is_small_integer(value: &Value) -> bool {
+    match value {
+        Value::Int(i) => (0..=255).contains(i),
+        Value::Number(n) => n.fract() == 0.0 && n.is_sign_positive() && *n <= 255.0,
+        _ => false,
+    }
+}
+
 impl<'a> Compiler<'a> {
-    pub fn compile(source: &'a str) -> Result<Rc<Function>, ()> {
+    /// Compiles `source` into the top-level script function.
+    ///
+    /// Strings are interned into the VM's `interner` and global names are
+    /// resolved to slots in the VM's `globals`, so both are borrowed for the
+    /// duration of the compile and handed back afterwards.
+    pub fn compile(
+        source: &'a str,
+        interner: &mut StringInterner,
+        globals: &mut Globals,
+        options: CompileOptions,
+    ) -> Result<Rc<Function>, Vec<CompileDiagnostic>> {
         let scanner = Scanner::new(source);
         let parser = Parser {
             current: None,
             previous: None,
-            had_error: false,
+            diagnostics: Vec::new(),
             panic_mode: false,
         };
 
@@ -113,7 +263,13 @@ impl<'a> Compiler<'a> {
             parser,
             current: None,
             current_class: None,
-            interner: StringInterner::new(),
+            interner: std::mem::take(interner),
+            globals: std::mem::take(globals),
+            options,
+            global_arities: HashMap::new(),
+            global_calls: Vec::new(),
+            const_globals: HashMap::new(),
+            last_variable: None,
         };
 
         let mut compiler = FunctionCompiler {
@@ -123,11 +279,22 @@ impl<'a> Compiler<'a> {
             locals: Vec::with_capacity(vm::U8_COUNT),
             upvalues: Vec::with_capacity(vm::U8_COUNT),
             scope_depth: 0,
+            last_constant: None,
+            last_call: None,
+            last_store: None,
+            loops: Vec::new(),
+            try_depth: 0,
         };
         compiler.locals.push(Local {
             name: "",
+            line: 0,
             depth: Some(0),
             is_captured: false,
+            is_read: false,
+            arity: None,
+            is_reassigned: false,
+            is_const: false,
+            calls: Vec::new(),
         });
 
         state.current = Some(Box::new(compiler));
@@ -137,10 +304,13 @@ impl<'a> Compiler<'a> {
             state.declaration();
         }
 
+        state.check_global_calls();
         let function = state.end_compiler();
+        *interner = state.interner;
+        *globals = state.globals;
 
-        if state.parser.had_error {
-            Err(())
+        if state.parser.had_error() {
+            Err(state.parser.diagnostics)
         } else {
             Ok(Rc::new(function))
         }
@@ -206,11 +376,63 @@ impl<'a> Compiler<'a> {
 
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
+        let offset = self.current_chunk().count();
         self.emit_bytes(OpCode::Constant.into(), constant);
+        self.current.as_mut().unwrap().last_constant = Some(offset);
+    }
+
+    /// Returns the number or string loaded by a `Constant` instruction at
+    /// `offset` if it is the last instruction emitted and no jump targets its
+    /// end.
+    fn constant_at(&mut self, offset: usize) -> Option<Value> {
+        if self.current.as_ref().unwrap().last_constant != Some(offset)
+            || self.current_chunk().count() != offset + 2
+        {
+            return None;
+        }
+
+        let chunk = self.current_chunk();
+        match &chunk.constants[chunk.code[offset + 1] as usize] {
+            constant @ (Value::Int(_) | Value::Number(_)) => Some(constant.clone()),
+            constant if constant.is_string() => Some(constant.clone()),
+            _ => None,
+        }
+    }
+
+    /// Replaces the constant loads from `offset` onward with a single load of
+    /// `value`, dropping their now unused constants when they are the newest.
+    /// Small integers stay, as earlier loads may share them.
+    fn replace_with_constant(&mut self, offset: usize, value: Value) {
+        let chunk = self.current_chunk();
+        let mut indexes: Vec<usize> = chunk.code[offset..]
+            .chunks_exact(2)
+            .map(|load| load[1] as usize)
+            .collect();
+        indexes.sort_unstable();
+        indexes.iter().rev().for_each(|&index| {
+            if index + 1 == chunk.constants.len() && !is_small_integer(&chunk.constants[index]) {
+                chunk.constants.pop();
+            }
+        });
+        chunk.code.truncate(offset);
+        chunk.lines.truncate(offset);
+
+        self.emit_constant(value);
     }
 
     fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.current_chunk().add_constant(value);
+        let same = |constant: &Value| match (constant, &value) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            _ => false,
+        };
+        let chunk = self.current_chunk();
+        let shared = if is_small_integer(&value) {
+            chunk.constants.iter().position(same)
+        } else {
+            None
+        };
+        let constant = shared.unwrap_or_else(|| chunk.add_constant(value));
         if constant > u8::MAX as usize {
             self.error("Too many constants in one chunk.");
             return 0;
@@ -248,12 +470,26 @@ impl<'a> Compiler<'a> {
         let bytes = (jump as u16).to_be_bytes();
         self.current_chunk().code[offset] = bytes[0];
         self.current_chunk().code[offset + 1] = bytes[1];
+        let compiler = self.current.as_mut().unwrap();
+        compiler.last_constant = None;
+        compiler.last_store = None;
     }
 
     fn end_compiler(&mut self) -> Function {
         self.emit_return();
+        self.last_variable = None;
         let compiler = self.current.take().unwrap();
-        let function = compiler.function;
+        // Locals in the function's outermost block are discarded with the
+        // frame rather than popped by `end_scope`.
+        for local in &compiler.locals {
+            self.warn_if_unused(local);
+            self.check_calls(local);
+        }
+        let mut function = compiler.function;
+
+        if self.options.optimize && !self.parser.had_error() {
+            peephole::optimize(&mut function.chunk);
+        }
 
         if let Some(enclosing) = compiler.enclosing {
             self.current = Some(enclosing);
@@ -274,25 +510,73 @@ impl<'a> Compiler<'a> {
             if local.depth.is_some()
                 && local.depth.unwrap() > self.current.as_ref().unwrap().scope_depth
             {
+                let local = self.current.as_mut().unwrap().locals.pop().unwrap();
+                self.warn_if_unused(&local);
+                self.check_calls(&local);
                 if local.is_captured {
                     self.emit_byte(OpCode::CloseUpvalue.into());
                 } else {
                     self.emit_byte(OpCode::Pop.into());
                 }
-                self.current.as_mut().unwrap().locals.pop();
             } else {
                 break;
             }
         }
     }
 
+    fn warn_if_unused(&self, local: &Local) {
+        if !self.options.warnings || local.is_read || matches!(local.name, "" | "this" | "super") {
+            return;
+        }
+
+        eprintln!(
+            "[line {}] Warning: unused local '{}'.",
+            local.line, local.name
+        );
+    }
+
+    /// Reports calls of a `fun`-declared local with the wrong number of
+    /// arguments, unless the local was ever assigned a different value.
+    fn check_calls(&mut self, local: &Local) {
+        let Some(arity) = local.arity.filter(|_| !local.is_reassigned) else {
+            return;
+        };
+        for call in &local.calls {
+            self.check_arity(call, arity);
+        }
+    }
+
+    /// Like `check_calls`, for the calls of globals declared in this unit.
+    fn check_global_calls(&mut self) {
+        for (slot, call) in std::mem::take(&mut self.global_calls) {
+            if let Some(&Some(arity)) = self.global_arities.get(&slot) {
+                self.check_arity(&call, arity);
+            }
+        }
+    }
+
+    fn check_arity(&mut self, call: &DirectCall, arity: usize) {
+        if call.arg_count as usize != arity {
+            // Reported after the fact, so don't enter panic mode and skip
+            // whatever is being parsed now.
+            self.report(
+                &call.name,
+                &format!("Expected {} arguments but got {}.", arity, call.arg_count),
+            );
+        }
+    }
+
     fn declaration(&mut self) {
         if self.match_token(TokenType::Class) {
             self.class_declaration();
+        } else if self.match_token(TokenType::Enum) {
+            self.enum_declaration();
         } else if self.match_token(TokenType::Fun) {
             self.fun_declaration();
         } else if self.match_token(TokenType::Var) {
             self.var_declaration();
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration();
         } else {
             self.statement();
         }
@@ -302,18 +586,67 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    /// `enum Name { A, B, ... }`, sugar for a class `Name` whose static
+    /// members `A`, `B`, ... hold the integers 0, 1, ... in declaration
+    /// order. Only instances have fields, so the members can't be assigned.
+    fn enum_declaration(&mut self) {
+        let doc = self.previous_doc();
+        self.consume(TokenType::Identifier, "Expect enum name.");
+        let enum_name = self.parser.previous.as_ref().unwrap().lexeme;
+        let name_constant = self.identifier_constant(enum_name);
+        self.declare_variable();
+        let global = if self.current.as_ref().unwrap().scope_depth > 0 {
+            0
+        } else {
+            self.global_slot(enum_name)
+        };
+
+        self.emit_class(name_constant, doc);
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before enum body.");
+        let mut members = Vec::new();
+        while !self.check(TokenType::RightBrace) {
+            self.consume(TokenType::Identifier, "Expect member name.");
+            let member = self.parser.previous.as_ref().unwrap().lexeme;
+            if members.contains(&member) {
+                self.error("Already a member with this name in this enum.");
+            }
+
+            let member_constant = self.identifier_constant(member);
+            self.emit_constant(Value::Int(members.len() as i64));
+            self.emit_bytes(OpCode::StaticMethod.into(), member_constant);
+            members.push(member);
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after enum body.");
+
+        self.define_variable(global);
+        self.forget_global_arity(global);
+    }
+
     fn class_declaration(&mut self) {
+        let doc = self.previous_doc();
         self.consume(TokenType::Identifier, "Expect class name.");
         let class_name = self.parser.previous.as_ref().unwrap().lexeme;
         let name_constant = self.identifier_constant(class_name);
         self.declare_variable();
+        let global = if self.current.as_ref().unwrap().scope_depth > 0 {
+            0
+        } else {
+            self.global_slot(class_name)
+        };
 
-        self.emit_bytes(OpCode::Class.into(), name_constant);
-        self.define_variable(name_constant);
+        self.emit_class(name_constant, doc);
+        self.define_variable(global);
+        self.forget_global_arity(global);
 
         let mut class_compiler = ClassCompiler {
             enclosing: None,
             has_superclass: false,
+            in_static_method: false,
         };
 
         if let Some(current_class) = self.current_class.take() {
@@ -359,29 +692,84 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    /// Emits the instruction that creates a class, with its doc comment if
+    /// it has one.
+    fn emit_class(&mut self, name_constant: u8, doc: Option<Rc<str>>) {
+        match doc {
+            Some(doc) => {
+                let doc_constant = self.identifier_constant(&doc);
+                self.emit_bytes(OpCode::DocumentedClass.into(), name_constant);
+                self.emit_byte(doc_constant);
+            }
+            None => self.emit_bytes(OpCode::Class.into(), name_constant),
+        }
+    }
+
+    /// The doc comment before the token just consumed, such as the `fun` of
+    /// a function declaration.
+    fn previous_doc(&self) -> Option<Rc<str>> {
+        let token = self.parser.previous.as_ref().unwrap();
+        token.doc_text().map(Rc::from)
+    }
+
     fn method(&mut self) {
+        // The comment sits before `static` or the name, whichever comes first.
+        let first = self.parser.current.as_ref().unwrap();
+        let doc = first.doc_text().map(Rc::from);
+        let is_static = self.match_token(TokenType::Static);
         self.consume(TokenType::Identifier, "Expect method name.");
         let name = self.parser.previous.as_ref().unwrap().lexeme;
         let constant = self.identifier_constant(name);
 
+        if is_static {
+            // Static methods have no receiver, so they compile like plain
+            // functions and slot zero holds the class being called on.
+            self.current_class.as_mut().unwrap().in_static_method = true;
+            self.function(FunctionType::Function, doc);
+            self.current_class.as_mut().unwrap().in_static_method = false;
+            self.emit_bytes(OpCode::StaticMethod.into(), constant);
+            return;
+        }
+
+        if self.check(TokenType::LeftBrace) {
+            if name == "init" {
+                self.error("An initializer can't be a getter.");
+            }
+            self.function(FunctionType::Getter, doc);
+            self.emit_bytes(OpCode::Getter.into(), constant);
+            return;
+        }
+
         let function_type = if name == "init" {
             FunctionType::Initializer
         } else {
             FunctionType::Method
         };
 
-        self.function(function_type);
+        self.function(function_type, doc);
         self.emit_bytes(OpCode::Method.into(), constant);
     }
 
     fn fun_declaration(&mut self) {
+        let doc = self.previous_doc();
         let global = self.parse_variable("Expect function name.");
         self.mark_initialized();
-        self.function(FunctionType::Function);
+        let arity = self.function(FunctionType::Function, doc);
         self.define_variable(global);
+
+        if self.current.as_ref().unwrap().scope_depth > 0 {
+            self.current.as_mut().unwrap().locals.last_mut().unwrap().arity = Some(arity);
+        } else {
+            self.global_arities
+                .entry(global)
+                .and_modify(|known| *known = None)
+                .or_insert(Some(arity));
+        }
     }
 
-    fn function(&mut self, function_type: FunctionType) {
+    /// Compiles a function body and emits the closure for it, returning the
+    /// function's arity.
+    fn function(&mut self, function_type: FunctionType, doc: Option<Rc<str>>) -> usize {
         let mut compiler = FunctionCompiler {
             enclosing: None,
             function: Function::new(),
@@ -389,6 +777,11 @@ impl<'a> Compiler<'a> {
             locals: Vec::with_capacity(vm::U8_COUNT),
             upvalues: Vec::with_capacity(vm::U8_COUNT),
             scope_depth: 0,
+            last_constant: None,
+            last_call: None,
+            last_store: None,
+            loops: Vec::new(),
+            try_depth: 0,
         };
         compiler.locals.push(Local {
             name: if function_type != FunctionType::Function {
@@ -396,13 +789,24 @@ impl<'a> Compiler<'a> {
             } else {
                 ""
             },
+            line: 0,
             depth: Some(0),
             is_captured: false,
+            is_read: false,
+            arity: None,
+            is_reassigned: false,
+            is_const: false,
+            calls: Vec::new(),
         });
 
         if function_type != FunctionType::Script {
-            let name = self.parser.previous.as_ref().unwrap().lexeme;
+            let previous = self.parser.previous.as_ref().unwrap();
+            let name = match previous.token_type {
+                TokenType::Fun => "anonymous",
+                _ => previous.lexeme,
+            };
             compiler.function.name = Some(Rc::from(name));
+            compiler.function.doc = doc;
         }
 
         compiler.enclosing = self.current.take();
@@ -410,26 +814,33 @@ impl<'a> Compiler<'a> {
 
         self.begin_scope();
 
-        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
-        if !self.check(TokenType::RightParen) {
-            loop {
-                self.current.as_mut().unwrap().function.arity += 1;
-                if self.current.as_ref().unwrap().function.arity > 255 {
-                    self.error_at_current("Can't have more than 255 parameters.");
-                }
-                let constant = self.parse_variable("Expect parameter name.");
-                self.define_variable(constant);
+        // A getter is declared with its body directly after the name.
+        if function_type != FunctionType::Getter {
+            self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+            if !self.check(TokenType::RightParen) {
+                loop {
+                    self.current.as_mut().unwrap().function.arity += 1;
+                    if self.current.as_ref().unwrap().function.arity > 255 {
+                        self.error_at_current("Can't have more than 255 parameters.");
+                    }
+                    let constant = self.parse_variable("Expect parameter name.");
+                    self.define_variable(constant);
+                    // Parameters are part of the signature, never reported unused.
+                    if let Some(local) = self.current.as_mut().unwrap().locals.last_mut() {
+                        local.is_read = true;
+                    }
 
-                if !self.match_token(TokenType::Comma) {
-                    break;
+                    if !self.match_token(TokenType::Comma) || self.check(TokenType::RightParen) {
+                        break;
+                    }
                 }
             }
+            self.consume(TokenType::RightParen, "Expect ')' after parameters.");
         }
-        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
         self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
         self.block();
 
-        let upvalue_data: Vec<(bool, u8)> = self
+        let upvalue_data: Vec<(bool, u16)> = self
             .current
             .as_ref()
             .unwrap()
@@ -439,16 +850,24 @@ impl<'a> Compiler<'a> {
             .collect();
 
         let function = self.end_compiler();
+        let arity = function.arity;
         let constant = self.make_constant(Value::Obj(Rc::new(Obj::Function(Rc::new(function)))));
         self.emit_bytes(OpCode::Closure.into(), constant);
 
         upvalue_data.into_iter().for_each(|(is_local, index)| {
+            let bytes = index.to_be_bytes();
             self.emit_byte(if is_local { 1 } else { 0 });
-            self.emit_byte(index);
+            self.emit_bytes(bytes[0], bytes[1]);
         });
+        arity
     }
 
     fn var_declaration(&mut self) {
+        if self.match_token(TokenType::LeftBracket) {
+            self.destructuring_declaration();
+            return;
+        }
+
         let global = self.parse_variable("Expect variable name.");
 
         if self.match_token(TokenType::Equal) {
@@ -462,9 +881,92 @@ impl<'a> Compiler<'a> {
             "Expect ';' after variable declaration.",
         );
         self.define_variable(global);
+        self.forget_global_arity(global);
+    }
+
+    /// `const name = value;`, a variable that can't be assigned after its
+    /// declaration.
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+        self.forget_global_arity(global);
+
+        if self.current.as_ref().unwrap().scope_depth > 0 {
+            self.define_variable(global);
+            self.current.as_mut().unwrap().locals.last_mut().unwrap().is_const = true;
+        } else {
+            self.const_globals.insert(global, true);
+            self.emit_global(OpCode::DefineConstGlobalSlot, global);
+        }
+    }
+
+    /// `var [a, b] = list;`, binding each name to the element of `list` at
+    /// its position. The list is evaluated once; a local one is kept in a
+    /// hidden slot below the new variables, a global one is popped after.
+    fn destructuring_declaration(&mut self) {
+        let is_local = self.current.as_ref().unwrap().scope_depth > 0;
+        if is_local {
+            self.add_local("");
+        }
+
+        let mut globals = Vec::new();
+        loop {
+            globals.push(self.parse_variable("Expect variable name."));
+            // A comma directly before ']' is a trailing comma.
+            if !self.match_token(TokenType::Comma) || self.check(TokenType::RightBracket) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after variable names.");
+        self.consume(TokenType::Equal, "Expect '=' after destructuring pattern.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        if !is_local {
+            for (index, &global) in globals.iter().enumerate() {
+                self.emit_byte(OpCode::Dup.into());
+                self.emit_element(index);
+                self.emit_global(OpCode::DefineGlobalSlot, global);
+                self.const_globals.insert(global, false);
+                self.forget_global_arity(global);
+            }
+            self.emit_byte(OpCode::Pop.into());
+            return;
+        }
+
+        let compiler = self.current.as_mut().unwrap();
+        let scope_depth = compiler.scope_depth;
+        let list_slot = compiler.locals.len().saturating_sub(globals.len() + 1);
+        compiler.locals[list_slot..]
+            .iter_mut()
+            .for_each(|local| local.depth = Some(scope_depth));
+
+        let get_op = if list_slot > u8::MAX as usize {
+            OpCode::GetLocalLong
+        } else {
+            OpCode::GetLocal
+        };
+        for index in 0..globals.len() {
+            self.emit_variable(get_op, list_slot);
+            self.emit_element(index);
+        }
+    }
+
+    /// Replaces the list on top of the stack with its element at `index`.
+    fn emit_element(&mut self, index: usize) {
+        self.emit_constant(Value::Int(index as i64));
+        self.emit_byte(OpCode::Index.into());
     }
 
-    fn parse_variable(&mut self, error_msg: &str) -> u8 {
+    fn parse_variable(&mut self, error_msg: &str) -> usize {
         self.consume(TokenType::Identifier, error_msg);
         self.declare_variable();
         if self.current.as_ref().unwrap().scope_depth > 0 {
@@ -472,7 +974,31 @@ impl<'a> Compiler<'a> {
         }
 
         let name = self.parser.previous.as_ref().unwrap().lexeme;
-        self.identifier_constant(name)
+        self.global_slot(name)
+    }
+
+    /// Records that global `slot` is bound to something other than a single
+    /// `fun` declaration, so calls through it can't be checked.
+    fn forget_global_arity(&mut self, slot: usize) {
+        if self.current.as_ref().unwrap().scope_depth == 0 {
+            self.global_arities.insert(slot, None);
+        }
+    }
+
+    fn global_slot(&mut self, name: &str) -> usize {
+        let interned_string = self.interner.intern(name);
+        self.globals.resolve(interned_string)
+    }
+
+    fn emit_global(&mut self, op: OpCode, slot: usize) {
+        if slot > u16::MAX as usize {
+            self.error("Too many global variables.");
+            return;
+        }
+
+        let bytes = (slot as u16).to_be_bytes();
+        self.emit_bytes(op.into(), bytes[0]);
+        self.emit_byte(bytes[1]);
     }
 
     fn identifier_constant(&mut self, name: &str) -> u8 {
@@ -507,25 +1033,33 @@ impl<'a> Compiler<'a> {
     }
 
     fn add_local(&mut self, name: &'a str) {
-        if self.current.as_ref().unwrap().locals.len() >= vm::U8_COUNT {
+        if self.current.as_ref().unwrap().locals.len() >= vm::U16_COUNT {
             self.error("Too many local variables in function.");
             return;
         }
 
+        let line = self.parser.previous.as_ref().map_or(0, |t| t.line);
         self.current.as_mut().unwrap().locals.push(Local {
             name,
+            line,
             depth: None,
             is_captured: false,
+            is_read: false,
+            arity: None,
+            is_reassigned: false,
+            is_const: false,
+            calls: Vec::new(),
         });
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, global: usize) {
         if self.current.as_ref().unwrap().scope_depth > 0 {
             self.mark_initialized();
             return;
         }
 
-        self.emit_bytes(OpCode::DefineGlobal.into(), global);
+        self.const_globals.insert(global, false);
+        self.emit_global(OpCode::DefineGlobalSlot, global);
     }
 
     fn mark_initialized(&mut self) {
@@ -542,13 +1076,25 @@ impl<'a> Compiler<'a> {
         if self.match_token(TokenType::Print) {
             self.print_statement();
         } else if self.match_token(TokenType::For) {
-            self.for_statement();
+            self.for_statement(None);
         } else if self.match_token(TokenType::If) {
             self.if_statement();
         } else if self.match_token(TokenType::Return) {
             self.return_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement();
         } else if self.match_token(TokenType::While) {
-            self.while_statement();
+            self.while_statement(None);
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.check(TokenType::Identifier)
+            && self.scanner.peek_token().token_type == TokenType::Colon
+        {
+            self.labeled_statement();
         } else if self.match_token(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -558,11 +1104,9 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    /// A `return` at the top level ends the script, giving its value to
+    /// `VM::eval`.
     fn return_statement(&mut self) {
-        if self.current.as_ref().unwrap().function_type == FunctionType::Script {
-            self.error("Can't return from top-level code.");
-        }
-
         if self.match_token(TokenType::Semicolon) {
             self.emit_return();
         } else {
@@ -572,10 +1116,60 @@ impl<'a> Compiler<'a> {
 
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.mark_tail_call();
             self.emit_byte(OpCode::Return.into());
         }
     }
 
+    /// Turns a `Call` that produces the value being returned into a
+    /// `TailCall`, which reuses the returning frame for the callee. A call
+    /// inside a `try` block is left alone, since the frame still owns the
+    /// block's exception handler.
+    fn mark_tail_call(&mut self) {
+        let compiler = self.current.as_ref().unwrap();
+        let count = compiler.function.chunk.count();
+        if compiler.try_depth == 0 && compiler.last_call.is_some_and(|call| call + 2 == count) {
+            self.current_chunk().code[count - 2] = OpCode::TailCall.into();
+        }
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_byte(OpCode::Throw.into());
+    }
+
+    fn try_statement(&mut self) {
+        let handler_jump = self.emit_jump(OpCode::PushHandler.into());
+
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        self.current.as_mut().unwrap().try_depth += 1;
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.current.as_mut().unwrap().try_depth -= 1;
+
+        self.emit_byte(OpCode::PopHandler.into());
+        let end_jump = self.emit_jump(OpCode::Jump.into());
+        self.patch_jump(handler_jump);
+
+        // The VM pushes the thrown value right where the try block started,
+        // so it lands in the slot of the catch variable.
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+        self.consume(TokenType::Identifier, "Expect exception variable name.");
+        self.begin_scope();
+        let name = self.parser.previous.as_ref().unwrap().lexeme;
+        self.add_local(name);
+        self.mark_initialized();
+        self.consume(TokenType::RightParen, "Expect ')' after catch variable.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before catch body.");
+        self.block();
+        self.end_scope();
+
+        self.patch_jump(end_jump);
+    }
+
     fn print_statement(&mut self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
@@ -601,7 +1195,34 @@ impl<'a> Compiler<'a> {
         self.patch_jump(else_jump);
     }
 
-    fn while_statement(&mut self) {
+    /// A loop preceded by `name:`, which `break name;` and `continue name;`
+    /// inside it can target.
+    fn labeled_statement(&mut self) {
+        self.advance();
+        let label = *self.parser.previous.as_ref().unwrap();
+        self.advance(); // The ':'.
+
+        let in_use = self
+            .current
+            .as_ref()
+            .unwrap()
+            .loops
+            .iter()
+            .any(|enclosing| enclosing.label == Some(label.lexeme));
+        if in_use {
+            self.error_at(&label, "A loop with this label already encloses this one.");
+        }
+
+        if self.match_token(TokenType::For) {
+            self.for_statement(Some(label.lexeme));
+        } else if self.match_token(TokenType::While) {
+            self.while_statement(Some(label.lexeme));
+        } else {
+            self.error_at_current("Expect loop after label.");
+        }
+    }
+
+    fn while_statement(&mut self, label: Option<&'a str>) {
         let loop_start = self.current_chunk().count();
 
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
@@ -610,18 +1231,25 @@ impl<'a> Compiler<'a> {
 
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse.into());
         self.emit_byte(OpCode::Pop.into());
-        self.statement();
+        self.loop_body(label, loop_start);
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(OpCode::Pop.into());
+        self.end_loop();
     }
 
-    fn for_statement(&mut self) {
+    fn for_statement(&mut self, label: Option<&'a str>) {
         self.begin_scope();
 
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
 
+        if self.at_for_in() {
+            self.for_in_loop(label);
+            self.end_scope();
+            return;
+        }
+
         if self.match_token(TokenType::Semicolon) {
             // No initializer.
         } else if self.match_token(TokenType::Var) {
@@ -646,7 +1274,7 @@ impl<'a> Compiler<'a> {
             let body_jump = self.emit_jump(OpCode::Jump.into());
             let increment_start = self.current_chunk().count();
             self.expression();
-            self.emit_byte(OpCode::Pop.into());
+            self.discard_value();
             self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
 
             self.emit_loop(loop_start);
@@ -654,20 +1282,208 @@ impl<'a> Compiler<'a> {
             self.patch_jump(body_jump);
         }
 
-        self.statement();
+        self.loop_body(label, loop_start);
         self.emit_loop(loop_start);
 
         if let Some(exit) = exit_jump {
             self.patch_jump(exit);
             self.emit_byte(OpCode::Pop.into()); // Condition.
         }
+        self.end_loop();
+
+        self.end_scope();
+    }
+
+    /// Whether the clauses of the `for` whose `(` was just consumed are
+    /// `x in sequence` or `var x in sequence`.
+    fn at_for_in(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut token = *self.parser.current.as_ref().unwrap();
+        if token.token_type == TokenType::Var {
+            token = scanner.scan_token();
+        }
+        token.token_type == TokenType::Identifier
+            && scanner.scan_token().token_type == TokenType::In
+    }
+
+    /// `for (x in sequence) body`, run once for each element the
+    /// sequence's iterator produces with `x` bound to it. `ForIn` replaces
+    /// the sequence with `sequence.iter()`, kept in a hidden local:
+    ///
+    /// ```text
+    /// start: iterator.hasNext() or exit
+    ///        { var x = iterator.next(); body }
+    ///        loop start
+    /// ```
+    fn for_in_loop(&mut self, label: Option<&'a str>) {
+        self.match_token(TokenType::Var);
+        self.consume(TokenType::Identifier, "Expect loop variable name.");
+        let name = self.parser.previous.as_ref().unwrap().lexeme;
+        self.consume(TokenType::In, "Expect 'in' after loop variable.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+        self.emit_byte(OpCode::ForIn.into());
+        self.add_local("");
+        self.mark_initialized();
+
+        let iterator_slot = self.current.as_ref().unwrap().locals.len() - 1;
+        let get_op = if iterator_slot > u8::MAX as usize {
+            OpCode::GetLocalLong
+        } else {
+            OpCode::GetLocal
+        };
+        let has_next = self.identifier_constant("hasNext");
+        let next = self.identifier_constant("next");
 
+        let loop_start = self.current_chunk().count();
+        self.emit_variable(get_op, iterator_slot);
+        self.emit_bytes(OpCode::Invoke.into(), has_next);
+        self.emit_byte(0);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse.into());
+        self.emit_byte(OpCode::Pop.into()); // Condition.
+
+        // The loop opens outside the variable's scope so that `continue`
+        // and `break` discard the variable along with the body's locals.
+        self.begin_loop(label, loop_start);
+        self.begin_scope();
+        self.emit_variable(get_op, iterator_slot);
+        self.emit_bytes(OpCode::Invoke.into(), next);
+        self.emit_byte(0);
+        self.add_local(name);
+        self.mark_initialized();
+        self.statement();
         self.end_scope();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop.into()); // Condition.
+        self.end_loop();
+    }
+
+    /// Compiles a loop's body with the loop open to `break` and `continue`.
+    /// The loop stays open until `end_loop`, which is where breaks land.
+    fn loop_body(&mut self, label: Option<&'a str>, continue_target: usize) {
+        self.begin_loop(label, continue_target);
+        self.statement();
+    }
+
+    fn begin_loop(&mut self, label: Option<&'a str>, continue_target: usize) {
+        let compiler = self.current.as_mut().unwrap();
+        let enclosing = Loop {
+            label,
+            continue_target,
+            scope_depth: compiler.scope_depth,
+            try_depth: compiler.try_depth,
+            breaks: Vec::new(),
+        };
+        compiler.loops.push(enclosing);
+    }
+
+    fn end_loop(&mut self) {
+        let closed = self.current.as_mut().unwrap().loops.pop().unwrap();
+        closed
+            .breaks
+            .into_iter()
+            .for_each(|jump| self.patch_jump(jump));
+    }
+
+    fn break_statement(&mut self) {
+        if let Some(index) = self.jump_target("break") {
+            let jump = self.emit_jump(OpCode::Jump.into());
+            self.current.as_mut().unwrap().loops[index].breaks.push(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        if let Some(index) = self.jump_target("continue") {
+            let target = self.current.as_ref().unwrap().loops[index].continue_target;
+            self.emit_loop(target);
+        }
+    }
+
+    /// Parses the optional label and `;` after `break` or `continue`, then
+    /// emits the code that leaves every scope and `try` block inside the
+    /// targeted loop. Returns that loop's index in `loops`.
+    fn jump_target(&mut self, keyword: &str) -> Option<usize> {
+        let keyword_token = *self.parser.previous.as_ref().unwrap();
+        let label = self
+            .match_token(TokenType::Identifier)
+            .then(|| *self.parser.previous.as_ref().unwrap());
+        self.consume(
+            TokenType::Semicolon,
+            &format!("Expect ';' after '{}'.", keyword),
+        );
+
+        let loops = &self.current.as_ref().unwrap().loops;
+        let index = match label {
+            Some(label) => loops
+                .iter()
+                .rposition(|enclosing| enclosing.label == Some(label.lexeme)),
+            None => loops.len().checked_sub(1),
+        };
+        let Some(index) = index else {
+            match label {
+                Some(label) => self.error_at(&label, "No enclosing loop has this label."),
+                None => self.error_at(
+                    &keyword_token,
+                    &format!("Can't use '{}' outside of a loop.", keyword),
+                ),
+            }
+            return None;
+        };
+
+        let compiler = self.current.as_ref().unwrap();
+        let target = &compiler.loops[index];
+        let handlers = compiler.try_depth - target.try_depth;
+        let exits: Vec<bool> = compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_some_and(|depth| depth > target.scope_depth))
+            .map(|local| local.is_captured)
+            .collect();
+
+        for is_captured in exits {
+            if is_captured {
+                self.emit_byte(OpCode::CloseUpvalue.into());
+            } else {
+                self.emit_byte(OpCode::Pop.into());
+            }
+        }
+        for _ in 0..handlers {
+            self.emit_byte(OpCode::PopHandler.into());
+        }
+        Some(index)
     }
 
     fn expression_statement(&mut self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.discard_value();
+    }
+
+    /// Pops the value of the expression just compiled. When that expression
+    /// ended with an assignment to a local or global, and no jump lands
+    /// after the store, the value on top is the one just stored, so the
+    /// store is turned into its popping form instead: `x = 1;` is
+    /// `SetLocalPop` rather than `SetLocal` and `Pop`.
+    fn discard_value(&mut self) {
+        let count = self.current_chunk().count();
+        let store = self.current.as_mut().unwrap().last_store.take();
+        if let Some(offset) = store.filter(|&offset| offset < count)
+            && offset + self.current_chunk().instruction_len(offset) == count
+        {
+            let popping = match self.current_chunk().code[offset].try_into() {
+                Ok(OpCode::SetLocal) => Some(OpCode::SetLocalPop),
+                Ok(OpCode::SetGlobalSlot) => Some(OpCode::SetGlobalSlotPop),
+                _ => None,
+            };
+            if let Some(op) = popping {
+                self.current_chunk().code[offset] = op.into();
+                return;
+            }
+        }
         self.emit_byte(OpCode::Pop.into());
     }
 
@@ -703,7 +1519,10 @@ impl<'a> Compiler<'a> {
                     }
                 }
 
-                if can_assign && self.match_token(TokenType::Equal) {
+                if can_assign
+                    && (self.match_token(TokenType::Equal)
+                        || self.match_token(TokenType::QuestionQuestionEqual))
+                {
                     self.error("Invalid assignment target.");
                 }
             }
@@ -720,16 +1539,41 @@ impl<'a> Compiler<'a> {
                 infix: Some(Self::call),
                 precedence: Precedence::Call,
             },
+            TokenType::LeftBrace => ParseRule {
+                prefix: Some(Self::map),
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::LeftBracket => ParseRule {
+                prefix: Some(Self::list),
+                infix: Some(Self::index),
+                precedence: Precedence::Call,
+            },
             TokenType::Dot => ParseRule {
                 prefix: None,
                 infix: Some(Self::dot),
                 precedence: Precedence::Call,
             },
+            TokenType::QuestionDot => ParseRule {
+                prefix: None,
+                infix: Some(Self::optional_dot),
+                precedence: Precedence::Call,
+            },
             TokenType::Minus => ParseRule {
                 prefix: Some(Self::unary),
                 infix: Some(Self::binary),
                 precedence: Precedence::Term,
             },
+            TokenType::PlusPlus => ParseRule {
+                prefix: None,
+                infix: Some(Self::invalid_postfix),
+                precedence: Precedence::Call,
+            },
+            TokenType::MinusMinus => ParseRule {
+                prefix: Some(Self::unary),
+                infix: Some(Self::invalid_postfix),
+                precedence: Precedence::Call,
+            },
             TokenType::Plus => ParseRule {
                 prefix: None,
                 infix: Some(Self::binary),
@@ -750,11 +1594,31 @@ impl<'a> Compiler<'a> {
                 infix: None,
                 precedence: Precedence::None,
             },
-            TokenType::Bang => ParseRule {
+            TokenType::Bang | TokenType::Tilde => ParseRule {
                 prefix: Some(Self::unary),
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Pipe => ParseRule {
+                prefix: None,
+                infix: Some(Self::binary),
+                precedence: Precedence::BitOr,
+            },
+            TokenType::Caret => ParseRule {
+                prefix: None,
+                infix: Some(Self::binary),
+                precedence: Precedence::BitXor,
+            },
+            TokenType::Ampersand => ParseRule {
+                prefix: None,
+                infix: Some(Self::binary),
+                precedence: Precedence::BitAnd,
+            },
+            TokenType::LessLess | TokenType::GreaterGreater => ParseRule {
+                prefix: None,
+                infix: Some(Self::binary),
+                precedence: Precedence::Shift,
+            },
             TokenType::BangEqual | TokenType::EqualEqual => ParseRule {
                 prefix: None,
                 infix: Some(Self::binary),
@@ -763,7 +1627,8 @@ impl<'a> Compiler<'a> {
             TokenType::Greater
             | TokenType::GreaterEqual
             | TokenType::Less
-            | TokenType::LessEqual => ParseRule {
+            | TokenType::LessEqual
+            | TokenType::Is => ParseRule {
                 prefix: None,
                 infix: Some(Self::binary),
                 precedence: Precedence::Comparison,
@@ -773,6 +1638,16 @@ impl<'a> Compiler<'a> {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Interpolation => ParseRule {
+                prefix: Some(Self::interpolation),
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Fun => ParseRule {
+                prefix: Some(Self::function_expression),
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Identifier => ParseRule {
                 prefix: Some(Self::variable),
                 infix: None,
@@ -807,15 +1682,16 @@ impl<'a> Compiler<'a> {
     }
 
     fn number(&mut self, _can_assign: bool) {
-        let value: f64 = self
-            .parser
-            .previous
-            .as_ref()
-            .unwrap()
-            .lexeme
-            .parse()
-            .unwrap();
-        self.emit_constant(Value::Number(value));
+        let lexeme = self.parser.previous.as_ref().unwrap().lexeme;
+        let digits = lexeme.replace('_', "");
+        // Literals without a fraction are integers unless they overflow.
+        let value = match (digits.parse(), digits.parse::<f64>()) {
+            (Ok(i), _) if !lexeme.contains('.') => Value::Int(i),
+            (_, Ok(n)) if n.is_finite() => Value::Number(n),
+            (_, Ok(_)) => return self.error("Number literal is too large."),
+            (_, Err(_)) => return self.error("Invalid number literal."),
+        };
+        self.emit_constant(value);
     }
 
     fn literal(&mut self, _can_assign: bool) {
@@ -828,22 +1704,84 @@ impl<'a> Compiler<'a> {
     }
 
     fn string(&mut self, _can_assign: bool) {
-        let lexeme = self.parser.previous.as_ref().unwrap().lexeme;
-        let string_value = &lexeme[1..lexeme.len() - 1];
-        let interned_string = self.interner.intern(string_value);
+        let token = *self.parser.previous.as_ref().unwrap();
+        if token.continues_string() {
+            self.error("Expect expression.");
+            return;
+        }
+        let interned_string = self.interner.intern(&token.string_value());
         let value = Value::Obj(Rc::new(Obj::String(interned_string)));
         self.emit_constant(value);
     }
 
+    /// `"a ${b} c"`: pushes each non-empty text segment and the value of each
+    /// embedded expression in order, then joins them with one `BuildString`.
+    fn interpolation(&mut self, _can_assign: bool) {
+        let mut token = *self.parser.previous.as_ref().unwrap();
+        if token.continues_string() {
+            self.error("Expect expression.");
+            return;
+        }
+
+        let mut parts = 0;
+        loop {
+            parts += self.string_segment(&token);
+
+            if self.parser.current.as_ref().unwrap().continues_string() {
+                self.error_at_current("Expect expression in string interpolation.");
+            } else {
+                self.expression();
+                parts += 1;
+            }
+
+            let current = *self.parser.current.as_ref().unwrap();
+            if !current.continues_string() {
+                self.error_at_current("Expect '}' after expression in string interpolation.");
+                break;
+            }
+            self.advance();
+            token = current;
+            if token.token_type == TokenType::String {
+                parts += self.string_segment(&token);
+                break;
+            }
+        }
+
+        if parts > u8::MAX as usize {
+            self.error("Too many parts in string interpolation.");
+            return;
+        }
+        self.emit_bytes(OpCode::BuildString.into(), parts as u8);
+    }
+
+    /// Pushes the text of one segment of an interpolated string, returning
+    /// how many values that added: none when the segment is empty.
+    fn string_segment(&mut self, token: &Token) -> usize {
+        let text = token.string_value();
+        if text.is_empty() {
+            return 0;
+        }
+        let interned_string = self.interner.intern(&text);
+        self.emit_constant(Value::Obj(Rc::new(Obj::String(interned_string))));
+        1
+    }
+
     fn variable(&mut self, can_assign: bool) {
         let name = self.parser.previous.as_ref().unwrap().lexeme;
         self.named_variable(name, can_assign);
     }
 
     fn this_(&mut self, _can_assign: bool) {
-        if self.current_class.is_none() {
-            self.error("Can't use 'this' outside of a class.");
-            return;
+        match &self.current_class {
+            None => {
+                self.error("Can't use 'this' outside of a class.");
+                return;
+            }
+            Some(class_compiler) if class_compiler.in_static_method => {
+                self.error("Can't use 'this' in a static method.");
+                return;
+            }
+            _ => {}
         }
         self.variable(false);
     }
@@ -853,6 +1791,9 @@ impl<'a> Compiler<'a> {
             None => {
                 self.error("Can't use 'super' outside of a class.");
             }
+            Some(class_compiler) if class_compiler.in_static_method => {
+                self.error("Can't use 'super' in a static method.");
+            }
             Some(class_compiler) if !class_compiler.has_superclass => {
                 self.error("Can't use 'super' in a class with no superclass.");
             }
@@ -877,24 +1818,137 @@ impl<'a> Compiler<'a> {
     }
 
     fn named_variable(&mut self, name: &str, can_assign: bool) {
-        let (get_op, set_op, arg) = if let Some(arg) = self.resolve_local(name) {
-            (OpCode::GetLocal.into(), OpCode::SetLocal.into(), arg)
+        let (get_op, set_op, arg) = self.resolve_variable(name);
+        let token = *self.parser.previous.as_ref().unwrap();
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.check_assignable(&token, set_op, arg);
+            self.expression();
+            let offset = self.current_chunk().count();
+            self.emit_variable(set_op, arg);
+            self.current.as_mut().unwrap().last_store = Some(offset);
+            self.mark_reassigned(name, set_op, arg);
+        } else if can_assign && self.match_token(TokenType::QuestionQuestionEqual) {
+            self.check_assignable(&token, set_op, arg);
+            // A value other than nil is kept as the result and the right
+            // side never runs.
+            self.emit_variable(get_op, arg);
+            let end_jump = self.emit_jump(OpCode::JumpIfNotNil.into());
+            self.emit_byte(OpCode::Pop.into());
+            self.expression();
+            self.emit_variable(set_op, arg);
+            self.patch_jump(end_jump);
+            self.mark_reassigned(name, set_op, arg);
+        } else if let Some(delta) = self.match_postfix() {
+            self.check_assignable(&token, set_op, arg);
+            // Leave the old value below the update: get, get, step, set, pop.
+            self.emit_variable(get_op, arg);
+            self.emit_variable(get_op, arg);
+            self.emit_step(delta);
+            self.emit_variable(set_op, arg);
+            self.emit_byte(OpCode::Pop.into());
+            self.mark_reassigned(name, set_op, arg);
+        } else {
+            self.emit_variable(get_op, arg);
+            let end = self.current_chunk().count();
+            self.last_variable = Some((token, end));
+        }
+    }
+
+    /// The instructions that read and write the variable `name`, and their
+    /// operand.
+    fn resolve_variable(&mut self, name: &str) -> (OpCode, OpCode, usize) {
+        if let Some(arg) = self.resolve_local(name) {
+            if arg > u8::MAX as usize {
+                (OpCode::GetLocalLong, OpCode::SetLocalLong, arg)
+            } else {
+                (OpCode::GetLocal, OpCode::SetLocal, arg)
+            }
         } else if let Some(arg) = self.resolve_upvalue(name) {
-            (OpCode::GetUpvalue.into(), OpCode::SetUpvalue.into(), arg)
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, arg as usize)
         } else {
-            let arg = self.identifier_constant(name);
-            (OpCode::GetGlobal.into(), OpCode::SetGlobal.into(), arg)
+            (OpCode::GetGlobalSlot, OpCode::SetGlobalSlot, self.global_slot(name))
+        }
+    }
+
+    /// Reports an assignment to `name` if it was declared with `const`.
+    fn check_assignable(&mut self, name: &Token<'a>, set_op: OpCode, arg: usize) {
+        let is_const = if set_op == OpCode::SetGlobalSlot {
+            match self.const_globals.get(&arg) {
+                Some(&is_const) => is_const,
+                None => self.globals.is_const(arg),
+            }
+        } else {
+            self.declared_local(name.lexeme)
+                .is_some_and(|local| local.is_const)
         };
+        if is_const {
+            let message = format!("Cannot assign to constant '{}'.", name.lexeme);
+            self.error_at(name, &message);
+        }
+    }
 
-        if can_assign && self.match_token(TokenType::Equal) {
-            self.expression();
-            self.emit_bytes(set_op, arg);
+    fn mark_reassigned(&mut self, name: &str, set_op: OpCode, arg: usize) {
+        if set_op == OpCode::SetGlobalSlot {
+            self.global_arities.insert(arg, None);
+        } else if let Some(local) = self.declared_local(name) {
+            local.is_reassigned = true;
+        }
+    }
+
+    /// Finds the local `name` resolves to, in the current function or, as
+    /// an upvalue, in an enclosing one.
+    fn declared_local(&mut self, name: &str) -> Option<&mut Local<'a>> {
+        let mut compiler = self.current.as_deref_mut()?;
+        if let Some(i) = compiler.locals.iter().rposition(|l| l.name == name) {
+            return Some(&mut compiler.locals[i]);
+        }
+        loop {
+            compiler = compiler.enclosing.as_deref_mut()?;
+            if let Some(i) = compiler.locals.iter().rposition(|l| l.name == name)
+                && compiler.locals[i].depth.is_some()
+            {
+                return Some(&mut compiler.locals[i]);
+            }
+        }
+    }
+
+    fn emit_variable(&mut self, op: OpCode, arg: usize) {
+        match op {
+            OpCode::GetGlobalSlot | OpCode::SetGlobalSlot => self.emit_global(op, arg),
+            OpCode::GetLocalLong | OpCode::SetLocalLong => {
+                let bytes = (arg as u16).to_be_bytes();
+                self.emit_bytes(op.into(), bytes[0]);
+                self.emit_byte(bytes[1]);
+            }
+            _ => self.emit_bytes(op.into(), arg as u8),
+        }
+
+        if matches!(op, OpCode::GetLocal | OpCode::GetLocalLong) {
+            self.current.as_mut().unwrap().locals[arg].is_read = true;
+        }
+    }
+
+    /// Consumes a postfix `++` or `--`, returning the step it applies.
+    fn match_postfix(&mut self) -> Option<i64> {
+        if self.match_token(TokenType::PlusPlus) {
+            Some(1)
+        } else if self.match_token(TokenType::MinusMinus) {
+            Some(-1)
         } else {
-            self.emit_bytes(get_op, arg);
+            None
         }
     }
 
-    fn resolve_local(&mut self, name: &str) -> Option<u8> {
+    /// Adds `delta` to the number on top of the stack. This subtracts the
+    /// negated step rather than adding, because `+` would quietly
+    /// concatenate onto a string where `++` should raise an error.
+    fn emit_step(&mut self, delta: i64) {
+        self.emit_constant(Value::Int(-delta));
+        self.emit_byte(OpCode::Subtract.into());
+    }
+
+    fn resolve_local(&mut self, name: &str) -> Option<usize> {
         let result = self
             .current
             .as_ref()
@@ -907,11 +1961,11 @@ impl<'a> Compiler<'a> {
             .map(|(i, local)| (i, local.depth.is_none()));
 
         match result {
-            Some((i, is_uninitialized)) if is_uninitialized => {
+            Some((_, is_uninitialized)) if is_uninitialized => {
                 self.error("Can't read local variable in its own initializer.");
                 None
             }
-            Some((i, _)) => Some(i as u8),
+            Some((i, _)) => Some(i),
             None => None,
         }
     }
@@ -936,8 +1990,9 @@ impl<'a> Compiler<'a> {
         if let Some(local) = local_result {
             if let Some(enclosing) = self.current.as_mut().unwrap().enclosing.as_mut() {
                 enclosing.locals[local].is_captured = true;
+                enclosing.locals[local].is_read = true;
             }
-            return Some(self.add_upvalue(local as u8, true));
+            return Some(self.add_upvalue(local as u16, true));
         }
 
         let mut enclosing = self.current.as_mut().unwrap().enclosing.take().unwrap();
@@ -946,12 +2001,18 @@ impl<'a> Compiler<'a> {
             parser: Parser {
                 current: None,
                 previous: None,
-                had_error: false,
+                diagnostics: Vec::new(),
                 panic_mode: false,
             },
             current: Some(enclosing),
             current_class: None,
             interner: StringInterner::new(),
+            globals: Globals::default(),
+            options: CompileOptions::default(),
+            global_arities: HashMap::new(),
+            global_calls: Vec::new(),
+            const_globals: HashMap::new(),
+            last_variable: None,
         };
 
         let upvalue_result = temp_state.resolve_upvalue(name);
@@ -960,13 +2021,13 @@ impl<'a> Compiler<'a> {
         self.current.as_mut().unwrap().enclosing = Some(enclosing);
 
         if let Some(upvalue) = upvalue_result {
-            return Some(self.add_upvalue(upvalue, false));
+            return Some(self.add_upvalue(upvalue as u16, false));
         }
 
         None
     }
 
-    fn add_upvalue(&mut self, index: u8, is_local: bool) -> u8 {
+    fn add_upvalue(&mut self, index: u16, is_local: bool) -> u8 {
         let upvalue_count = self.current.as_ref().unwrap().function.upvalue_count;
 
         if let Some(i) = self.current.as_ref().unwrap().upvalues[..upvalue_count]
@@ -987,14 +2048,60 @@ impl<'a> Compiler<'a> {
         (upvalue_count) as u8
     }
 
+    /// `fun (params) { body }` in an expression: a function without a name,
+    /// so `(fun () { return 42; })()` calls one as soon as it is made.
+    fn function_expression(&mut self, _can_assign: bool) {
+        // A named function is a declaration, which can't appear where an
+        // expression is expected, as in `if (x) fun f() {}`.
+        if self.check(TokenType::Identifier) {
+            self.error("Expect expression.");
+            return;
+        }
+        self.function(FunctionType::Function, None);
+    }
+
     fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
     fn call(&mut self, _can_assign: bool) {
+        // Only a variable read directly before the '(' is the callee.
+        let count = self.current_chunk().count();
+        let callee = self
+            .last_variable
+            .take()
+            .filter(|&(_, end)| end == count)
+            .map(|(name, _)| name);
+
         let arg_count = self.argument_list();
+        let offset = self.current_chunk().count();
         self.emit_bytes(OpCode::Call.into(), arg_count);
+        self.current.as_mut().unwrap().last_call = Some(offset);
+
+        // A malformed argument list has already been reported.
+        if let Some(name) = callee.filter(|_| !self.parser.panic_mode) {
+            self.record_call(DirectCall { name, arg_count });
+        }
+    }
+
+    /// Remembers a call by name so its argument count can be checked once
+    /// the callee's declaration and every assignment to it have been seen.
+    fn record_call(&mut self, call: DirectCall<'a>) {
+        if let Some(local) = self.declared_local(call.name.lexeme) {
+            local.calls.push(call);
+        } else {
+            let slot = self.global_slot(call.name.lexeme);
+            self.global_calls.push((slot, call));
+        }
+    }
+
+    /// `a?.b` is `a.b`, or `nil` without touching `b` when `a` is `nil`.
+    /// The nil receiver is simply left on the stack as the result.
+    fn optional_dot(&mut self, _can_assign: bool) {
+        let nil_jump = self.emit_jump(OpCode::JumpIfNil.into());
+        self.dot(false);
+        self.patch_jump(nil_jump);
     }
 
     fn dot(&mut self, can_assign: bool) {
@@ -1005,12 +2112,151 @@ impl<'a> Compiler<'a> {
         if can_assign && self.match_token(TokenType::Equal) {
             self.expression();
             self.emit_bytes(OpCode::SetProperty.into(), name_constant);
+        } else if can_assign && self.match_token(TokenType::QuestionQuestionEqual) {
+            // Read the field off a copy of the receiver. A value other than
+            // nil replaces the receiver; nil is dropped for the store.
+            self.emit_byte(OpCode::Dup.into());
+            self.emit_get_property(name_constant);
+            let keep_jump = self.emit_jump(OpCode::JumpIfNotNil.into());
+            self.emit_byte(OpCode::Pop.into());
+            self.expression();
+            self.emit_bytes(OpCode::SetProperty.into(), name_constant);
+            let end_jump = self.emit_jump(OpCode::Jump.into());
+            self.patch_jump(keep_jump);
+            self.emit_byte(OpCode::Swap.into());
+            self.emit_byte(OpCode::Pop.into());
+            self.patch_jump(end_jump);
         } else if self.match_token(TokenType::LeftParen) {
             let arg_count = self.argument_list();
             self.emit_bytes(OpCode::Invoke.into(), name_constant);
             self.emit_byte(arg_count);
+        } else if let Some(delta) = self.match_postfix() {
+            // The receiver is evaluated once; juggle it so the old value
+            // ends up alone on the stack after the store.
+            self.emit_byte(OpCode::Dup.into());
+            self.emit_get_property(name_constant);
+            self.emit_byte(OpCode::Swap.into());
+            self.emit_byte(OpCode::Over.into());
+            self.emit_step(delta);
+            self.emit_bytes(OpCode::SetProperty.into(), name_constant);
+            self.emit_byte(OpCode::Pop.into());
+        } else {
+            self.emit_get_property(name_constant);
+        }
+    }
+
+    fn emit_get_property(&mut self, name_constant: u8) {
+        self.emit_bytes(OpCode::GetProperty.into(), name_constant);
+        let cache = self.current_chunk().add_property_cache();
+        if cache > u16::MAX as usize {
+            self.error("Too many property accesses in one chunk.");
+        }
+        let bytes = (cache as u16).to_be_bytes();
+        self.emit_bytes(bytes[0], bytes[1]);
+    }
+
+    /// A postfix `++` or `--` after something that isn't a variable or
+    /// field, like `f()++`. Valid targets consume the operator themselves.
+    fn invalid_postfix(&mut self, _can_assign: bool) {
+        self.error("Invalid increment target.");
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::IndexSet.into());
         } else {
-            self.emit_bytes(OpCode::GetProperty.into(), name_constant);
+            self.emit_byte(OpCode::Index.into());
+        }
+    }
+
+    /// A map literal, `{key: value, ...}`. Entries are added one at a time
+    /// so the map never needs more than three stack slots to build.
+    fn map(&mut self, _can_assign: bool) {
+        self.emit_byte(OpCode::Map.into());
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+                self.emit_byte(OpCode::MapEntry.into());
+                // A comma directly before '}' is a trailing comma.
+                if !self.match_token(TokenType::Comma) || self.check(TokenType::RightBrace) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+    }
+
+    fn list(&mut self, can_assign: bool) {
+        if can_assign && self.at_destructuring_assignment() {
+            self.destructuring_assignment();
+            return;
+        }
+
+        self.emit_byte(OpCode::List.into());
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                self.emit_byte(OpCode::ListAppend.into());
+                // A comma directly before ']' is a trailing comma.
+                if !self.match_token(TokenType::Comma) || self.check(TokenType::RightBracket) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.");
+    }
+
+    /// Whether the `[` just consumed opens the targets of a destructuring
+    /// assignment, `[a, b] = ...`, rather than a list literal.
+    fn at_destructuring_assignment(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut token = *self.parser.current.as_ref().unwrap();
+        loop {
+            if token.token_type != TokenType::Identifier {
+                return false;
+            }
+            token = scanner.scan_token();
+            match token.token_type {
+                TokenType::Comma => token = scanner.scan_token(),
+                TokenType::RightBracket => {}
+                _ => return false,
+            }
+            if token.token_type == TokenType::RightBracket {
+                return scanner.scan_token().token_type == TokenType::Equal;
+            }
+        }
+    }
+
+    /// `[a, b] = list`, assigning each variable the element of `list` at its
+    /// position. Like any assignment it evaluates to the assigned value, the
+    /// whole list, so `[a, b] = [b, a]` swaps.
+    fn destructuring_assignment(&mut self) {
+        let mut names = Vec::new();
+        loop {
+            self.consume(TokenType::Identifier, "Expect variable name.");
+            names.push(*self.parser.previous.as_ref().unwrap());
+            if !self.match_token(TokenType::Comma) || self.check(TokenType::RightBracket) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after variable names.");
+        self.consume(TokenType::Equal, "Expect '=' after destructuring pattern.");
+        self.expression();
+
+        for (index, name) in names.into_iter().enumerate() {
+            let (_, set_op, arg) = self.resolve_variable(name.lexeme);
+            self.check_assignable(&name, set_op, arg);
+            self.emit_byte(OpCode::Dup.into());
+            self.emit_element(index);
+            self.emit_variable(set_op, arg);
+            self.mark_reassigned(name.lexeme, set_op, arg);
+            self.emit_byte(OpCode::Pop.into());
         }
     }
 
@@ -1024,7 +2270,8 @@ impl<'a> Compiler<'a> {
                 } else {
                     arg_count += 1;
                 }
-                if !self.match_token(TokenType::Comma) {
+                // A comma directly before ')' is a trailing comma.
+                if !self.match_token(TokenType::Comma) || self.check(TokenType::RightParen) {
                     break;
                 }
             }
@@ -1035,12 +2282,28 @@ impl<'a> Compiler<'a> {
 
     fn unary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.as_ref().unwrap().token_type;
+        let operand = self.current_chunk().count();
 
         self.parse_precedence(Precedence::Unary);
 
+        if operator_type == TokenType::Minus
+            && let Some(negated) = self
+                .constant_at(operand)
+                .and_then(|n| n.negate())
+        {
+            self.replace_with_constant(operand, negated);
+            return;
+        }
+
         match operator_type {
             TokenType::Minus => self.emit_byte(OpCode::Negate.into()),
+            // There is no prefix decrement; `--x` is still `-(-x)`.
+            TokenType::MinusMinus => {
+                self.emit_byte(OpCode::Negate.into());
+                self.emit_byte(OpCode::Negate.into());
+            }
             TokenType::Bang => self.emit_byte(OpCode::Not.into()),
+            TokenType::Tilde => self.emit_byte(OpCode::BitNot.into()),
             _ => unreachable!(),
         }
     }
@@ -1048,23 +2311,86 @@ impl<'a> Compiler<'a> {
     fn binary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.as_ref().unwrap().token_type;
         let rule = Self::get_rule(operator_type);
+        let left = self.current_chunk().count().checked_sub(2);
+        let left_constant = left.and_then(|offset| self.constant_at(offset));
+        let right = self.current_chunk().count();
         self.parse_precedence(rule.precedence.next());
+        self.warn_if_chained(operator_type);
+
+        // Fold arithmetic on two number literals, or `+` on two string
+        // literals, into one constant. Numbers are computed with the same
+        // function the VM would use, so `1 / 0` still yields infinity and
+        // integer overflow still promotes.
+        if let (Some(left), Some(a)) = (left, left_constant)
+            && let Some(b) = self.constant_at(right)
+        {
+            let folded = match operator_type {
+                TokenType::Plus => value::add(&a, &b).or_else(|| self.concatenate(&a, &b)),
+                TokenType::Minus => value::subtract(&a, &b),
+                TokenType::Star => value::multiply(&a, &b),
+                TokenType::Slash => value::divide(&a, &b),
+                _ => None,
+            };
+            if let Some(value) = folded {
+                self.replace_with_constant(left, value);
+                return;
+            }
+        }
 
         match operator_type {
             TokenType::Plus => self.emit_byte(OpCode::Add.into()),
             TokenType::Minus => self.emit_byte(OpCode::Subtract.into()),
             TokenType::Star => self.emit_byte(OpCode::Multiply.into()),
             TokenType::Slash => self.emit_byte(OpCode::Divide.into()),
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal.into(), OpCode::Not.into()),
+            TokenType::BangEqual => self.emit_byte(OpCode::NotEqual.into()),
             TokenType::EqualEqual => self.emit_byte(OpCode::Equal.into()),
             TokenType::Greater => self.emit_byte(OpCode::Greater.into()),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less.into(), OpCode::Not.into()),
+            TokenType::GreaterEqual => self.emit_byte(OpCode::GreaterEqual.into()),
             TokenType::Less => self.emit_byte(OpCode::Less.into()),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater.into(), OpCode::Not.into()),
+            TokenType::LessEqual => self.emit_byte(OpCode::LessEqual.into()),
+            TokenType::Ampersand => self.emit_byte(OpCode::BitAnd.into()),
+            TokenType::Pipe => self.emit_byte(OpCode::BitOr.into()),
+            TokenType::Caret => self.emit_byte(OpCode::BitXor.into()),
+            TokenType::LessLess => self.emit_byte(OpCode::ShiftLeft.into()),
+            TokenType::Is => self.emit_byte(OpCode::IsInstance.into()),
+            TokenType::GreaterGreater => self.emit_byte(OpCode::ShiftRight.into()),
             _ => unreachable!(),
         }
     }
 
+    /// Warns about `a < b < c`, which compares the boolean `a < b` with `c`
+    /// rather than checking that `b` lies between `a` and `c`.
+    fn warn_if_chained(&self, operator_type: TokenType) {
+        if !self.options.warnings {
+            return;
+        }
+        let next = self.parser.current.as_ref().unwrap();
+        let (Some(first), Some(second)) = (
+            comparison_lexeme(operator_type),
+            comparison_lexeme(next.token_type),
+        ) else {
+            return;
+        };
+
+        eprintln!(
+            "[line {}] Warning: 'a {} b {} c' compares the boolean 'a {} b' with 'c'; \
+             write 'a {} b and b {} c'.",
+            next.line, first, second, first, first, second
+        );
+    }
+
+    /// Joins two string constants into one interned string.
+    fn concatenate(&mut self, a: &Value, b: &Value) -> Option<Value> {
+        let (Value::Obj(a), Value::Obj(b)) = (a, b) else {
+            return None;
+        };
+        let (Obj::String(a), Obj::String(b)) = (&**a, &**b) else {
+            return None;
+        };
+        let joined = self.interner.intern(&format!("{}{}", a, b));
+        Some(Value::Obj(Rc::new(Obj::String(joined))))
+    }
+
     fn and_(&mut self, _can_assign: bool) {
         let end_jump = self.emit_jump(OpCode::JumpIfFalse.into());
 
@@ -1075,13 +2401,11 @@ impl<'a> Compiler<'a> {
     }
 
     fn or_(&mut self, _can_assign: bool) {
-        let else_jump = self.emit_jump(OpCode::JumpIfFalse.into());
-        let end_jump = self.emit_jump(OpCode::Jump.into());
+        let end_jump = self.emit_jump(OpCode::JumpIfTrue.into());
 
-        self.patch_jump(else_jump);
         self.emit_byte(OpCode::Pop.into());
-
         self.parse_precedence(Precedence::Or);
+
         self.patch_jump(end_jump);
     }
 
@@ -1095,13 +2419,19 @@ impl<'a> Compiler<'a> {
 
             match self.parser.current.as_ref().map(|t| t.token_type) {
                 Some(TokenType::Class)
+                | Some(TokenType::Const)
+                | Some(TokenType::Enum)
                 | Some(TokenType::Fun)
                 | Some(TokenType::Var)
                 | Some(TokenType::For)
                 | Some(TokenType::If)
                 | Some(TokenType::While)
                 | Some(TokenType::Print)
-                | Some(TokenType::Return) => return,
+                | Some(TokenType::Return)
+                | Some(TokenType::Throw)
+                | Some(TokenType::Try)
+                | Some(TokenType::Break)
+                | Some(TokenType::Continue) => return,
                 _ => {} // Do nothing.
             }
 
@@ -1114,19 +2444,22 @@ impl<'a> Compiler<'a> {
             return;
         }
         self.parser.panic_mode = true;
+        self.report(token, message);
+    }
 
-        eprint!("[line {}] Error", token.line);
-
-        if token.token_type == TokenType::Eof {
-            eprint!(" at end");
-        } else if token.token_type == TokenType::Error {
-            // Nothing.
-        } else {
-            eprint!(" at '{}'", token.lexeme);
-        }
-
-        eprintln!(": {}", message);
-        self.parser.had_error = true;
+    /// Records a compile error, which fails the compilation.
+    fn report(&mut self, token: &Token, message: &str) {
+        let at = match token.token_type {
+            TokenType::Eof => Some("end".to_string()),
+            TokenType::Error => None,
+            _ => Some(format!("'{}'", token.lexeme)),
+        };
+        self.parser.diagnostics.push(CompileDiagnostic {
+            line: token.line,
+            column: token.column,
+            at,
+            message: message.to_string(),
+        });
     }
 
     fn error(&mut self, message: &str) {
@@ -1,132 +1,329 @@
 use crate::{
     chunk::{Chunk, OpCode},
-    value::{Obj, Value},
+    scanner::{Token, TokenType},
+    value::{Function, Obj, Value},
 };
+use std::fmt::Write;
+
+/// Prints `function`'s chunk followed by every function nested in its
+/// constant table, depth first.
+pub fn disassemble_function(function: &Function) {
+    let name = function.name.as_deref().unwrap_or("<script>");
+    disassemble_chunk(&function.chunk, name);
+
+    function.chunk.constants.iter().for_each(|constant| {
+        if let Value::Obj(obj) = constant
+            && let Obj::Function(nested) = &**obj
+        {
+            println!();
+            disassemble_function(nested);
+        }
+    });
+}
+
+/// Prints `token` as jlox's scanner does: its type in upper snake case, its
+/// lexeme, and the value of a string or number literal (`null` otherwise).
+pub fn print_token(token: &Token) {
+    let literal = match token.token_type {
+        TokenType::String | TokenType::Interpolation => token.string_value().into_owned(),
+        TokenType::Number => {
+            let n: f64 = token.lexeme.replace('_', "").parse().unwrap();
+            if n.fract() == 0.0 {
+                format!("{:.1}", n)
+            } else {
+                n.to_string()
+            }
+        }
+        _ => "null".to_string(),
+    };
+    println!(
+        "{} {} {}",
+        token_type_name(token.token_type),
+        token.lexeme,
+        literal
+    );
+}
+
+/// `LeftParen` as `LEFT_PAREN`, and so on, the way jlox names token types.
+fn token_type_name(token_type: TokenType) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", token_type).chars() {
+        if c.is_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
+
+pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
+    print!("{}", disassemble_chunk_to_string(chunk, name));
+}
+
+/// The text `disassemble_chunk` prints, for callers that want to inspect or
+/// compare it rather than write it to stdout.
+pub fn disassemble_chunk_to_string(chunk: &Chunk, name: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "== {} ==", name).unwrap();
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = write_instruction(&mut out, chunk, offset);
+    }
+    out
+}
+
+/// Prints the value stack, bottom first, on the line before an instruction
+/// in a trace.
+pub fn print_stack(stack: &[Value]) {
+    print!("          ");
+    stack.iter().for_each(|slot| print!("[ {} ]", slot));
+    println!();
+}
 
-#[allow(dead_code)]
 pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
+    let mut out = String::new();
+    let next = write_instruction(&mut out, chunk, offset);
+    print!("{}", out);
+    next
+}
+
+/// Appends the line (or lines, for a closure's upvalues) describing the
+/// instruction at `offset` to `out` and returns the next instruction's offset.
+fn write_instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize {
+    write!(out, "{:04} ", offset).unwrap();
 
     if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ");
+        write!(out, "   | ").unwrap();
     } else {
-        print!("{:4} ", chunk.lines[offset]);
+        write!(out, "{:4} ", chunk.lines[offset]).unwrap();
     }
 
     let instruction = chunk.code[offset];
     match instruction.try_into().ok() {
-        Some(OpCode::Constant) => constant_instruction("OP_CONSTANT", chunk, offset),
-        Some(OpCode::Nil) => simple_instruction("OP_NIL", offset),
-        Some(OpCode::True) => simple_instruction("OP_TRUE", offset),
-        Some(OpCode::False) => simple_instruction("OP_FALSE", offset),
-        Some(OpCode::Pop) => simple_instruction("OP_POP", offset),
-        Some(OpCode::GetLocal) => byte_instruction("OP_GET_LOCAL", chunk, offset),
-        Some(OpCode::SetLocal) => byte_instruction("OP_SET_LOCAL", chunk, offset),
-        Some(OpCode::GetGlobal) => constant_instruction("OP_GET_GLOBAL", chunk, offset),
-        Some(OpCode::DefineGlobal) => constant_instruction("OP_DEFINE_GLOBAL", chunk, offset),
-        Some(OpCode::SetGlobal) => constant_instruction("OP_SET_GLOBAL", chunk, offset),
-        Some(OpCode::GetUpvalue) => byte_instruction("OP_GET_UPVALUE", chunk, offset),
-        Some(OpCode::SetUpvalue) => byte_instruction("OP_SET_UPVALUE", chunk, offset),
-        Some(OpCode::GetProperty) => constant_instruction("OP_GET_PROPERTY", chunk, offset),
-        Some(OpCode::SetProperty) => constant_instruction("OP_SET_PROPERTY", chunk, offset),
-        Some(OpCode::GetSuper) => constant_instruction("OP_GET_SUPER", chunk, offset),
-        Some(OpCode::Equal) => simple_instruction("OP_EQUAL", offset),
-        Some(OpCode::Greater) => simple_instruction("OP_GREATER", offset),
-        Some(OpCode::Less) => simple_instruction("OP_LESS", offset),
-        Some(OpCode::Add) => simple_instruction("OP_ADD", offset),
-        Some(OpCode::Subtract) => simple_instruction("OP_SUBTRACT", offset),
-        Some(OpCode::Multiply) => simple_instruction("OP_MULTIPLY", offset),
-        Some(OpCode::Divide) => simple_instruction("OP_DIVIDE", offset),
-        Some(OpCode::Not) => simple_instruction("OP_NOT", offset),
-        Some(OpCode::Negate) => simple_instruction("OP_NEGATE", offset),
-        Some(OpCode::Print) => simple_instruction("OP_PRINT", offset),
-        Some(OpCode::Jump) => jump_instruction("OP_JUMP", 1, chunk, offset),
-        Some(OpCode::JumpIfFalse) => jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset),
-        Some(OpCode::Loop) => jump_instruction("OP_LOOP", -1, chunk, offset),
-        Some(OpCode::Call) => byte_instruction("OP_CALL", chunk, offset),
-        Some(OpCode::Invoke) => invoke_instruction("OP_INVOKE", chunk, offset),
-        Some(OpCode::SuperInvoke) => invoke_instruction("OP_SUPER_INVOKE", chunk, offset),
+        Some(OpCode::Constant) => constant_instruction(out, "OP_CONSTANT", chunk, offset),
+        Some(OpCode::Nil) => simple_instruction(out, "OP_NIL", offset),
+        Some(OpCode::True) => simple_instruction(out, "OP_TRUE", offset),
+        Some(OpCode::False) => simple_instruction(out, "OP_FALSE", offset),
+        Some(OpCode::Pop) => simple_instruction(out, "OP_POP", offset),
+        Some(OpCode::GetLocal) => byte_instruction(out, "OP_GET_LOCAL", chunk, offset),
+        Some(OpCode::SetLocal) => byte_instruction(out, "OP_SET_LOCAL", chunk, offset),
+        Some(OpCode::SetLocalPop) => byte_instruction(out, "OP_SET_LOCAL_POP", chunk, offset),
+        Some(OpCode::GetGlobalSlot) => short_instruction(out, "OP_GET_GLOBAL_SLOT", chunk, offset),
+        Some(OpCode::DefineGlobalSlot) => {
+            short_instruction(out, "OP_DEFINE_GLOBAL_SLOT", chunk, offset)
+        }
+        Some(OpCode::DefineConstGlobalSlot) => {
+            short_instruction(out, "OP_DEFINE_CONST_GLOBAL_SLOT", chunk, offset)
+        }
+        Some(OpCode::SetGlobalSlot) => short_instruction(out, "OP_SET_GLOBAL_SLOT", chunk, offset),
+        Some(OpCode::SetGlobalSlotPop) => {
+            short_instruction(out, "OP_SET_GLOBAL_SLOT_POP", chunk, offset)
+        }
+        Some(OpCode::GetUpvalue) => byte_instruction(out, "OP_GET_UPVALUE", chunk, offset),
+        Some(OpCode::SetUpvalue) => byte_instruction(out, "OP_SET_UPVALUE", chunk, offset),
+        Some(OpCode::GetProperty) => property_instruction(out, "OP_GET_PROPERTY", chunk, offset),
+        Some(OpCode::SetProperty) => constant_instruction(out, "OP_SET_PROPERTY", chunk, offset),
+        Some(OpCode::GetSuper) => constant_instruction(out, "OP_GET_SUPER", chunk, offset),
+        Some(OpCode::Equal) => simple_instruction(out, "OP_EQUAL", offset),
+        Some(OpCode::NotEqual) => simple_instruction(out, "OP_NOT_EQUAL", offset),
+        Some(OpCode::Greater) => simple_instruction(out, "OP_GREATER", offset),
+        Some(OpCode::GreaterEqual) => simple_instruction(out, "OP_GREATER_EQUAL", offset),
+        Some(OpCode::Less) => simple_instruction(out, "OP_LESS", offset),
+        Some(OpCode::LessEqual) => simple_instruction(out, "OP_LESS_EQUAL", offset),
+        Some(OpCode::Add) => simple_instruction(out, "OP_ADD", offset),
+        Some(OpCode::Subtract) => simple_instruction(out, "OP_SUBTRACT", offset),
+        Some(OpCode::Multiply) => simple_instruction(out, "OP_MULTIPLY", offset),
+        Some(OpCode::Divide) => simple_instruction(out, "OP_DIVIDE", offset),
+        Some(OpCode::Not) => simple_instruction(out, "OP_NOT", offset),
+        Some(OpCode::Negate) => simple_instruction(out, "OP_NEGATE", offset),
+        Some(OpCode::Print) => simple_instruction(out, "OP_PRINT", offset),
+        Some(OpCode::Jump) => jump_instruction(out, "OP_JUMP", 1, chunk, offset),
+        Some(OpCode::JumpIfFalse) => jump_instruction(out, "OP_JUMP_IF_FALSE", 1, chunk, offset),
+        Some(OpCode::JumpIfNil) => jump_instruction(out, "OP_JUMP_IF_NIL", 1, chunk, offset),
+        Some(OpCode::JumpIfNotNil) => jump_instruction(out, "OP_JUMP_IF_NOT_NIL", 1, chunk, offset),
+        Some(OpCode::JumpIfTrue) => jump_instruction(out, "OP_JUMP_IF_TRUE", 1, chunk, offset),
+        Some(OpCode::Loop) => jump_instruction(out, "OP_LOOP", -1, chunk, offset),
+        Some(OpCode::Call) => byte_instruction(out, "OP_CALL", chunk, offset),
+        Some(OpCode::TailCall) => byte_instruction(out, "OP_TAIL_CALL", chunk, offset),
+        Some(OpCode::BuildString) => byte_instruction(out, "OP_BUILD_STRING", chunk, offset),
+        Some(OpCode::Invoke) => invoke_instruction(out, "OP_INVOKE", chunk, offset),
+        Some(OpCode::SuperInvoke) => invoke_instruction(out, "OP_SUPER_INVOKE", chunk, offset),
         Some(OpCode::Closure) => {
             let mut new_offset = offset + 1;
             let constant = chunk.code[new_offset];
             new_offset += 1;
-            print!("{:<16} {:4} ", "OP_CLOSURE", constant);
-            println!("{}", chunk.constants[constant as usize]);
+            write!(out, "{:<16} {:4} ", "OP_CLOSURE", constant).unwrap();
+            writeln!(out, "{}", chunk.constants[constant as usize]).unwrap();
 
             if let Value::Obj(obj) = &chunk.constants[constant as usize]
                 && let Obj::Function(function) = &**obj
             {
                 chunk.code[new_offset..]
-                    .chunks_exact(2)
+                    .chunks_exact(3)
                     .take(function.upvalue_count)
                     .enumerate()
-                    .for_each(|(i, pair)| {
-                        let upvalue_offset = new_offset + i * 2;
-                        let is_local = pair[0];
-                        let index = pair[1];
-                        println!(
+                    .for_each(|(i, entry)| {
+                        let upvalue_offset = new_offset + i * 3;
+                        let is_local = entry[0];
+                        let index = u16::from_be_bytes([entry[1], entry[2]]);
+                        writeln!(
+                            out,
                             "{:04}      |                     {} {}",
                             upvalue_offset,
                             if is_local != 0 { "local" } else { "upvalue" },
                             index
-                        );
+                        )
+                        .unwrap();
                     });
 
-                new_offset += function.upvalue_count * 2;
+                new_offset += function.upvalue_count * 3;
             }
 
             new_offset
         }
-        Some(OpCode::CloseUpvalue) => simple_instruction("OP_CLOSE_UPVALUE", offset),
-        Some(OpCode::Return) => simple_instruction("OP_RETURN", offset),
-        Some(OpCode::Class) => constant_instruction("OP_CLASS", chunk, offset),
-        Some(OpCode::Inherit) => simple_instruction("OP_INHERIT", offset),
-        Some(OpCode::Method) => constant_instruction("OP_METHOD", chunk, offset),
+        Some(OpCode::CloseUpvalue) => simple_instruction(out, "OP_CLOSE_UPVALUE", offset),
+        Some(OpCode::Return) => simple_instruction(out, "OP_RETURN", offset),
+        Some(OpCode::Class) => constant_instruction(out, "OP_CLASS", chunk, offset),
+        Some(OpCode::DocumentedClass) => documented_class_instruction(out, chunk, offset),
+        Some(OpCode::Inherit) => simple_instruction(out, "OP_INHERIT", offset),
+        Some(OpCode::IsInstance) => simple_instruction(out, "OP_IS_INSTANCE", offset),
+        Some(OpCode::ForIn) => simple_instruction(out, "OP_FOR_IN", offset),
+        Some(OpCode::Method) => constant_instruction(out, "OP_METHOD", chunk, offset),
+        Some(OpCode::StaticMethod) => constant_instruction(out, "OP_STATIC_METHOD", chunk, offset),
+        Some(OpCode::Getter) => constant_instruction(out, "OP_GETTER", chunk, offset),
+        Some(OpCode::Map) => simple_instruction(out, "OP_MAP", offset),
+        Some(OpCode::MapEntry) => simple_instruction(out, "OP_MAP_ENTRY", offset),
+        Some(OpCode::Index) => simple_instruction(out, "OP_INDEX", offset),
+        Some(OpCode::IndexSet) => simple_instruction(out, "OP_INDEX_SET", offset),
+        Some(OpCode::Dup) => simple_instruction(out, "OP_DUP", offset),
+        Some(OpCode::Swap) => simple_instruction(out, "OP_SWAP", offset),
+        Some(OpCode::Over) => simple_instruction(out, "OP_OVER", offset),
+        Some(OpCode::List) => simple_instruction(out, "OP_LIST", offset),
+        Some(OpCode::ListAppend) => simple_instruction(out, "OP_LIST_APPEND", offset),
+        Some(OpCode::Throw) => simple_instruction(out, "OP_THROW", offset),
+        Some(OpCode::PushHandler) => jump_instruction(out, "OP_PUSH_HANDLER", 1, chunk, offset),
+        Some(OpCode::PopHandler) => simple_instruction(out, "OP_POP_HANDLER", offset),
+        Some(OpCode::BitAnd) => simple_instruction(out, "OP_BIT_AND", offset),
+        Some(OpCode::BitOr) => simple_instruction(out, "OP_BIT_OR", offset),
+        Some(OpCode::BitXor) => simple_instruction(out, "OP_BIT_XOR", offset),
+        Some(OpCode::BitNot) => simple_instruction(out, "OP_BIT_NOT", offset),
+        Some(OpCode::ShiftLeft) => simple_instruction(out, "OP_SHIFT_LEFT", offset),
+        Some(OpCode::ShiftRight) => simple_instruction(out, "OP_SHIFT_RIGHT", offset),
+        Some(OpCode::GetLocalLong) => short_instruction(out, "OP_GET_LOCAL_LONG", chunk, offset),
+        Some(OpCode::SetLocalLong) => short_instruction(out, "OP_SET_LOCAL_LONG", chunk, offset),
+        Some(OpCode::PopN) => byte_instruction(out, "OP_POP_N", chunk, offset),
         None => {
-            println!("Unknown opcode {}", instruction);
+            writeln!(out, "Unknown opcode {}", instruction).unwrap();
             offset + 1
         }
     }
 }
 
-#[allow(dead_code)]
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
+fn simple_instruction(out: &mut String, name: &str, offset: usize) -> usize {
+    writeln!(out, "{}", name).unwrap();
     offset + 1
 }
 
-#[allow(dead_code)]
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn constant_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
     let constant = chunk.code[offset + 1];
-    print!("{:<16} {:4} ", name, constant);
-    println!("{}", chunk.constants[constant as usize]);
+    write!(out, "{:<16} {:4} ", name, constant).unwrap();
+    writeln!(out, "{}", chunk.constants[constant as usize]).unwrap();
     offset + 2
 }
 
-#[allow(dead_code)]
-fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn byte_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
     let slot = chunk.code[offset + 1];
-    println!("{:<16} {:4}", name, slot);
+    writeln!(out, "{:<16} {:4}", name, slot).unwrap();
     offset + 2
 }
 
-#[allow(dead_code)]
-fn jump_instruction(name: &str, sign: i32, chunk: &Chunk, offset: usize) -> usize {
+fn short_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let slot = u16::from_be_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+    writeln!(out, "{:<16} {:4}", name, slot).unwrap();
+    offset + 3
+}
+
+fn jump_instruction(
+    out: &mut String,
+    name: &str,
+    sign: i32,
+    chunk: &Chunk,
+    offset: usize,
+) -> usize {
     let jump = u16::from_be_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
     let target = if sign > 0 {
         offset + 3 + jump as usize
     } else {
         offset + 3 - jump as usize
     };
-    println!("{:<16} {:4} -> {}", name, offset, target);
+    writeln!(out, "{:<16} {:4} -> {}", name, offset, target).unwrap();
+    offset + 3
+}
+
+fn property_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let constant = chunk.code[offset + 1];
+    let cache = u16::from_be_bytes([chunk.code[offset + 2], chunk.code[offset + 3]]);
+    write!(out, "{:<16} {:4} ", name, constant).unwrap();
+    writeln!(
+        out,
+        "{} (cache {})",
+        chunk.constants[constant as usize], cache
+    )
+    .unwrap();
+    offset + 4
+}
+
+fn documented_class_instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize {
+    let constant = chunk.code[offset + 1];
+    let doc = chunk.code[offset + 2];
+    write!(out, "{:<16} {:4} ", "OP_DOCUMENTED_CLASS", constant).unwrap();
+    writeln!(out, "{} (doc {})", chunk.constants[constant as usize], doc).unwrap();
     offset + 3
 }
 
-#[allow(dead_code)]
-fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn invoke_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
     let constant = chunk.code[offset + 1];
     let arg_count = chunk.code[offset + 2];
-    print!("{:<16} ({} args) {:4} ", name, arg_count, constant);
-    println!("{}", chunk.constants[constant as usize]);
+    write!(out, "{:<16} ({} args) {:4} ", name, arg_count, constant).unwrap();
+    writeln!(out, "{}", chunk.constants[constant as usize]).unwrap();
     offset + 3
 }
+
+#[cfg(test)]
+mod tests {
+    use super::disassemble_chunk_to_string;
+    use crate::value::{Obj, Value};
+    use crate::vm::VM;
+
+    #[test]
+    fn disassembles_a_compiled_function() {
+        let mut vm = VM::new();
+        let script = vm
+            .compile("fun add(a, b) {\n  return a + b;\n}\n")
+            .expect("the source compiles");
+        let add = script
+            .chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Obj(obj) => match &**obj {
+                    Obj::Function(function) => Some(function),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .expect("the script holds the function");
+
+        assert_eq!(
+            disassemble_chunk_to_string(&add.chunk, "add"),
+            "== add ==\n\
+             0000    2 OP_GET_LOCAL        1\n\
+             0002    | OP_GET_LOCAL        2\n\
+             0004    | OP_ADD\n\
+             0005    | OP_RETURN\n\
+             0006    3 OP_NIL\n\
+             0007    | OP_RETURN\n"
+        );
+    }
+}
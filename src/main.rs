@@ -1,35 +1,195 @@
+mod builtin;
+mod bytecode;
 mod chunk;
 mod compiler;
 mod debug;
 mod native;
+mod peephole;
 mod scanner;
 mod value;
 mod vm;
 
+use compiler::{CompileDiagnostic, CompileOptions};
+use scanner::{Scanner, TokenType};
 use std::env;
-use std::fs;
+use std::fs::{self, OpenOptions};
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process;
-use vm::{InterpretResult, VM};
+use std::time::{Duration, Instant};
+use value::Value;
+use vm::{InterpretResult, StepResult, VM};
+
+/// How the VM is set up from the command-line flags.
+#[derive(Clone, Copy)]
+struct Settings {
+    options: CompileOptions,
+    frames_max: usize,
+    stack_max: usize,
+    fuel: Option<u64>,
+}
+
+impl Settings {
+    fn vm(&self) -> VM {
+        let mut vm = VM::with_limits(self.frames_max, self.stack_max);
+        if let Some(fuel) = self.fuel {
+            vm = vm.with_fuel(fuel);
+        }
+        vm.set_compile_options(self.options);
+        vm
+    }
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Interpret,
+    Step,
+    Debug,
+    Dump,
+    Tokens,
+    Compile,
+    RunCompiled,
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut settings = Settings {
+        options: CompileOptions::default(),
+        frames_max: vm::FRAMES_MAX,
+        stack_max: vm::STACK_MAX,
+        fuel: None,
+    };
+    let mut mode = Mode::Interpret;
+    let mut path = None;
+    let mut output = None;
+    let mut time = false;
+    let mut leak_check = false;
+    let mut quiet = false;
+    let mut script_args = Vec::new();
+    let mut breakpoints = Vec::new();
+    let mut watched = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--warn" => settings.options.warnings = true,
+            "--optimize" => settings.options.optimize = true,
+            "--time" => time = true,
+            "--leak-check" => leak_check = true,
+            "--quiet" => quiet = true,
+            "--dump" => mode = Mode::Dump,
+            "--step" => mode = Mode::Step,
+            "--break" => {
+                mode = Mode::Debug;
+                breakpoints.push(limit_argument(args.next()));
+            }
+            "--watch" => {
+                mode = Mode::Debug;
+                watched.push(args.next().unwrap_or_else(|| usage()));
+            }
+            "--tokens" => mode = Mode::Tokens,
+            "--compile" => mode = Mode::Compile,
+            "--run" => mode = Mode::RunCompiled,
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "--max-frames" => settings.frames_max = limit_argument(args.next()),
+            "--max-stack" => settings.stack_max = limit_argument(args.next()),
+            "--fuel" => settings.fuel = Some(limit_argument(args.next())),
+            // Everything after the script's path is the script's own, except
+            // that `--compile` still takes `-o` there.
+            _ if path.is_none() && !arg.starts_with('-') => {
+                path = Some(arg);
+                if mode != Mode::Compile {
+                    script_args.extend(args.by_ref());
+                }
+            }
+            _ => usage(),
+        }
+    }
+
+    if output.is_some() && mode != Mode::Compile {
+        usage();
+    }
 
-    let mut vm = VM::new();
+    let mut vm = settings.vm();
+    vm.set_script_args(script_args);
 
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&mut vm, &args[1]),
-        _ => {
-            eprintln!("Usage: rlox [path]");
-            process::exit(64);
+    match (mode, path) {
+        (Mode::Interpret, None) => {
+            if !quiet {
+                print_banner();
+            }
+            repl(&mut vm)
+        }
+        (Mode::Interpret, Some(path)) => {
+            let result = run_file(&mut vm, &path, time);
+            finish(vm, result, leak_check);
+        }
+        (Mode::Step, Some(path)) => {
+            let result = step_file(&mut vm, &path);
+            finish(vm, result, leak_check);
+        }
+        (Mode::Debug, Some(path)) => {
+            breakpoints.iter().for_each(|&line| vm.set_breakpoint(line));
+            watched.iter().for_each(|name| vm.watch_global(name));
+            let result = debug_file(&mut vm, &path);
+            finish(vm, result, leak_check);
         }
+        (Mode::Dump, Some(path)) => dump_file(&mut vm, &path),
+        (Mode::Tokens, Some(path)) => dump_tokens(&path),
+        (Mode::Compile, Some(path)) => {
+            let output = output.unwrap_or_else(|| {
+                Path::new(&path)
+                    .with_extension("loxc")
+                    .to_string_lossy()
+                    .into_owned()
+            });
+            compile_file(&mut vm, &path, &output);
+        }
+        (Mode::RunCompiled, Some(path)) => {
+            let result = run_compiled_file(&mut vm, &path, time);
+            finish(vm, result, leak_check);
+        }
+        (_, None) => usage(),
     }
 }
 
+fn usage() -> ! {
+    eprintln!("Usage: rlox [--warn] [--optimize] [--time] [--dump] [path [args...]]");
+    eprintln!("       rlox [--quiet]");
+    eprintln!("       rlox --tokens <path>");
+    eprintln!("       rlox --step <path> [args...]");
+    eprintln!("       rlox [--break <line>...] [--watch <global>...] <path> [args...]");
+    eprintln!("       rlox [--max-frames <n>] [--max-stack <n>] [--fuel <n>] [path]");
+    eprintln!("       rlox --compile <path> [-o <output>]");
+    eprintln!("       rlox [--time] --run <compiled path> [args...]");
+    eprintln!("       rlox --leak-check [--run] <path> [args...]");
+    process::exit(64);
+}
+
+fn limit_argument<T: std::str::FromStr>(argument: Option<String>) -> T {
+    argument
+        .and_then(|argument| argument.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+/// Greets an interactive session. Running a file never prints this, so a
+/// script's output is only its own.
+fn print_banner() {
+    println!("rlox {}", env!("CARGO_PKG_VERSION"));
+    println!(
+        "Lines starting with ':' are commands: :load <path>, :doc <name>, :reset, :history and :quit."
+    );
+}
+
+/// How many entries `:history` lists when no count is given.
+const HISTORY_SHOWN: usize = 20;
+
 fn repl(vm: &mut VM) {
     let stdin = io::stdin();
     let mut stdout = io::stdout();
+    let mut history = History::load();
+    // `///` lines typed so far, which document the declaration on the line
+    // after them.
+    let mut doc = String::new();
 
     loop {
         print!("> ");
@@ -42,21 +202,395 @@ fn repl(vm: &mut VM) {
                 break;
             }
             Ok(_) => {
-                vm.interpret(&line);
+                history.add(line.trim_end());
+                match line.trim().strip_prefix(':') {
+                    Some(command) => {
+                        if !repl_command(vm, &history, command) {
+                            break;
+                        }
+                    }
+                    None if line.trim_start().starts_with("///") => doc.push_str(&line),
+                    None => {
+                        doc.push_str(&line);
+                        repl_line(vm, &doc);
+                        doc.clear();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs a line typed at the REPL. A line holding a single expression,
+/// such as `1 + 2` or `name;`, also echoes its value with `repr()` unless
+/// it is `nil`, so a string shows up quoted.
+fn repl_line(vm: &mut VM, line: &str) {
+    let expression = line.trim().trim_end_matches(';');
+    // The newline keeps a trailing `//` comment from swallowing the `);`.
+    if !expression.is_empty()
+        && let Ok(function) = vm.compile(&format!("return ({}\n);", expression))
+    {
+        if let Ok(value) = vm.eval_function(function)
+            && !matches!(value, Value::Nil)
+        {
+            println!("{}", value.repr(vm.number_precision()));
+        }
+        return;
+    }
+
+    if let InterpretResult::CompileError(diagnostics) = vm.interpret(line) {
+        report_compile_errors(&diagnostics);
+    }
+}
+
+/// Runs a `:command` typed at the REPL, returning false to end the session.
+fn repl_command(vm: &mut VM, history: &History, command: &str) -> bool {
+    let (name, argument) = command
+        .split_once(char::is_whitespace)
+        .map_or((command, ""), |(name, argument)| (name, argument.trim()));
+
+    match (name, argument) {
+        ("load", "") => eprintln!("Usage: :load <path>"),
+        ("load", path) => match fs::read_to_string(path) {
+            // Globals the file defines stay available in the session.
+            Ok(source) => {
+                if let InterpretResult::CompileError(diagnostics) = vm.interpret(&source) {
+                    report_compile_errors(&diagnostics);
+                }
             }
+            Err(err) => eprintln!("Could not open file \"{}\": {}", path, err),
+        },
+        ("doc", "") => eprintln!("Usage: :doc <name>"),
+        ("doc", name) => print_doc(vm, name),
+        ("reset", "") => vm.reset(),
+        ("history", "") => history.print(HISTORY_SHOWN),
+        ("history", count) => match count.parse() {
+            Ok(count) => history.print(count),
+            Err(_) => eprintln!("Usage: :history [count]"),
+        },
+        ("quit", "") => return false,
+        _ => eprintln!(
+            "Unknown command ':{}'. Commands are :load <path>, :doc <name>, :reset, :history [count] and :quit.",
+            command
+        ),
+    }
+    true
+}
+
+/// Shows the doc comment of the function or class `name` evaluates to,
+/// which may also be an expression such as `point.area`.
+fn print_doc(vm: &mut VM, name: &str) {
+    let value = match vm.compile(&format!("return ({}\n);", name)) {
+        Ok(function) => match vm.eval_function(function) {
+            Ok(value) => value,
+            Err(_) => return,
+        },
+        Err(diagnostics) => {
+            report_compile_errors(&diagnostics);
+            return;
         }
+    };
+
+    if !value.is_callable() {
+        eprintln!("Only functions and classes have doc comments.");
+    } else if let Some(doc) = value.doc_comment() {
+        println!("{}", doc);
+    } else {
+        println!("{} has no doc comment.", name);
     }
 }
 
-fn run_file(vm: &mut VM, path: &str) {
-    let source = fs::read_to_string(path).unwrap_or_else(|err| {
+/// Lines entered at the REPL, kept across sessions in `~/.rlox_history`.
+struct History {
+    path: PathBuf,
+    entries: Vec<String>,
+}
+
+impl History {
+    /// Reads earlier sessions' history. The file lives in the home
+    /// directory, or the current one if the home directory is unknown.
+    fn load() -> Self {
+        let home = env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map_or_else(|| PathBuf::from("."), PathBuf::from);
+        let path = home.join(".rlox_history");
+        let entries = fs::read_to_string(&path)
+            .map(|contents| contents.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        History { path, entries }
+    }
+
+    /// Records a line, appending it to the history file straight away so a
+    /// crashed session keeps what was typed. Write failures are ignored:
+    /// history is a convenience and shouldn't interrupt the session.
+    fn add(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        self.entries.push(line.to_string());
+        if let Ok(mut file) = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+        {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    fn print(&self, count: usize) {
+        let start = self.entries.len().saturating_sub(count);
+        self.entries[start..]
+            .iter()
+            .enumerate()
+            .for_each(|(i, entry)| println!("{:>5}  {}", start + i + 1, entry));
+    }
+}
+
+fn read_file(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|err| {
         eprintln!("Could not open file \"{}\": {}", path, err);
         process::exit(74);
+    })
+}
+
+fn dump_file(vm: &mut VM, path: &str) {
+    match vm.compile(&read_file(path)) {
+        Ok(function) => debug::disassemble_function(&function),
+        Err(diagnostics) => {
+            report_compile_errors(&diagnostics);
+            process::exit(65);
+        }
+    }
+}
+
+/// Runs `path` one instruction at a time through `VM::step`, printing the
+/// stack and the instruction about to run before each step, as a debugger
+/// stepping through it would show them. A header names the function and
+/// line execution is in whenever a call starts or returns.
+fn step_file(vm: &mut VM, path: &str) -> InterpretResult {
+    let function = match vm.compile(&read_file(path)) {
+        Ok(function) => function,
+        Err(diagnostics) => {
+            report_compile_errors(&diagnostics);
+            return InterpretResult::CompileError(diagnostics);
+        }
+    };
+
+    if !vm.begin(function) {
+        return InterpretResult::RuntimeError;
+    }
+    let (mut depth, mut ip) = (1, 0);
+    let mut shown_depth = 0;
+    loop {
+        // The script's own output goes before the trace of what follows it.
+        vm.flush_output().ok();
+        let function = vm.current_function().expect("a script is running");
+        if depth != shown_depth {
+            shown_depth = depth;
+            let name = function.name.as_deref().unwrap_or("<script>");
+            println!("== {} at line {} ==", name, vm.current_line().unwrap());
+        }
+        debug::print_stack(&vm.stack_snapshot());
+        debug::disassemble_instruction(&function.chunk, ip);
+
+        match vm.step() {
+            StepResult::Continue {
+                depth: next_depth,
+                ip: next_ip,
+            } => (depth, ip) = (next_depth, next_ip),
+            StepResult::Finished(_) | StepResult::NotRunning => return InterpretResult::Ok,
+            StepResult::Error => return InterpretResult::RuntimeError,
+        }
+    }
+}
+
+/// Runs `path` with `VM::continue_run`, printing each change to a watched
+/// global, and the function, line and stack each time execution halts at a
+/// breakpoint, before resuming it.
+fn debug_file(vm: &mut VM, path: &str) -> InterpretResult {
+    let function = match vm.compile(&read_file(path)) {
+        Ok(function) => function,
+        Err(diagnostics) => {
+            report_compile_errors(&diagnostics);
+            return InterpretResult::CompileError(diagnostics);
+        }
+    };
+
+    if !vm.begin(function) {
+        return InterpretResult::RuntimeError;
+    }
+    loop {
+        match vm.continue_run() {
+            StepResult::Continue { .. } => {
+                vm.flush_output().ok();
+                if let Some(change) = vm.take_global_change() {
+                    match change.old {
+                        Some(old) => println!(
+                            "== {} changed from {} to {} at line {} ==",
+                            change.name, old, change.new, change.line
+                        ),
+                        None => println!(
+                            "== {} defined as {} at line {} ==",
+                            change.name, change.new, change.line
+                        ),
+                    }
+                    continue;
+                }
+                let function = vm.current_function().expect("a script is running");
+                let name = function.name.as_deref().unwrap_or("<script>");
+                let line = vm.current_line().unwrap();
+                println!("== breakpoint in {} at line {} ==", name, line);
+                debug::print_stack(&vm.stack_snapshot());
+            }
+            StepResult::Finished(_) | StepResult::NotRunning => return InterpretResult::Ok,
+            StepResult::Error => return InterpretResult::RuntimeError,
+        }
+    }
+}
+
+/// Scans `path` without compiling it, printing every token up to and
+/// including EOF. Scan errors are reported as the compiler would, after which
+/// scanning carries on.
+fn dump_tokens(path: &str) {
+    let source = read_file(path);
+    let mut scanner = Scanner::new(&source);
+    let mut had_error = false;
+
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::Error => {
+                eprintln!(
+                    "[line {}] Error: {} (column {})",
+                    token.line, token.lexeme, token.column
+                );
+                had_error = true;
+            }
+            _ => debug::print_token(&token),
+        }
+        if token.token_type == TokenType::Eof {
+            break;
+        }
+    }
+
+    if had_error {
+        process::exit(65);
+    }
+}
+
+fn compile_file(vm: &mut VM, path: &str, output: &str) {
+    let function = vm.compile(&read_file(path)).unwrap_or_else(|diagnostics| {
+        report_compile_errors(&diagnostics);
+        process::exit(65);
+    });
+
+    let bytes = bytecode::serialize(&function, vm.global_names()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(65);
     });
 
-    match vm.interpret(&source) {
+    if let Err(err) = fs::write(output, bytes) {
+        eprintln!("Could not write file \"{}\": {}", output, err);
+        process::exit(74);
+    }
+}
+
+fn run_compiled_file(vm: &mut VM, path: &str, time: bool) -> InterpretResult {
+    let bytes = fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Could not open file \"{}\": {}", path, err);
+        process::exit(74);
+    });
+
+    let start = Instant::now();
+    let function = vm.load(&bytes).unwrap_or_else(|err| {
+        eprintln!("Could not load \"{}\": {}", path, err);
+        process::exit(65);
+    });
+    let load_time = start.elapsed();
+
+    let start = Instant::now();
+    let result = vm.interpret_function(function);
+    if time {
+        report_time(vm, "load", load_time, start.elapsed());
+    }
+    result
+}
+
+fn run_file(vm: &mut VM, path: &str, time: bool) -> InterpretResult {
+    let source = read_file(path);
+
+    let start = Instant::now();
+    let function = vm.compile(&source);
+    let compile_time = start.elapsed();
+
+    let start = Instant::now();
+    let result = match function {
+        Ok(function) => vm.interpret_function(function),
+        Err(diagnostics) => {
+            report_compile_errors(&diagnostics);
+            InterpretResult::CompileError(diagnostics)
+        }
+    };
+    if time {
+        report_time(vm, "compile", compile_time, start.elapsed());
+    }
+    result
+}
+
+/// Exits with the status for `result`, first reporting leaks if asked to.
+fn finish(vm: VM, result: InterpretResult, leak_check: bool) {
+    if leak_check {
+        report_leaks(vm);
+    }
+    exit_on_error(result);
+}
+
+/// Drops `vm`, which frees everything it holds, and prints to stderr how
+/// many instances and closures are still allocated. Only reference cycles
+/// can keep those alive, since nothing outside the VM refers to them.
+fn report_leaks(vm: VM) {
+    drop(vm);
+    let (instances, closures) = (value::live_instances(), value::live_closures());
+    if instances == 0 && closures == 0 {
+        eprintln!("[leak check] no instances or closures leaked");
+        return;
+    }
+
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+    eprintln!(
+        "[leak check] {} instance{} and {} closure{} leaked, kept alive by reference cycles",
+        instances,
+        plural(instances),
+        closures,
+        plural(closures)
+    );
+}
+
+/// Prints `--time` statistics to stderr, keeping stdout comparable.
+fn report_time(vm: &VM, phase: &str, prepare: Duration, run: Duration) {
+    eprintln!(
+        "[time] {}: {:.3} ms, run: {:.3} ms",
+        phase,
+        prepare.as_secs_f64() * 1000.0,
+        run.as_secs_f64() * 1000.0
+    );
+    eprintln!(
+        "[time] final stack size: {}, globals defined: {}",
+        vm.stack_size(),
+        vm.defined_global_count()
+    );
+}
+
+fn report_compile_errors(diagnostics: &[CompileDiagnostic]) {
+    diagnostics
+        .iter()
+        .for_each(|diagnostic| eprintln!("{}", diagnostic));
+}
+
+fn exit_on_error(result: InterpretResult) {
+    match result {
         InterpretResult::Ok => {}
-        InterpretResult::CompileError => process::exit(65),
+        InterpretResult::CompileError(_) => process::exit(65),
         InterpretResult::RuntimeError => process::exit(70),
     }
 }
@@ -1,9 +1,451 @@
-use crate::value::Value;
+use crate::value::{HashableValue, List, NativeError, NativeStep, Obj, Value};
+use crate::vm::VM;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::rc::Rc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn clock(_arg_count: usize, _args: &[Value]) -> Value {
+/// Wall-clock seconds since the UNIX epoch. This jumps if the system clock
+/// is changed, so `monotonic()` is better for measuring elapsed time.
+pub fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
     let duration = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
-    Value::Number(duration.as_secs_f64())
+    Ok(Value::Number(duration.as_secs_f64()))
+}
+
+/// Seconds since the VM was created, from a clock that never goes back.
+pub fn monotonic(vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Number(vm.start_time().elapsed().as_secs_f64()))
+}
+
+/// Wall-clock milliseconds since the UNIX epoch, as an integer.
+pub fn now_millis(_vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
+    let duration = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    Ok(Value::Int(duration.as_millis() as i64))
+}
+
+/// The interpreter's version, such as `"0.1.0"`.
+pub fn version(vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
+    Ok(vm.new_string(env!("CARGO_PKG_VERSION")))
+}
+
+/// Prints a value and a newline like `print`, then returns the value so
+/// the call can sit inside a larger expression.
+pub fn print_value(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let text = args[0].display(vm.number_precision());
+    writeln!(vm.output(), "{}", text).map_err(|err| err.to_string())?;
+    Ok(args[0].clone())
+}
+
+/// Prints a value without a trailing newline.
+pub fn write(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let text = args[0].display(vm.number_precision());
+    write!(vm.output(), "{}", text).map_err(|err| err.to_string())?;
+    Ok(Value::Nil)
+}
+
+/// Writes out everything printed so far, which is otherwise buffered until
+/// the script ends.
+pub fn flush(vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
+    vm.flush_output().map_err(|err| err.to_string())?;
+    Ok(Value::Nil)
+}
+
+/// Prints a value and a newline to stderr, keeping it out of the
+/// program's output. Buffered output is flushed first so the two stay in
+/// order on a terminal.
+pub fn eprint(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    vm.flush_output().map_err(|err| err.to_string())?;
+    let mut stderr = io::stderr();
+    writeln!(stderr, "{}", args[0].display(vm.number_precision()))
+        .and_then(|_| stderr.flush())
+        .map_err(|err| err.to_string())?;
+    Ok(Value::Nil)
+}
+
+/// Sets how many significant digits `print` and string conversion show
+/// numbers with, from 1 to 17. Seventeen digits are enough to tell any two
+/// numbers apart; the default is six.
+pub fn set_precision(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match args[0].as_f64() {
+        Some(digits) if digits.fract() == 0.0 && (1.0..=17.0).contains(&digits) => {
+            vm.set_number_precision(digits as usize);
+            Ok(Value::Nil)
+        }
+        _ => Err("Precision must be a whole number from 1 to 17."
+            .to_string()
+            .into()),
+    }
+}
+
+/// The value as a string, the way `print` shows it.
+pub fn str(vm: &mut VM, args: &[Value]) -> Result<NativeStep, NativeError> {
+    vm.stringify(&args[0])
+}
+
+/// The value as a string for debugging, with strings quoted so that `"1"`
+/// and `1` look different. Instances are not asked for their `toString()`.
+pub fn repr(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let text = args[0].repr(vm.number_precision()).to_string();
+    Ok(vm.new_string(&text))
+}
+
+/// The `///` comment written before a function, method or class
+/// declaration, or `nil` when it has none.
+pub fn doc(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    if !args[0].is_callable() {
+        return Err("Only functions and classes have doc comments."
+            .to_string()
+            .into());
+    }
+    Ok(match args[0].doc_comment() {
+        Some(doc) => vm.new_string(doc),
+        None => Value::Nil,
+    })
+}
+
+/// The value of the environment variable `name`, or `nil` when it is unset
+/// or isn't valid Unicode.
+pub fn getenv(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let name = args[0]
+        .as_str()
+        .ok_or_else(|| "Variable name must be a string.".to_string())?;
+    Ok(match env::var(name) {
+        Ok(value) => vm.new_string(&value),
+        Err(_) => Value::Nil,
+    })
+}
+
+/// A new list of the command-line arguments given after the script path.
+pub fn argv(vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
+    let args = vm.script_args().to_vec();
+    let items = args.iter().map(|arg| vm.new_string(arg)).collect();
+    Ok(list_value(items))
+}
+
+/// The whole contents of the file at `path`, which must be UTF-8.
+pub fn read_file(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let path = path_argument(&args[0])?;
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Could not open file \"{}\": {}", path, err))?;
+    Ok(vm.new_string(&contents))
+}
+
+/// Replaces the file at `path` with `contents`, creating it if needed.
+pub fn write_file(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let path = path_argument(&args[0])?;
+    let contents = args[1]
+        .as_str()
+        .ok_or_else(|| "File contents must be a string.".to_string())?;
+    fs::write(path, contents)
+        .map_err(|err| format!("Could not write file \"{}\": {}", path, err))?;
+    Ok(Value::Nil)
+}
+
+/// A new list of the names of every defined global, natives included, in
+/// sorted order.
+pub fn globals(vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
+    let items = vm
+        .defined_global_names()
+        .into_iter()
+        .map(|name| Value::Obj(Rc::new(Obj::String(name))))
+        .collect();
+    Ok(list_value(items))
+}
+
+/// Whether a global variable called `name` currently holds a value.
+pub fn defined(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let name = args[0]
+        .as_str()
+        .ok_or_else(|| "Variable name must be a string.".to_string())?;
+    Ok(Value::Bool(vm.is_global_defined(name)))
+}
+
+/// Sorts a list in place, ascending by `<` or by `comparator(a, b)`, which
+/// returns whether `a` goes before `b`. The sort is stable. The comparator
+/// works on a copy, so changes it makes to the list itself are lost.
+pub fn sort(_vm: &mut VM, args: &[Value]) -> Result<NativeStep, NativeError> {
+    let list = list_argument(&args[0], "sort")?;
+    let mut sort = MergeSort::new(list.items.borrow().clone());
+
+    let Some(comparator) = args.get(1) else {
+        while let Some((a, b)) = sort.next_pair() {
+            let less = match a.compare(b) {
+                Some(ordering) => ordering == Some(Ordering::Less),
+                None => {
+                    return Err("Can only sort numbers or strings without a comparator."
+                        .to_string()
+                        .into());
+                }
+            };
+            sort.take(less);
+        }
+        *list.items.borrow_mut() = sort.into_sorted();
+        return Ok(NativeStep::Done(Value::Nil));
+    };
+    callable_argument(comparator)?;
+    sort_with(Rc::clone(list), comparator.clone(), sort)
+}
+
+/// Goes on with `sort` up to the next comparison, which is a call of
+/// `comparator`.
+fn sort_with(
+    list: Rc<List>,
+    comparator: Value,
+    mut sort: MergeSort,
+) -> Result<NativeStep, NativeError> {
+    let Some((a, b)) = sort.next_pair() else {
+        *list.items.borrow_mut() = sort.into_sorted();
+        return Ok(NativeStep::Done(Value::Nil));
+    };
+    let args = vec![a.clone(), b.clone()];
+    Ok(NativeStep::call(
+        comparator.clone(),
+        args,
+        move |_, result| {
+            match result {
+                Value::Bool(less) => sort.take(less),
+                _ => return Err("Comparator must return a boolean.".to_string().into()),
+            }
+            sort_with(list, comparator, sort)
+        },
+    ))
+}
+
+/// A new list of the results of calling `function` on each element.
+pub fn map(_vm: &mut VM, args: &[Value]) -> Result<NativeStep, NativeError> {
+    let items = list_argument(&args[0], "map")?.items.borrow().clone();
+    let function = callable_argument(&args[1])?.clone();
+    map_from(items, function, Vec::new())
+}
+
+fn map_from(
+    items: Vec<Value>,
+    function: Value,
+    mut mapped: Vec<Value>,
+) -> Result<NativeStep, NativeError> {
+    let Some(item) = items.get(mapped.len()).cloned() else {
+        return Ok(NativeStep::Done(list_value(mapped)));
+    };
+    Ok(NativeStep::call(
+        function.clone(),
+        vec![item],
+        move |_, result| {
+            mapped.push(result);
+            map_from(items, function, mapped)
+        },
+    ))
+}
+
+/// A new list of the elements for which `function` returns a truthy value.
+pub fn filter(_vm: &mut VM, args: &[Value]) -> Result<NativeStep, NativeError> {
+    let items = list_argument(&args[0], "filter")?.items.borrow().clone();
+    let function = callable_argument(&args[1])?.clone();
+    filter_from(items, 0, function, Vec::new())
+}
+
+fn filter_from(
+    items: Vec<Value>,
+    index: usize,
+    function: Value,
+    mut kept: Vec<Value>,
+) -> Result<NativeStep, NativeError> {
+    let Some(item) = items.get(index).cloned() else {
+        return Ok(NativeStep::Done(list_value(kept)));
+    };
+    Ok(NativeStep::call(
+        function.clone(),
+        vec![item.clone()],
+        move |_, keep| {
+            if !keep.is_falsey() {
+                kept.push(item);
+            }
+            filter_from(items, index + 1, function, kept)
+        },
+    ))
+}
+
+/// Folds the list from the left: each element is combined with the result
+/// so far by `function(accumulator, element)`, starting from `initial`.
+pub fn reduce(_vm: &mut VM, args: &[Value]) -> Result<NativeStep, NativeError> {
+    let items = list_argument(&args[0], "reduce")?.items.borrow().clone();
+    let function = callable_argument(&args[1])?.clone();
+    reduce_from(items, 0, function, args[2].clone())
+}
+
+fn reduce_from(
+    items: Vec<Value>,
+    index: usize,
+    function: Value,
+    accumulator: Value,
+) -> Result<NativeStep, NativeError> {
+    let Some(item) = items.get(index).cloned() else {
+        return Ok(NativeStep::Done(accumulator));
+    };
+    Ok(NativeStep::call(
+        function.clone(),
+        vec![accumulator, item],
+        move |_, result| reduce_from(items, index + 1, function, result),
+    ))
+}
+
+/// A stable bottom-up merge sort that stops at each comparison, so the
+/// comparison can be a call into Lox code: `next_pair` gives the two
+/// elements to compare and `take` carries on with the answer. The
+/// standard library's sorts can't be paused, and may panic when a Lox
+/// comparator is inconsistent.
+struct MergeSort {
+    items: Vec<Value>,
+    /// The runs merged so far in this pass.
+    merged: Vec<Value>,
+    /// The length of the sorted runs that this pass merges in pairs.
+    width: usize,
+    /// What is left of the two runs being merged.
+    left: Range<usize>,
+    right: Range<usize>,
+}
+
+impl MergeSort {
+    fn new(items: Vec<Value>) -> Self {
+        let mut sort = MergeSort {
+            merged: Vec::with_capacity(items.len()),
+            items,
+            width: 1,
+            left: 0..0,
+            right: 0..0,
+        };
+        sort.start_runs(0);
+        sort
+    }
+
+    fn start_runs(&mut self, start: usize) {
+        let len = self.items.len();
+        let middle = (start + self.width).min(len);
+        self.left = start..middle;
+        self.right = middle..(middle + self.width).min(len);
+    }
+
+    /// The heads of the right and left run, in that order: whether the
+    /// first goes before the second decides which one is taken next.
+    /// `None` once the list is sorted.
+    fn next_pair(&mut self) -> Option<(&Value, &Value)> {
+        while self.left.is_empty() || self.right.is_empty() {
+            if self.width >= self.items.len() {
+                return None;
+            }
+            let (left, right) = (self.left.clone(), self.right.clone());
+            self.merged.extend_from_slice(&self.items[left]);
+            self.merged.extend_from_slice(&self.items[right.clone()]);
+
+            if right.end < self.items.len() {
+                self.start_runs(right.end);
+            } else {
+                std::mem::swap(&mut self.items, &mut self.merged);
+                self.merged.clear();
+                self.width *= 2;
+                self.start_runs(0);
+            }
+        }
+        Some((&self.items[self.right.start], &self.items[self.left.start]))
+    }
+
+    /// Takes the head of the right run if `right_first`, or else the head of
+    /// the left run. Equal elements come from the left first, which keeps
+    /// them in order.
+    fn take(&mut self, right_first: bool) {
+        let run = if right_first {
+            &mut self.right
+        } else {
+            &mut self.left
+        };
+        self.merged.push(self.items[run.start].clone());
+        run.start += 1;
+    }
+
+    fn into_sorted(self) -> Vec<Value> {
+        self.items
+    }
+}
+
+/// The list a list-processing native works on. Natives that call back into
+/// Lox code take a copy of its elements first, as the callback may change it.
+fn list_argument<'a>(value: &'a Value, verb: &str) -> Result<&'a Rc<List>, NativeError> {
+    match value {
+        Value::Obj(obj) => match &**obj {
+            Obj::List(list) => Ok(list),
+            _ => Err(format!("Can only {} lists.", verb).into()),
+        },
+        _ => Err(format!("Can only {} lists.", verb).into()),
+    }
+}
+
+/// Checks a function argument up front, so a bad one fails even when the
+/// list is too short for it to be called.
+fn callable_argument(value: &Value) -> Result<&Value, NativeError> {
+    if !value.is_callable() {
+        return Err("Can only call functions and classes.".to_string().into());
+    }
+    Ok(value)
+}
+
+fn list_value(items: Vec<Value>) -> Value {
+    Value::Obj(Rc::new(Obj::List(Rc::new(List {
+        items: RefCell::new(items),
+    }))))
+}
+
+fn path_argument(value: &Value) -> Result<&str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| "Path must be a string.".to_string())
+}
+
+/// Floored division: `floor(a / b)`. Stays an integer for two integers.
+pub fn idiv(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let (a, b) = integer_operands(args)?;
+    if let [Value::Int(x), Value::Int(y)] = args
+        && let Some(quotient) = x.checked_div(*y)
+    {
+        let rounded_up = x % y != 0 && (*x < 0) != (*y < 0);
+        return Ok(Value::Int(quotient - rounded_up as i64));
+    }
+    Ok(Value::Number((a / b).floor()))
+}
+
+/// Euclidean remainder: always in `[0, |b|)`, even for negative operands.
+pub fn modulo(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let (a, b) = integer_operands(args)?;
+    if let [Value::Int(x), Value::Int(y)] = args
+        && let Some(remainder) = x.checked_rem_euclid(*y)
+    {
+        return Ok(Value::Int(remainder));
+    }
+    Ok(Value::Number(a.rem_euclid(b)))
+}
+
+/// The hash a map uses for `value`. Instances hash through their own
+/// `hash()` method, so they are rejected rather than hashed by identity.
+pub fn hash(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match HashableValue::new(args[0].clone()) {
+        Some(key) => Ok(Value::Int(key.to_int())),
+        None if args[0].is_instance() => {
+            Err("Call hash() on the instance itself.".to_string().into())
+        }
+        None => Err("Value is not hashable.".to_string().into()),
+    }
+}
+
+fn integer_operands(args: &[Value]) -> Result<(f64, f64), String> {
+    match (args[0].as_f64(), args[1].as_f64()) {
+        (Some(_), Some(0.0)) => Err("Division by zero.".to_string()),
+        (Some(a), Some(b)) => Ok((a, b)),
+        _ => Err("Operands must be numbers.".to_string()),
+    }
 }
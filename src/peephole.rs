@@ -0,0 +1,192 @@
+use crate::chunk::{Chunk, OpCode};
+
+/// Rewrites `chunk` in place to execute fewer instructions:
+///
+/// - a `Jump` or conditional jump that lands on a `Jump` is retargeted to
+///   that jump's destination,
+/// - a `Jump` to the instruction right after it is dropped,
+/// - runs of `Pop` become a single `PopN`.
+///
+/// Dropping and merging instructions shifts everything after them, so every
+/// jump operand is re-encoded against the new layout at the end.
+pub fn optimize(chunk: &mut Chunk) {
+    let Some(mut instructions) = decode(chunk) else {
+        return;
+    };
+    let index_of = |offset: usize, instructions: &[Instruction]| {
+        instructions.binary_search_by_key(&offset, |instruction| instruction.offset)
+    };
+
+    // Thread jumps through unconditional jumps. Only forward jumps are
+    // followed so the final target stays encodable as a forward offset;
+    // that also guarantees the walk terminates.
+    (0..instructions.len()).for_each(|i| {
+        if !matches!(
+            instructions[i].op,
+            OpCode::Jump
+                | OpCode::JumpIfFalse
+                | OpCode::JumpIfTrue
+                | OpCode::JumpIfNil
+                | OpCode::JumpIfNotNil
+        ) {
+            return;
+        }
+        while let Some(target) = instructions[i].target
+            && let Ok(next) = index_of(target, &instructions)
+            && instructions[next].op == OpCode::Jump
+            && instructions[next].target > Some(target)
+        {
+            instructions[i].target = instructions[next].target;
+        }
+    });
+
+    instructions
+        .iter_mut()
+        .filter(|instruction| {
+            instruction.op == OpCode::Jump
+                && instruction.target == Some(instruction.offset + instruction.len)
+        })
+        .for_each(|instruction| instruction.removed = true);
+
+    let mut targeted = vec![false; chunk.code.len() + 1];
+    instructions
+        .iter()
+        .filter(|instruction| !instruction.removed)
+        .filter_map(|instruction| instruction.target)
+        .for_each(|target| targeted[target] = true);
+
+    // A removed jump passes its incoming edges on to the next instruction.
+    let mut incoming = false;
+    instructions.iter_mut().for_each(|instruction| {
+        incoming |= targeted[instruction.offset];
+        if instruction.removed {
+            return;
+        }
+        instruction.is_target = incoming;
+        incoming = false;
+    });
+
+    let mut code = Vec::with_capacity(chunk.code.len());
+    let mut lines = Vec::with_capacity(chunk.lines.len());
+    let mut new_offsets = vec![0; chunk.code.len() + 1];
+    let mut jumps = Vec::new();
+
+    let mut i = 0;
+    while i < instructions.len() {
+        let instruction = &instructions[i];
+        new_offsets[instruction.offset] = code.len();
+
+        if instruction.removed {
+            i += 1;
+            continue;
+        }
+
+        if instruction.op == OpCode::Pop {
+            // Extend the run over pops nobody jumps into the middle of,
+            // looking past dropped jumps that sat between them.
+            let mut count = 1;
+            let mut end = i + 1;
+            let mut next = end;
+            while next < instructions.len() && count < u8::MAX as usize {
+                if instructions[next].removed {
+                    next += 1;
+                    continue;
+                }
+                if instructions[next].op != OpCode::Pop || instructions[next].is_target {
+                    break;
+                }
+                count += 1;
+                next += 1;
+                end = next;
+            }
+
+            if count > 1 {
+                instructions[i + 1..end]
+                    .iter()
+                    .for_each(|merged| new_offsets[merged.offset] = code.len());
+                let line = chunk.lines[instruction.offset];
+                code.extend_from_slice(&[OpCode::PopN.into(), count as u8]);
+                lines.extend_from_slice(&[line, line]);
+                i = end;
+                continue;
+            }
+        }
+
+        if instruction.target.is_some() {
+            jumps.push((code.len(), i));
+        }
+        let range = instruction.offset..instruction.offset + instruction.len;
+        code.extend_from_slice(&chunk.code[range.clone()]);
+        lines.extend_from_slice(&chunk.lines[range]);
+        i += 1;
+    }
+    new_offsets[chunk.code.len()] = code.len();
+
+    jumps.into_iter().for_each(|(offset, i)| {
+        let instruction = &instructions[i];
+        let target = new_offsets[instruction.target.unwrap()];
+        let after = offset + instruction.len;
+        let distance = if instruction.op == OpCode::Loop {
+            after - target
+        } else {
+            target - after
+        };
+        let bytes = (distance as u16).to_be_bytes();
+        code[offset + 1] = bytes[0];
+        code[offset + 2] = bytes[1];
+    });
+
+    chunk.code = code;
+    chunk.lines = lines;
+}
+
+struct Instruction {
+    offset: usize,
+    len: usize,
+    op: OpCode,
+    /// Destination offset, for instructions with a jump operand.
+    target: Option<usize>,
+    removed: bool,
+    is_target: bool,
+}
+
+/// Splits `chunk` into instructions, or `None` if it holds an unknown opcode.
+fn decode(chunk: &Chunk) -> Option<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    while offset < chunk.code.len() {
+        let len = chunk.instruction_len(offset);
+        let op = OpCode::try_from(chunk.code[offset]).ok()?;
+
+        let target = match op {
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfTrue
+            | OpCode::JumpIfNil
+            | OpCode::JumpIfNotNil
+            | OpCode::Loop
+            | OpCode::PushHandler => {
+                let jump = u16::from_be_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+                if op == OpCode::Loop {
+                    Some(offset + len - jump as usize)
+                } else {
+                    Some(offset + len + jump as usize)
+                }
+            }
+            _ => None,
+        };
+
+        instructions.push(Instruction {
+            offset,
+            len,
+            op,
+            target,
+            removed: false,
+            is_target: false,
+        });
+        offset += len;
+    }
+
+    Some(instructions)
+}
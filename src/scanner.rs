@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenType {
     // Single-character tokens
@@ -5,44 +7,73 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
+    QuestionDot,
+    QuestionQuestionEqual,
     Minus,
     Plus,
     Semicolon,
     Slash,
     Star,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
 
     // One or two character tokens
     Bang,
     BangEqual,
+    MinusMinus,
+    PlusPlus,
     Equal,
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
 
     // Literals
     Identifier,
     String,
+    /// The part of an interpolated string before a `${`: from the opening
+    /// `"`, or the `}` ending the previous interpolation, through the `${`.
+    /// The expression inside follows as ordinary tokens, and the rest of the
+    /// string comes after as another `Interpolation` or a `String` that
+    /// starts with `}`.
+    Interpolation,
     Number,
 
     // Keywords
     And,
+    Break,
+    Catch,
     Class,
+    Const,
+    Continue,
     Else,
+    Enum,
     False,
     For,
     Fun,
     If,
+    In,
+    Is,
     Nil,
     Or,
     Print,
     Return,
+    Static,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -55,14 +86,67 @@ pub struct Token<'a> {
     pub token_type: TokenType,
     pub lexeme: &'a str,
     pub line: usize,
+    /// 1-based column of the token's first character, counted in chars.
+    pub column: usize,
+    /// The `///` comment lines right before the token, as written.
+    pub doc: Option<&'a str>,
+}
+
+impl<'a> Token<'a> {
+    /// The text of a `String` or `Interpolation` token, without the quotes,
+    /// braces or `${` around it and with every `\${` turned into `${`.
+    pub fn string_value(&self) -> Cow<'a, str> {
+        let end = match self.token_type {
+            TokenType::Interpolation => "${".len(),
+            _ => 1,
+        };
+        // The delimiters are ASCII, so this slice stays on character
+        // boundaries whatever the string holds.
+        let text = &self.lexeme[1..self.lexeme.len() - end];
+        if text.contains("\\${") {
+            Cow::Owned(text.replace("\\${", "${"))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// The text of the `///` comment before the token, without the slashes
+    /// or the space after them, one line per comment line.
+    pub fn doc_text(&self) -> Option<String> {
+        let lines: Vec<&str> = self
+            .doc?
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("///"))
+            .map(|text| text.strip_prefix(' ').unwrap_or(text))
+            .collect();
+        Some(lines.join("\n"))
+    }
+
+    /// Whether this is the rest of an interpolated string after a `}`,
+    /// rather than a string literal of its own.
+    pub fn continues_string(&self) -> bool {
+        matches!(
+            self.token_type,
+            TokenType::String | TokenType::Interpolation
+        ) && self.lexeme.starts_with('}')
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Scanner<'a> {
     source: &'a str,
     start: usize,
     current: usize,
     line: usize,
+    /// Chars consumed so far on the current line.
+    column: usize,
+    start_column: usize,
+    /// For each `${` whose `}` hasn't been reached yet, innermost last, how
+    /// many `{` inside it are still open.
+    interpolations: Vec<usize>,
+    /// The doc comment `skip_whitespace` last passed over, for the next
+    /// token.
+    doc: Option<&'a str>,
 }
 
 impl<'a> Scanner<'a> {
@@ -72,12 +156,17 @@ impl<'a> Scanner<'a> {
             start: 0,
             current: 0,
             line: 1,
+            column: 0,
+            start_column: 1,
+            interpolations: Vec::new(),
+            doc: None,
         }
     }
 
     pub fn scan_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_column = self.column + 1;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -85,7 +174,7 @@ impl<'a> Scanner<'a> {
 
         let c = self.advance();
 
-        if c.is_ascii_alphabetic() || c == '_' {
+        if is_identifier_start(c) {
             return self.identifier();
         }
 
@@ -96,15 +185,51 @@ impl<'a> Scanner<'a> {
         match c {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RightBrace)
+                }
+                None => self.make_token(TokenType::RightBrace),
+            },
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
+            ':' => self.make_token(TokenType::Colon),
             ';' => self.make_token(TokenType::Semicolon),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
+            '-' => {
+                let token_type = if self.match_char('-') {
+                    TokenType::MinusMinus
+                } else {
+                    TokenType::Minus
+                };
+                self.make_token(token_type)
+            }
+            '+' => {
+                let token_type = if self.match_char('+') {
+                    TokenType::PlusPlus
+                } else {
+                    TokenType::Plus
+                };
+                self.make_token(token_type)
+            }
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
+            '&' => self.make_token(TokenType::Ampersand),
+            '|' => self.make_token(TokenType::Pipe),
+            '^' => self.make_token(TokenType::Caret),
+            '~' => self.make_token(TokenType::Tilde),
             '!' => {
                 let token_type = if self.match_char('=') {
                     TokenType::BangEqual
@@ -124,6 +249,8 @@ impl<'a> Scanner<'a> {
             '<' => {
                 let token_type = if self.match_char('=') {
                     TokenType::LessEqual
+                } else if self.match_char('<') {
+                    TokenType::LessLess
                 } else {
                     TokenType::Less
                 };
@@ -132,16 +259,29 @@ impl<'a> Scanner<'a> {
             '>' => {
                 let token_type = if self.match_char('=') {
                     TokenType::GreaterEqual
+                } else if self.match_char('>') {
+                    TokenType::GreaterGreater
                 } else {
                     TokenType::Greater
                 };
                 self.make_token(token_type)
             }
+            '?' if self.match_char('.') => self.make_token(TokenType::QuestionDot),
+            '?' if self.peek() == '?' && self.peek_next() == '=' => {
+                self.advance();
+                self.advance();
+                self.make_token(TokenType::QuestionQuestionEqual)
+            }
             '"' => self.string(),
             _ => self.error_token("Unexpected character."),
         }
     }
 
+    /// The token `scan_token` will return next, without consuming it.
+    pub fn peek_token(&self) -> Token<'a> {
+        self.clone().scan_token()
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
@@ -149,6 +289,11 @@ impl<'a> Scanner<'a> {
     fn advance(&mut self) -> char {
         let c = self.source[self.current..].chars().next().unwrap();
         self.current += c.len_utf8();
+        if c == '\n' {
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
         c
     }
 
@@ -177,6 +322,7 @@ impl<'a> Scanner<'a> {
             return false;
         }
         self.current += expected.len_utf8();
+        self.column += 1;
         true
     }
 
@@ -185,6 +331,8 @@ impl<'a> Scanner<'a> {
             token_type,
             lexeme: &self.source[self.start..self.current],
             line: self.line,
+            column: self.start_column,
+            doc: self.doc,
         }
     }
 
@@ -193,10 +341,16 @@ impl<'a> Scanner<'a> {
             token_type: TokenType::Error,
             lexeme: message,
             line: self.line,
+            column: self.start_column,
+            doc: None,
         }
     }
 
+    /// Skips to the next token, noting the run of `///` comments right
+    /// before it. Any other comment in between ends the run, so a doc
+    /// comment only belongs to the declaration it sits on.
     fn skip_whitespace(&mut self) {
+        let mut doc: Option<(usize, usize)> = None;
         loop {
             let c = self.peek();
             match c {
@@ -207,22 +361,28 @@ impl<'a> Scanner<'a> {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    let start = self.current;
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
+                    let comment = &self.source[start..self.current];
+                    doc = if comment.starts_with("///") && !comment.starts_with("////") {
+                        Some((doc.map_or(start, |(first, _)| first), self.current))
+                    } else {
+                        None
+                    };
+                }
+                _ => {
+                    self.doc = doc.map(|(start, end)| &self.source[start..end]);
+                    return;
                 }
-                _ => return,
             }
         }
     }
 
     fn identifier(&mut self) -> Token<'a> {
-        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
+        while is_identifier_start(self.peek()) || self.peek().is_alphanumeric() {
             self.advance();
         }
         let token_type = self.identifier_type();
@@ -233,19 +393,29 @@ impl<'a> Scanner<'a> {
         let text = &self.source[self.start..self.current];
         match text {
             "and" => TokenType::And,
+            "break" => TokenType::Break,
+            "catch" => TokenType::Catch,
             "class" => TokenType::Class,
+            "const" => TokenType::Const,
+            "continue" => TokenType::Continue,
             "else" => TokenType::Else,
+            "enum" => TokenType::Enum,
             "false" => TokenType::False,
             "for" => TokenType::For,
             "fun" => TokenType::Fun,
             "if" => TokenType::If,
+            "in" => TokenType::In,
+            "is" => TokenType::Is,
             "nil" => TokenType::Nil,
             "or" => TokenType::Or,
             "print" => TokenType::Print,
             "return" => TokenType::Return,
+            "static" => TokenType::Static,
             "super" => TokenType::Super,
             "this" => TokenType::This,
+            "throw" => TokenType::Throw,
             "true" => TokenType::True,
+            "try" => TokenType::Try,
             "var" => TokenType::Var,
             "while" => TokenType::While,
             _ => TokenType::Identifier,
@@ -253,24 +423,85 @@ impl<'a> Scanner<'a> {
     }
 
     fn number(&mut self) -> Token<'a> {
-        while self.peek().is_ascii_digit() {
+        if !self.digits() {
+            return self.separator_error();
+        }
+
+        if self.peek() == '.' && self.peek_next() == '_' {
             self.advance();
+            return self.separator_error();
         }
 
         if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             self.advance();
 
-            while self.peek().is_ascii_digit() {
+            if !self.digits() {
+                return self.separator_error();
+            }
+        }
+
+        // An `e` straight after the digits always starts an exponent, so
+        // `1e` and `2e+` are errors rather than a number and a name.
+        if matches!(self.peek(), 'e' | 'E') {
+            self.advance();
+            if matches!(self.peek(), '+' | '-') {
                 self.advance();
             }
+            if self.peek() == '_' {
+                return self.separator_error();
+            }
+            if !self.peek().is_ascii_digit() {
+                return self.error_token("Expect digits in exponent.");
+            }
+            if !self.digits() {
+                return self.separator_error();
+            }
         }
 
         self.make_token(TokenType::Number)
     }
 
+    /// Consumes a run of digits, returning false at a `_` that isn't
+    /// followed by a digit. Callers only start a run after a digit, so an
+    /// accepted `_` always sits between two digits, as in `1_000`.
+    fn digits(&mut self) -> bool {
+        loop {
+            match self.peek() {
+                c if c.is_ascii_digit() => {}
+                '_' if self.peek_next().is_ascii_digit() => {}
+                '_' => return false,
+                _ => return true,
+            }
+            self.advance();
+        }
+    }
+
+    /// Skips the rest of a number with a misplaced `_`, fraction and
+    /// exponent included, so that it's reported once rather than again as a
+    /// stray name or `.`.
+    fn separator_error(&mut self) -> Token<'a> {
+        while self.peek().is_ascii_alphanumeric()
+            || self.peek() == '_'
+            || (self.peek() == '.' && self.peek_next().is_ascii_digit())
+        {
+            self.advance();
+        }
+        self.error_token("Separator '_' must be between digits.")
+    }
+    /// Scans the rest of a string after its opening `"` or, when it holds
+    /// interpolations, after the `}` ending one.
     fn string(&mut self) -> Token<'a> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
+            let rest = &self.source[self.current..];
+            if rest.starts_with("\\${") {
+                self.advance();
+                self.advance();
+            } else if rest.starts_with("${") {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                return self.make_token(TokenType::Interpolation);
+            } else if self.peek() == '\n' {
                 self.line += 1;
             }
             self.advance();
@@ -284,3 +515,9 @@ impl<'a> Scanner<'a> {
         self.make_token(TokenType::String)
     }
 }
+
+/// Whether `c` can start an identifier: a letter in any script, or `_`.
+/// Later characters may also be digits, in any script too.
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
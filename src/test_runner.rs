@@ -3,17 +3,43 @@ use std::fs;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 #[derive(Debug, Clone)]
 enum Expectation {
     Output { _line: usize, value: String },
-    CompileError { _line: usize, message: String },
-    RuntimeError { _line: usize, message: String },
+    /// A compile error reported on `line`. `message` is everything after the
+    /// `[line N] ` prefix, or just the text after `Error...: ` when `exact` is
+    /// false (the `// expect error:` form).
+    CompileError {
+        line: usize,
+        message: String,
+        exact: bool,
+    },
+    RuntimeError { line: usize, message: String },
+    /// A line written to stderr by the script itself, such as by `eprint`.
+    Stderr { value: String },
 }
 
 #[derive(Debug)]
 struct TestCase {
     path: PathBuf,
+    /// Extra interpreter flags, passed before the path: the fixture's mode
+    /// flag, then any from a `// args:` line.
+    args: Vec<String>,
+    /// Arguments for the script itself from a `// argv:` line, passed after
+    /// the path.
+    script_args: Vec<String>,
+    /// Environment variables from `// env: NAME=value` lines.
+    env: Vec<(String, String)>,
+    /// A compiled file beside the fixture, named on a `// compiled:` line,
+    /// that is run with `--run` in place of the fixture.
+    compiled: Option<PathBuf>,
+    /// Why `--run` should refuse to load `compiled`, from an
+    /// `// expect load error:` line.
+    load_error: Option<String>,
     expectations: Vec<Expectation>,
 }
 
@@ -31,10 +57,18 @@ struct TestStats {
     skipped: usize,
 }
 
+#[derive(PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
 struct Config {
     verbose: bool,
     show_skipped: bool,
     filter: Option<String>,
+    jobs: usize,
+    format: OutputFormat,
 }
 
 impl TestCase {
@@ -42,58 +76,118 @@ impl TestCase {
         let file = fs::File::open(&path)?;
         let reader = BufReader::new(file);
         let mut expectations = Vec::new();
+        let mut args: Vec<String> = mode_flag(&path).map(str::to_string).into_iter().collect();
+        let mut script_args = Vec::new();
+        let mut env = Vec::new();
+        let mut compiled = None;
+        let mut load_error = None;
 
         reader.lines().enumerate().try_for_each(
             |(line_num, line)| -> Result<(), std::io::Error> {
                 let line = line?;
                 let line_number = line_num + 1;
 
+                if let Some(pos) = line.find("// args:") {
+                    args.extend(line[pos + 8..].split_whitespace().map(str::to_string));
+                }
+
+                if let Some(pos) = line.find("// argv:") {
+                    script_args.extend(line[pos + 8..].split_whitespace().map(str::to_string));
+                }
+
+                if let Some((name, value)) = line
+                    .find("// env:")
+                    .and_then(|pos| line[pos + 7..].trim().split_once('='))
+                {
+                    env.push((name.to_string(), value.to_string()));
+                }
+
+                if let Some(pos) = line.find("// compiled:") {
+                    compiled = path.parent().map(|dir| dir.join(line[pos + 12..].trim()));
+                }
+
+                if let Some(pos) = line.find("// expect load error:") {
+                    load_error = Some(line[pos + 21..].trim().to_string());
+                }
+
                 if let Some(pos) = line.find("// expect:") {
-                    let value = line[pos + 10..].trim().to_string();
+                    // Only the space after the colon is dropped: a token
+                    // dump's trailing space is part of the expected line.
+                    let value = line[pos + 10..]
+                        .strip_prefix(' ')
+                        .unwrap_or(&line[pos + 10..])
+                        .to_string();
                     expectations.push(Expectation::Output {
                         _line: line_number,
                         value,
                     });
                 }
 
+                if let Some(pos) = line.find("// expect stderr:") {
+                    let value = line[pos + 17..]
+                        .strip_prefix(' ')
+                        .unwrap_or(&line[pos + 17..]);
+                    expectations.push(Expectation::Stderr {
+                        value: value.to_string(),
+                    });
+                }
+
                 if let Some(pos) = line.find("// expect runtime error:") {
                     let message = line[pos + 24..].trim().to_string();
                     expectations.push(Expectation::RuntimeError {
-                        _line: line_number,
+                        line: line_number,
+                        message,
+                    });
+                } else if let Some(pos) = line.find("// expect error:") {
+                    let message = line[pos + 16..].trim().to_string();
+                    expectations.push(Expectation::CompileError {
+                        line: line_number,
                         message,
+                        exact: false,
                     });
                 }
 
                 if let Some(pos) = line.find("// Error") {
-                    let error_part = &line[pos + 3..];
                     expectations.push(Expectation::CompileError {
-                        _line: line_number,
-                        message: error_part.to_string(),
-                    });
-                } else if let Some(pos) = line.find("// [line") {
-                    let error_part = &line[pos + 3..];
-                    expectations.push(Expectation::CompileError {
-                        _line: line_number,
-                        message: error_part.to_string(),
+                        line: line_number,
+                        message: line[pos + 3..].trim_end().to_string(),
+                        exact: true,
                     });
+                } else if let Some(expectation) = parse_line_prefixed_error(&line) {
+                    expectations.push(expectation);
                 }
 
                 Ok(())
             },
         )?;
 
-        Ok(TestCase { path, expectations })
+        Ok(TestCase {
+            path,
+            args,
+            script_args,
+            env,
+            compiled,
+            load_error,
+            expectations,
+        })
     }
 
     fn run(&self, interpreter: &Path) -> TestResult {
-        if self.expectations.is_empty() {
+        if self.expectations.is_empty() && self.load_error.is_none() {
             return TestResult::Skip {
                 reason: "No expectations found".to_string(),
             };
         }
 
-        let output = match Command::new(interpreter)
-            .arg(&self.path)
+        let mut command = Command::new(interpreter);
+        command.args(&self.args);
+        match &self.compiled {
+            Some(compiled) => command.arg("--run").arg(compiled),
+            None => command.arg(&self.path),
+        };
+        let output = match command
+            .args(&self.script_args)
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -131,7 +225,7 @@ impl TestCase {
             })
             .collect();
 
-        let expected_exit = if has_compile_error {
+        let expected_exit = if has_compile_error || self.load_error.is_some() {
             65
         } else if has_runtime_error {
             70
@@ -145,28 +239,27 @@ impl TestCase {
             };
         }
 
-        if has_compile_error
-            && let Some(Expectation::CompileError { message, .. }) =
-                self.expectations.iter().find(|exp| {
-                    matches!(exp, Expectation::CompileError { message, .. }
-                        if !stderr.contains(message) && !stdout.contains(message))
-                })
-        {
-            return TestResult::Fail {
-                reason: format!("Expected compile error '{}' not found", message),
-            };
+        if has_compile_error && let Err(reason) = self.check_compile_errors(&stderr) {
+            return TestResult::Fail { reason };
         }
 
-        if has_runtime_error
-            && let Some(Expectation::RuntimeError { message, .. }) =
-                self.expectations.iter().find(|exp| {
-                    matches!(exp, Expectation::RuntimeError { message, .. }
-                        if !stderr.contains(message) && !stdout.contains(message))
-                })
-        {
-            return TestResult::Fail {
-                reason: format!("Expected runtime error '{}' not found", message),
-            };
+        // The message follows the compiled file's path, which depends on
+        // the directory the runner was given.
+        if let Some(message) = &self.load_error {
+            let first = stderr.lines().next().unwrap_or("");
+            if !first.ends_with(&format!(": {}", message)) {
+                return TestResult::Fail {
+                    reason: format!("Expected load error '{}' but got '{}'", message, first),
+                };
+            }
+        }
+
+        if has_runtime_error && let Err(reason) = self.check_runtime_error(&stderr) {
+            return TestResult::Fail { reason };
+        }
+
+        if let Err(reason) = self.check_stderr(&stderr) {
+            return TestResult::Fail { reason };
         }
 
         if !output_expectations.is_empty() {
@@ -201,24 +294,181 @@ impl TestCase {
 
         TestResult::Pass
     }
+
+    /// Every expected compile error must be reported on its line, and every
+    /// reported error must be expected.
+    fn check_compile_errors(&self, stderr: &str) -> Result<(), String> {
+        let mut reported: Vec<(usize, &str)> = stderr
+            .lines()
+            .filter_map(|line| {
+                let rest = line.strip_prefix("[line ")?;
+                let (number, message) = rest.split_once("] ")?;
+                message
+                    .starts_with("Error")
+                    .then(|| (number.parse().ok(), message))
+                    .and_then(|(number, message)| Some((number?, message)))
+            })
+            .collect();
+
+        self.expectations.iter().try_for_each(|expectation| {
+            let Expectation::CompileError {
+                line,
+                message,
+                exact,
+            } = expectation
+            else {
+                return Ok(());
+            };
+
+            let found = reported.iter().position(|&(number, actual)| {
+                number == *line
+                    && if *exact {
+                        actual == message || strip_column(actual) == message
+                    } else {
+                        strip_column(actual)
+                            .split_once(": ")
+                            .is_some_and(|(_, text)| text == message)
+                    }
+            });
+            match found {
+                Some(index) => {
+                    reported.remove(index);
+                    Ok(())
+                }
+                None => Err(format!(
+                    "Expected compile error '[line {}] {}' not found",
+                    line, message
+                )),
+            }
+        })?;
+
+        match reported.first() {
+            Some((number, message)) => Err(format!(
+                "Unexpected compile error '[line {}] {}'",
+                number, message
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// When there are `// expect stderr:` lines, they must be the whole of
+    /// stderr, in order.
+    fn check_stderr(&self, stderr: &str) -> Result<(), String> {
+        let expected: Vec<_> = self
+            .expectations
+            .iter()
+            .filter_map(|e| match e {
+                Expectation::Stderr { value } => Some(value.as_str()),
+                _ => None,
+            })
+            .collect();
+        if expected.is_empty() {
+            return Ok(());
+        }
+
+        let actual: Vec<_> = stderr.lines().collect();
+        if actual.len() != expected.len() {
+            return Err(format!(
+                "Expected {} stderr lines but got {}",
+                expected.len(),
+                actual.len()
+            ));
+        }
+        match expected.iter().zip(&actual).position(|(e, a)| e != a) {
+            Some(i) => Err(format!(
+                "Stderr line {}: expected '{}' but got '{}'",
+                i + 1,
+                expected[i],
+                actual[i]
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The first runtime error expectation must be the first line of stderr
+    /// and the innermost stack frame must point at its line. Any further
+    /// expectations must each match a whole line of the report.
+    fn check_runtime_error(&self, stderr: &str) -> Result<(), String> {
+        let mut expected = self.expectations.iter().filter_map(|e| match e {
+            Expectation::RuntimeError { line, message } => Some((*line, message.as_str())),
+            _ => None,
+        });
+        let Some((line, message)) = expected.next() else {
+            return Ok(());
+        };
+
+        let mut lines = stderr.lines();
+        let first = lines.next().unwrap_or("");
+        if first != message {
+            return Err(format!(
+                "Expected runtime error '{}' but got '{}'",
+                message, first
+            ));
+        }
+
+        let trace_line = lines
+            .find_map(|l| l.strip_prefix("[line ")?.split_once(']'))
+            .and_then(|(number, _)| number.parse::<usize>().ok());
+        if trace_line != Some(line) {
+            return Err(format!(
+                "Expected runtime error on line {} but got {}",
+                line,
+                trace_line.map_or("none".to_string(), |n| n.to_string())
+            ));
+        }
+
+        expected.try_for_each(|(_, message)| {
+            if stderr.lines().any(|l| l == message) {
+                Ok(())
+            } else {
+                Err(format!("Expected runtime error '{}' not found", message))
+            }
+        })
+    }
 }
 
-fn is_scanner_only_test(path: &Path) -> bool {
-    let scanner_only_tests = [
-        "expressions/evaluate.lox",
-        "expressions/parse.lox",
-        "scanning/identifiers.lox",
-        "scanning/keywords.lox",
-        "scanning/numbers.lox",
-        "scanning/punctuators.lox",
-        "scanning/strings.lox",
-        "scanning/whitespace.lox",
-    ];
+/// Parses `// [line N] Error...` and `// [c line N] Error...`. The
+/// `// [java line N]` form only applies to jlox and is ignored.
+fn parse_line_prefixed_error(line: &str) -> Option<Expectation> {
+    let pos = line.find("// [")?;
+    let rest = &line[pos + 4..];
+    let rest = rest.strip_prefix("c ").unwrap_or(rest);
+    let (number, message) = rest.strip_prefix("line ")?.split_once("] ")?;
+
+    Some(Expectation::CompileError {
+        line: number.parse().ok()?,
+        message: message.trim_end().to_string(),
+        exact: true,
+    })
+}
+
+/// Drops the ` (column C)` suffix rlox appends to compile errors, which the
+/// shared test suite does not spell out.
+fn strip_column(message: &str) -> &str {
+    message
+        .rsplit_once(" (column ")
+        .filter(|(_, column)| {
+            column
+                .strip_suffix(')')
+                .is_some_and(|digits| digits.chars().all(|c| c.is_ascii_digit()))
+        })
+        .map_or(message, |(message, _)| message)
+}
+
+/// The interpreter flag a fixture written for one of jlox's early chapters
+/// needs, if any: the scanning chapter's fixtures expect a token dump.
+fn mode_flag(path: &Path) -> Option<&'static str> {
+    let dir = path.parent()?.file_name()?;
+    (dir == "scanning").then_some("--tokens")
+}
+
+/// Fixtures for jlox's expression chapters, which evaluate or print the tree
+/// of a bare expression. rlox has no mode for either.
+fn is_jlox_only_test(path: &Path) -> bool {
+    let jlox_only_tests = ["expressions/evaluate.lox", "expressions/parse.lox"];
 
     let path_str = path.to_string_lossy();
-    scanner_only_tests
-        .iter()
-        .any(|test| path_str.contains(test))
+    jlox_only_tests.iter().any(|test| path_str.contains(test))
 }
 
 fn find_tests(test_dir: &Path) -> Vec<PathBuf> {
@@ -232,7 +482,7 @@ fn find_tests(test_dir: &Path) -> Vec<PathBuf> {
                     tests.extend(find_tests(&path));
                 }
             } else if path.extension().and_then(|s| s.to_str()) == Some("lox")
-                && !is_scanner_only_test(&path)
+                && !is_jlox_only_test(&path)
             {
                 tests.push(path);
             }
@@ -243,6 +493,109 @@ fn find_tests(test_dir: &Path) -> Vec<PathBuf> {
     tests
 }
 
+/// Runs every test on `jobs` worker threads pulling from a shared index,
+/// returning the results in the same order as `test_files`.
+fn run_tests(
+    test_files: &[PathBuf],
+    interpreter: &Path,
+    jobs: usize,
+) -> Vec<Result<TestResult, std::io::Error>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(test_files.len()));
+
+    thread::scope(|scope| {
+        (0..jobs).for_each(|_| {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(test_file) = test_files.get(index) else {
+                        break;
+                    };
+                    let result = TestCase::parse(test_file.clone())
+                        .map(|test_case| test_case.run(interpreter));
+                    results.lock().unwrap().push((index, result));
+                }
+            });
+        });
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Prints `{"tests": [{path, status, reason}, ...], "totals": {...}}` and
+/// returns whether every test passed or was skipped.
+fn print_json_report(
+    test_files: &[PathBuf],
+    results: Vec<Result<TestResult, std::io::Error>>,
+) -> bool {
+    let mut stats = TestStats {
+        total: test_files.len(),
+        passed: 0,
+        failed: 0,
+        skipped: 0,
+    };
+
+    let tests: Vec<String> = test_files
+        .iter()
+        .zip(results)
+        .map(|(test_file, result)| {
+            let (status, reason) = match result {
+                Ok(TestResult::Pass) => {
+                    stats.passed += 1;
+                    ("pass", None)
+                }
+                Ok(TestResult::Fail { reason }) => {
+                    stats.failed += 1;
+                    ("fail", Some(reason))
+                }
+                Ok(TestResult::Skip { reason }) => {
+                    stats.skipped += 1;
+                    ("skip", Some(reason))
+                }
+                Err(e) => {
+                    stats.failed += 1;
+                    ("fail", Some(format!("Failed to parse: {}", e)))
+                }
+            };
+            format!(
+                "{{\"path\":{},\"status\":\"{}\",\"reason\":{}}}",
+                json_string(&test_file.to_string_lossy()),
+                status,
+                reason.map_or("null".to_string(), |reason| json_string(&reason))
+            )
+        })
+        .collect();
+
+    println!(
+        "{{\"tests\":[{}],\"totals\":{{\"total\":{},\"passed\":{},\"failed\":{},\"skipped\":{}}}}}",
+        tests.join(","),
+        stats.total,
+        stats.passed,
+        stats.failed,
+        stats.skipped
+    );
+
+    stats.failed == 0
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    s.chars().for_each(|c| match c {
+        '"' => escaped.push_str("\\\""),
+        '\\' => escaped.push_str("\\\\"),
+        '\n' => escaped.push_str("\\n"),
+        '\r' => escaped.push_str("\\r"),
+        '\t' => escaped.push_str("\\t"),
+        c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+        c => escaped.push(c),
+    });
+    escaped.push('"');
+    escaped
+}
+
 fn print_usage() {
     eprintln!("Rust-Native Lox Test Runner");
     eprintln!();
@@ -256,6 +609,8 @@ fn print_usage() {
     eprintln!("  -v, --verbose       Show all passing tests");
     eprintln!("  -s, --show-skipped  Show skipped tests");
     eprintln!("  -f, --filter <text> Only run tests matching filter");
+    eprintln!("  -j, --jobs <n>      Run n tests in parallel (default: CPU count)");
+    eprintln!("  --format <format>   Report as 'text' (default) or 'json'");
     eprintln!("  -h, --help          Show this help message");
     eprintln!();
     eprintln!("Examples:");
@@ -271,6 +626,8 @@ fn main() {
         verbose: false,
         show_skipped: false,
         filter: None,
+        jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+        format: OutputFormat::Text,
     };
 
     let mut interpreter_path = None;
@@ -293,6 +650,27 @@ fn main() {
                 }
                 config.filter = Some(args[i].clone());
             }
+            "--format" => {
+                i += 1;
+                config.format = match args.get(i).map(String::as_str) {
+                    Some("text") => OutputFormat::Text,
+                    Some("json") => OutputFormat::Json,
+                    _ => {
+                        eprintln!("Error: --format must be 'text' or 'json'");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "-j" | "--jobs" => {
+                i += 1;
+                match args.get(i).and_then(|arg| arg.parse().ok()) {
+                    Some(jobs) if jobs > 0 => config.jobs = jobs,
+                    _ => {
+                        eprintln!("Error: --jobs requires a positive number");
+                        std::process::exit(1);
+                    }
+                }
+            }
             arg => {
                 if interpreter_path.is_none() {
                     interpreter_path = Some(arg.to_string());
@@ -336,6 +714,18 @@ fn main() {
         std::process::exit(1);
     }
 
+    let mut test_files = find_tests(&test_dir);
+
+    if let Some(ref filter) = config.filter {
+        test_files.retain(|path| path.to_string_lossy().contains(filter));
+    }
+
+    if config.format == OutputFormat::Json {
+        let results = run_tests(&test_files, &interpreter, config.jobs);
+        let all_passed = print_json_report(&test_files, results);
+        std::process::exit(if all_passed { 0 } else { 1 });
+    }
+
     println!("🧪 Lox Test Suite");
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!("Interpreter: {}", interpreter.display());
@@ -345,12 +735,6 @@ fn main() {
     }
     println!();
 
-    let mut test_files = find_tests(&test_dir);
-
-    if let Some(ref filter) = config.filter {
-        test_files.retain(|path| path.to_string_lossy().contains(filter));
-    }
-
     let mut stats = TestStats {
         total: test_files.len(),
         passed: 0,
@@ -360,49 +744,48 @@ fn main() {
 
     let mut failures = Vec::new();
 
-    test_files.iter().for_each(|test_file| {
-        let test_case = match TestCase::parse(test_file.clone()) {
-            Ok(tc) => tc,
-            Err(e) => {
-                println!("✗ {} - Failed to parse: {}", test_file.display(), e);
-                stats.failed += 1;
-                return;
-            }
-        };
+    let results = run_tests(&test_files, &interpreter, config.jobs);
 
-        let result = test_case.run(&interpreter);
+    test_files
+        .iter()
+        .zip(results)
+        .for_each(|(test_file, result)| {
+            let result = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    println!("✗ {} - Failed to parse: {}", test_file.display(), e);
+                    stats.failed += 1;
+                    return;
+                }
+            };
 
-        match result {
-            TestResult::Pass => {
-                stats.passed += 1;
-                if config.verbose {
-                    println!("✓ {}", test_file.display());
+            match result {
+                TestResult::Pass => {
+                    stats.passed += 1;
+                    if config.verbose {
+                        println!("✓ {}", test_file.display());
+                    }
                 }
-            }
-            TestResult::Fail { reason } => {
-                stats.failed += 1;
-                println!("✗ {}", test_file.display());
-                println!("  {}", reason);
-                failures.push((test_file.display().to_string(), reason));
-            }
-            TestResult::Skip { reason } => {
-                stats.skipped += 1;
-                if config.show_skipped {
-                    println!("⊘ {} - {}", test_file.display(), reason);
+                TestResult::Fail { reason } => {
+                    stats.failed += 1;
+                    println!("✗ {}", test_file.display());
+                    println!("  {}", reason);
+                    failures.push((test_file.display().to_string(), reason));
+                }
+                TestResult::Skip { reason } => {
+                    stats.skipped += 1;
+                    if config.show_skipped {
+                        println!("⊘ {} - {}", test_file.display(), reason);
+                    }
                 }
             }
-        }
-    });
+        });
 
     println!();
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!("Total tests: {}", stats.total);
 
-    let pass_percent = if stats.total > 0 {
-        (stats.passed * 100) / stats.total
-    } else {
-        0
-    };
+    let pass_percent = (stats.passed * 100).checked_div(stats.total).unwrap_or(0);
 
     println!("✓ Passed: {} ({}%)", stats.passed, pass_percent);
     if stats.failed > 0 {
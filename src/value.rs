@@ -1,13 +1,21 @@
 use crate::chunk::Chunk;
-use std::cell::RefCell;
+use crate::vm::VM;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::rc::{Rc, Weak};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Debug, Clone)]
 pub enum Value {
     Nil,
     Bool(bool),
+    /// Produced by integer literals and kept by `+`, `-` and `*` while both
+    /// operands are integers and the result fits. Anything else, including
+    /// any `/`, gives a `Number`.
+    Int(i64),
     Number(f64),
     Obj(Rc<Obj>),
 }
@@ -17,6 +25,57 @@ impl Value {
         matches!(self, Value::Obj(obj) if matches!(**obj, Obj::Instance(_)))
     }
 
+    pub fn is_string(&self) -> bool {
+        matches!(self, Value::Obj(obj) if matches!(**obj, Obj::String(_)))
+    }
+
+    /// The text of a string value.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Obj(obj) => match &**obj {
+                Obj::String(s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The closure a closure value holds. Cloning just the inner `Rc` is
+    /// one refcount bump where cloning the value and then the closure is two.
+    pub fn as_closure(&self) -> Option<Rc<Closure>> {
+        match self {
+            Value::Obj(obj) => match &**obj {
+                Obj::Closure(closure) => Some(Rc::clone(closure)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether a call of this value can succeed: a function, bound method,
+    /// class or native.
+    pub fn is_callable(&self) -> bool {
+        matches!(self, Value::Obj(obj) if matches!(
+            **obj,
+            Obj::Closure(_) | Obj::BoundMethod(_) | Obj::Class(_) | Obj::Native(_)
+        ))
+    }
+
+    /// The `///` comment written before the declaration of this function,
+    /// method or class, if it had one.
+    pub fn doc_comment(&self) -> Option<&Rc<str>> {
+        let Value::Obj(obj) = self else {
+            return None;
+        };
+        match &**obj {
+            Obj::Function(function) => function.doc.as_ref(),
+            Obj::Closure(closure) => closure.function.doc.as_ref(),
+            Obj::BoundMethod(bound) => bound.method.function.doc.as_ref(),
+            Obj::Class(class) => class.doc.as_ref(),
+            _ => None,
+        }
+    }
+
     pub fn is_falsey(&self) -> bool {
         match self {
             Value::Nil => true,
@@ -24,6 +83,84 @@ impl Value {
             _ => false,
         }
     }
+
+    /// The value as a float, if it is a number of either kind.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Orders two numbers or two strings the way `<` and `>` do, or returns
+    /// `None` for any other pair. Integers compare exactly and mixed numbers
+    /// as floats, so NaN gives `Some(None)`. Strings compare by their UTF-8
+    /// bytes, which orders them by code point.
+    pub fn compare(&self, other: &Value) -> Option<Option<std::cmp::Ordering>> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Some(Some(a.cmp(b))),
+            _ => match (self.as_f64(), other.as_f64(), self.as_str(), other.as_str()) {
+                (Some(a), Some(b), _, _) => Some(a.partial_cmp(&b)),
+                (_, _, Some(a), Some(b)) => Some(Some(a.cmp(b))),
+                _ => None,
+            },
+        }
+    }
+
+    /// Negates a number, or returns `None` for any other value.
+    pub fn negate(&self) -> Option<Value> {
+        match self {
+            // Integers have no negative zero, so `-0` stays a float.
+            Value::Int(0) => Some(Value::Number(-0.0)),
+            Value::Int(i) => Some(
+                i.checked_neg()
+                    .map_or(Value::Number(-(*i as f64)), Value::Int),
+            ),
+            Value::Number(n) => Some(Value::Number(-n)),
+            _ => None,
+        }
+    }
+}
+
+pub fn add(a: &Value, b: &Value) -> Option<Value> {
+    arithmetic(a, b, i64::checked_add, |a, b| a + b)
+}
+
+pub fn subtract(a: &Value, b: &Value) -> Option<Value> {
+    arithmetic(a, b, i64::checked_sub, |a, b| a - b)
+}
+
+pub fn multiply(a: &Value, b: &Value) -> Option<Value> {
+    arithmetic(a, b, i64::checked_mul, |a, b| a * b)
+}
+
+pub fn divide(a: &Value, b: &Value) -> Option<Value> {
+    arithmetic(a, b, |_, _| None, |a, b| a / b)
+}
+
+/// Applies an arithmetic operator to two numbers, or returns `None` if
+/// either isn't one. Two integers stay an `Int` unless `int_op` overflows,
+/// in which case the result is computed in floating point instead.
+fn arithmetic(
+    a: &Value,
+    b: &Value,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Option<Value> {
+    if let (Value::Int(x), Value::Int(y)) = (a, b)
+        && let Some(result) = int_op(*x, *y)
+    {
+        return Some(Value::Int(result));
+    }
+    Some(Value::Number(float_op(a.as_f64()?, b.as_f64()?)))
+}
+
+/// Whether an integer and a float are the same number, exactly.
+fn int_equals_float(i: i64, n: f64) -> bool {
+    // 2^63 itself saturates to i64::MAX when cast, so exclude it first.
+    n.fract() == 0.0 && (i64::MIN as f64..-(i64::MIN as f64)).contains(&n)
+        && n as i64 == i
 }
 
 impl PartialEq for Value {
@@ -32,6 +169,10 @@ impl PartialEq for Value {
             (Value::Nil, Value::Nil) => true,
             (Value::Bool(a), Value::Bool(b)) => a == b,
             (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(i), Value::Number(n)) | (Value::Number(n), Value::Int(i)) => {
+                int_equals_float(*i, *n)
+            }
             (Value::Obj(a), Value::Obj(b)) => match (&**a, &**b) {
                 (Obj::String(s1), Obj::String(s2)) => Rc::ptr_eq(s1, s2),
                 _ => Rc::ptr_eq(a, b),
@@ -46,12 +187,107 @@ impl fmt::Display for Value {
         match self {
             Value::Nil => write!(f, "nil"),
             Value::Bool(b) => write!(f, "{}", b),
-            Value::Number(n) => write!(f, "{}", n),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Number(n) => write!(f, "{}", format_number(*n, DEFAULT_PRECISION)),
             Value::Obj(obj) => write!(f, "{}", obj),
         }
     }
 }
 
+impl Value {
+    /// Displays the value with numbers, including those inside lists and
+    /// maps, rounded to `precision` significant digits.
+    pub fn display(&self, precision: usize) -> ValueDisplay<'_> {
+        ValueDisplay {
+            value: self,
+            style: Style {
+                precision,
+                repr: false,
+            },
+        }
+    }
+
+    /// Displays the value for debugging: like [`Value::display`], but
+    /// strings are quoted and classes shown as `<class Name>`, so values
+    /// that print alike, such as `1` and `"1"`, can be told apart.
+    pub fn repr(&self, precision: usize) -> ValueDisplay<'_> {
+        ValueDisplay {
+            value: self,
+            style: Style {
+                precision,
+                repr: true,
+            },
+        }
+    }
+}
+
+/// A value printed with a chosen number precision, from [`Value::display`]
+/// or [`Value::repr`].
+pub struct ValueDisplay<'a> {
+    value: &'a Value,
+    style: Style,
+}
+
+#[derive(Clone, Copy)]
+struct Style {
+    precision: usize,
+    repr: bool,
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_element(self.value, f, &mut Vec::new(), self.style)
+    }
+}
+
+/// Significant digits `print` uses unless a script calls `set_precision`,
+/// matching C's default `%g`.
+pub const DEFAULT_PRECISION: usize = 6;
+
+/// Formats a number the way clox's `printf("%.*g", precision, value)` does.
+///
+/// Values are rounded to `precision` significant digits. Exponents below -4
+/// or at or above the precision switch to scientific notation (`1e+21`),
+/// and trailing zeros in the fraction are trimmed in both forms.
+pub fn format_number(n: f64, precision: usize) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+
+    // Round to the target precision first; the exponent of the rounded
+    // value decides between fixed and scientific notation.
+    let scientific = format!("{:.*e}", precision - 1, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    if (-4..precision as i32).contains(&exponent) {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        trim_fraction(&format!("{:.*}", decimals, n)).to_string()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{}{:02}",
+            trim_fraction(mantissa),
+            sign,
+            exponent.unsigned_abs()
+        )
+    }
+}
+
+fn trim_fraction(digits: &str) -> &str {
+    if digits.contains('.') {
+        digits.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        digits
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Obj {
     String(Rc<str>),
@@ -61,6 +297,9 @@ pub enum Obj {
     Class(Rc<Class>),
     Instance(Rc<Instance>),
     BoundMethod(Rc<BoundMethod>),
+    Map(Rc<Map>),
+    List(Rc<List>),
+    ListIterator(Rc<ListIterator>),
 }
 
 impl fmt::Display for Obj {
@@ -83,13 +322,7 @@ impl fmt::Display for Obj {
                 }
             }
             Obj::Class(class) => write!(f, "{}", class.name),
-            Obj::Instance(instance) => {
-                if let Some(class) = instance.class.upgrade() {
-                    write!(f, "{} instance", class.name)
-                } else {
-                    write!(f, "<dropped class> instance")
-                }
-            }
+            Obj::Instance(instance) => write!(f, "{} instance", instance.class.name),
             Obj::BoundMethod(bound) => {
                 if let Some(name) = &bound.method.function.name {
                     write!(f, "<fn {}>", name)
@@ -97,7 +330,84 @@ impl fmt::Display for Obj {
                     write!(f, "<script>")
                 }
             }
+            Obj::Map(_) | Obj::List(_) => {
+                let style = Style {
+                    precision: DEFAULT_PRECISION,
+                    repr: false,
+                };
+                write_container(self, f, &mut Vec::new(), style)
+            }
+            Obj::ListIterator(_) => write!(f, "<iterator>"),
+        }
+    }
+}
+
+/// Writes a list or map with its elements, showing one that contains itself
+/// as `[...]` or `{...}` where it recurs instead of looping forever. `open`
+/// holds the containers being written further up, so a container that is
+/// merely shared by two siblings is still written out both times.
+fn write_container(
+    obj: &Obj,
+    f: &mut fmt::Formatter<'_>,
+    open: &mut Vec<*const ()>,
+    style: Style,
+) -> fmt::Result {
+    let ptr = match obj {
+        Obj::Map(map) => Rc::as_ptr(map) as *const (),
+        Obj::List(list) => Rc::as_ptr(list) as *const (),
+        _ => return write!(f, "{}", obj),
+    };
+    if open.contains(&ptr) {
+        return match obj {
+            Obj::Map(_) => write!(f, "{{...}}"),
+            _ => write!(f, "[...]"),
+        };
+    }
+
+    open.push(ptr);
+    match obj {
+        Obj::Map(map) => {
+            write!(f, "{{")?;
+            for (i, (key, value)) in map.entries.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_element(key.value(), f, open, style)?;
+                write!(f, ": ")?;
+                write_element(value, f, open, style)?;
+            }
+            write!(f, "}}")?;
+        }
+        Obj::List(list) => {
+            write!(f, "[")?;
+            for (i, item) in list.items.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_element(item, f, open, style)?;
+            }
+            write!(f, "]")?;
         }
+        _ => unreachable!(),
+    }
+    open.pop();
+    Ok(())
+}
+
+fn write_element(
+    value: &Value,
+    f: &mut fmt::Formatter<'_>,
+    open: &mut Vec<*const ()>,
+    style: Style,
+) -> fmt::Result {
+    match value {
+        Value::Number(n) => write!(f, "{}", format_number(*n, style.precision)),
+        Value::Obj(obj) => match &**obj {
+            Obj::String(s) if style.repr => write!(f, "\"{}\"", s),
+            Obj::Class(class) if style.repr => write!(f, "<class {}>", class.name),
+            _ => write_container(obj, f, open, style),
+        },
+        _ => write!(f, "{}", value),
     }
 }
 
@@ -135,6 +445,8 @@ pub struct Function {
     pub upvalue_count: usize,
     pub chunk: Chunk,
     pub name: Option<Rc<str>>,
+    /// The `///` comment before the declaration, for `doc()`.
+    pub doc: Option<Rc<str>>,
 }
 
 impl Function {
@@ -144,6 +456,7 @@ impl Function {
             upvalue_count: 0,
             chunk: Chunk::new(),
             name: None,
+            doc: None,
         }
     }
 }
@@ -154,25 +467,114 @@ impl Default for Function {
     }
 }
 
+/// A native receives the VM and between `arity` and `max_arity` arguments;
+/// an `Err` is raised as a runtime error in the calling Lox code.
+pub type NativeFn = fn(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError>;
+
+/// A native that may call back into Lox code, such as `sort` with a
+/// comparator. Rather than running the call itself, it returns a
+/// `NativeStep::Call` and the VM runs the function in a frame of its own,
+/// so callbacks nest on the VM's stacks instead of Rust's.
+pub type SteppedNativeFn = fn(vm: &mut VM, args: &[Value]) -> Result<NativeStep, NativeError>;
+
+/// The rest of a stepped native's work, given the result of the function
+/// it asked to call.
+pub type Continuation = Box<dyn FnOnce(&mut VM, Value) -> Result<NativeStep, NativeError>>;
+
+pub enum NativeStep {
+    /// The native is finished and this is its result.
+    Done(Value),
+    /// Call `callee` with `args`, then pass the result to `then`. The call
+    /// may be to another native, stepped or not.
+    Call {
+        callee: Value,
+        args: Vec<Value>,
+        then: Continuation,
+    },
+}
+
+impl NativeStep {
+    pub fn call<F>(callee: Value, args: Vec<Value>, then: F) -> Self
+    where
+        F: FnOnce(&mut VM, Value) -> Result<NativeStep, NativeError> + 'static,
+    {
+        NativeStep::Call {
+            callee,
+            args,
+            then: Box::new(then),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NativeError {
+    /// A message to raise as a runtime error.
+    Message(String),
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError::Message(message)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum NativeFunction {
+    Plain(NativeFn),
+    Stepped(SteppedNativeFn),
+}
+
 #[derive(Clone)]
 pub struct Native {
-    pub function: fn(arg_count: usize, args: &[Value]) -> Value,
+    pub arity: usize,
+    /// At least `arity`; any arguments past `arity` are optional.
+    pub max_arity: usize,
+    pub function: NativeFunction,
 }
 
 impl fmt::Debug for Native {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ObjNative")
+            .field("arity", &self.arity)
+            .field("max_arity", &self.max_arity)
             .field("function", &"<native fn>")
             .finish()
     }
 }
 
-#[derive(Debug, Clone)]
+/// How many closures and instances exist right now. Once the VM is gone,
+/// any that remain are only kept alive by reference cycles, which `Rc`
+/// never frees; `--leak-check` reports them.
+static LIVE_CLOSURES: AtomicUsize = AtomicUsize::new(0);
+static LIVE_INSTANCES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn live_closures() -> usize {
+    LIVE_CLOSURES.load(Ordering::Relaxed)
+}
+
+pub fn live_instances() -> usize {
+    LIVE_INSTANCES.load(Ordering::Relaxed)
+}
+
+#[derive(Debug)]
 pub struct Closure {
     pub function: Rc<Function>,
     pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
 }
 
+impl Closure {
+    pub fn new(function: Rc<Function>, upvalues: Vec<Rc<RefCell<Upvalue>>>) -> Self {
+        LIVE_CLOSURES.fetch_add(1, Ordering::Relaxed);
+        Closure { function, upvalues }
+    }
+}
+
+impl Drop for Closure {
+    fn drop(&mut self) {
+        LIVE_CLOSURES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Upvalue {
     pub location: usize,
@@ -180,20 +582,26 @@ pub struct Upvalue {
 }
 
 impl Upvalue {
-    pub fn get_value(&self, stack: &[Value]) -> Value {
+    /// The captured value, or `None` if the stack has shrunk below an open
+    /// upvalue's slot, which only corrupt bytecode can cause.
+    pub fn get_value(&self, stack: &[Value]) -> Option<Value> {
         if let Some(closed) = &self.closed {
-            closed.clone()
+            Some(closed.clone())
         } else {
-            stack[self.location].clone()
+            stack.get(self.location).cloned()
         }
     }
 
-    pub fn set_value(&mut self, value: Value, stack: &mut [Value]) {
+    /// Stores `value`, returning false where `get_value` gives `None`.
+    pub fn set_value(&mut self, value: Value, stack: &mut [Value]) -> bool {
         if self.closed.is_some() {
             self.closed = Some(value);
+        } else if let Some(slot) = stack.get_mut(self.location) {
+            *slot = value;
         } else {
-            stack[self.location] = value;
+            return false;
         }
+        true
     }
 }
 
@@ -201,16 +609,243 @@ impl Upvalue {
 pub struct Class {
     pub name: Rc<str>,
     pub methods: RefCell<HashMap<Rc<str>, Value>>,
+    /// Parameterless methods run on property access, like `circle.radius`.
+    pub getters: RefCell<HashMap<Rc<str>, Value>>,
+    /// Methods declared `static`, called on the class itself without `this`.
+    pub static_methods: RefCell<HashMap<Rc<str>, Value>>,
+    /// The class named after `<`, set when the subclass inherits from it.
+    /// Members the subclass doesn't declare itself are looked up there.
+    pub superclass: RefCell<Option<Rc<Class>>>,
+    /// The `///` comment before the declaration, for `doc()`.
+    pub doc: Option<Rc<str>>,
 }
 
-#[derive(Debug, Clone)]
+impl Class {
+    /// Finds the method `name` in this class or the nearest superclass that
+    /// declares it. A getter by that name declared nearer hides the method.
+    pub fn find_method(&self, name: &str) -> Option<Rc<Closure>> {
+        if self.getters.borrow().contains_key(name) {
+            return None;
+        }
+        if let Some(method) = self.methods.borrow().get(name) {
+            return method.as_closure();
+        }
+        self.superclass.borrow().as_ref()?.find_method(name)
+    }
+
+    /// Like `find_method`, for getters.
+    pub fn find_getter(&self, name: &str) -> Option<Rc<Closure>> {
+        if self.methods.borrow().contains_key(name) {
+            return None;
+        }
+        if let Some(getter) = self.getters.borrow().get(name) {
+            return getter.as_closure();
+        }
+        self.superclass.borrow().as_ref()?.find_getter(name)
+    }
+
+    /// The names of this class's methods and getters, inherited ones
+    /// included.
+    pub fn method_names(&self) -> Vec<Rc<str>> {
+        let mut names: Vec<Rc<str>> = self.methods.borrow().keys().cloned().collect();
+        names.extend(self.getters.borrow().keys().cloned());
+        if let Some(superclass) = self.superclass.borrow().as_ref() {
+            names.extend(superclass.method_names());
+        }
+        names
+    }
+
+    /// Finds the static method `name` in this class or a superclass.
+    pub fn find_static_method(&self, name: &str) -> Option<Value> {
+        if let Some(method) = self.static_methods.borrow().get(name) {
+            return Some(method.clone());
+        }
+        self.superclass.borrow().as_ref()?.find_static_method(name)
+    }
+
+    /// Whether this is `other` or a class that inherits from it, however
+    /// indirectly.
+    pub fn inherits_from(&self, other: &Class) -> bool {
+        std::ptr::eq(self, other)
+            || self
+                .superclass
+                .borrow()
+                .as_ref()
+                .is_some_and(|superclass| superclass.inherits_from(other))
+    }
+}
+
+#[derive(Debug)]
 pub struct Instance {
-    pub class: Weak<Class>,
+    /// Held strongly, so an instance keeps working after its class goes out
+    /// of scope, such as one made from a class declared in a block.
+    pub class: Rc<Class>,
     pub fields: RefCell<HashMap<Rc<str>, Value>>,
 }
 
+impl Instance {
+    pub fn new(class: &Rc<Class>) -> Self {
+        LIVE_INSTANCES.fetch_add(1, Ordering::Relaxed);
+        Instance {
+            class: Rc::clone(class),
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        LIVE_INSTANCES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BoundMethod {
     pub receiver: Value,
     pub method: Rc<Closure>,
 }
+
+#[derive(Debug, Default)]
+pub struct Map {
+    pub entries: RefCell<MapEntries>,
+}
+
+/// A map's entries in the order their keys were first inserted, so that
+/// printing a map and iterating over it always go in the same order.
+#[derive(Debug, Default)]
+pub struct MapEntries {
+    /// Where each key's entry is in `entries`.
+    positions: HashMap<HashableValue, usize>,
+    entries: Vec<(HashableValue, Value)>,
+}
+
+impl MapEntries {
+    pub fn get(&self, key: &HashableValue) -> Option<&Value> {
+        self.positions.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    /// Sets the value for `key`. A new key goes after every other; one that
+    /// is already present keeps its place.
+    pub fn insert(&mut self, key: HashableValue, value: Value) {
+        match self.positions.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.positions.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&HashableValue, &Value)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &HashableValue> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct List {
+    pub items: RefCell<Vec<Value>>,
+}
+
+/// What `iter()` on a list or map returns: a position in a list, which for
+/// a map is a list of its keys.
+#[derive(Debug)]
+pub struct ListIterator {
+    pub list: Rc<List>,
+    /// Index of the element `next()` returns.
+    pub next: Cell<usize>,
+}
+
+/// A `Value` usable as a map key.
+///
+/// Only values with a stable identity qualify: `nil`, booleans, numbers
+/// (hashed by bit pattern, with `-0` folded into `0` and every NaN into one
+/// NaN so that they can be looked up again), strings (hashed by their
+/// interned pointer) and instances whose class defines a `hash()` method.
+/// Such an instance is keyed by the number its `hash()` returned, so two
+/// instances of one class with equal hashes are the same key. Other objects
+/// (functions, classes, maps, lists, plain instances) can't be keys.
+#[derive(Debug, Clone)]
+pub struct HashableValue {
+    value: Value,
+    hash: u64,
+}
+
+impl HashableValue {
+    /// Wraps `value` if it hashes without running Lox code.
+    pub fn new(value: Value) -> Option<Self> {
+        let hash = match &value {
+            Value::Nil => mix(0, 0),
+            Value::Bool(b) => mix(1, *b as u64),
+            // An Int and an equal Number must land in the same bucket.
+            Value::Int(i) => mix(2, number_bits(*i as f64)),
+            Value::Number(n) => mix(2, number_bits(*n)),
+            Value::Obj(obj) => match &**obj {
+                Obj::String(s) => mix(3, Rc::as_ptr(s) as *const u8 as u64),
+                _ => return None,
+            },
+        };
+        Some(Self { value, hash })
+    }
+
+    /// Wraps an instance keyed by the result of its `hash()` method.
+    pub fn instance(value: Value, hash: f64) -> Self {
+        Self {
+            value,
+            hash: mix(4, number_bits(hash)),
+        }
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// The hash as a Lox integer, keeping the 53 bits an `f64` holds exactly.
+    pub fn to_int(&self) -> i64 {
+        (self.hash & ((1 << 53) - 1)) as i64
+    }
+}
+
+impl PartialEq for HashableValue {
+    fn eq(&self, other: &Self) -> bool {
+        if self.hash != other.hash {
+            return false;
+        }
+        match (&self.value, &other.value) {
+            (Value::Number(a), Value::Number(b)) => number_bits(*a) == number_bits(*b),
+            (Value::Obj(a), Value::Obj(b)) => match (&**a, &**b) {
+                (Obj::Instance(a), Obj::Instance(b)) => Rc::ptr_eq(&a.class, &b.class),
+                _ => self.value == other.value,
+            },
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl Eq for HashableValue {}
+
+impl Hash for HashableValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+fn number_bits(n: f64) -> u64 {
+    if n == 0.0 {
+        0
+    } else if n.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        n.to_bits()
+    }
+}
+
+fn mix(tag: u8, payload: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tag.hash(&mut hasher);
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
@@ -1,467 +1,1336 @@
-use crate::chunk::OpCode;
-use crate::compiler::Compiler;
+use crate::builtin;
+use crate::bytecode;
+use crate::chunk::{OpCode, PropertyCache};
+use crate::compiler::{CompileDiagnostic, CompileOptions, Compiler};
 use crate::native;
 use crate::value::{
-    BoundMethod, Class, Closure, Instance, Native, Obj, StringInterner, Upvalue, Value,
+    self, BoundMethod, Class, Closure, Continuation, Function, HashableValue, Instance, List, Map,
+    Native, NativeError, NativeFn, NativeFunction, NativeStep, Obj, SteppedNativeFn,
+    StringInterner, Upvalue, Value,
 };
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufWriter, Write};
 use std::rc::Rc;
+use std::time::Instant;
 
-const FRAMES_MAX: usize = 64;
+/// Default limits for `VM::new`; see `VM::with_limits`.
+pub const FRAMES_MAX: usize = 64;
 pub const U8_COUNT: usize = u8::MAX as usize + 1;
-const STACK_MAX: usize = FRAMES_MAX * U8_COUNT;
+pub const U16_COUNT: usize = u16::MAX as usize + 1;
+pub const STACK_MAX: usize = FRAMES_MAX * U8_COUNT;
+/// Innermost and outermost frames kept when a stack trace is abbreviated.
+const TRACE_HEAD_FRAMES: usize = 10;
+const TRACE_TAIL_FRAMES: usize = 3;
 
 #[derive(Debug)]
 struct CallFrame {
     closure: Rc<Closure>,
     ip: usize,
     slot_offset: usize,
+    on_return: ReturnAction,
+}
+
+/// What to do with a frame's result before handing it back to the caller.
+/// Operators implemented by calling into Lox code use this to finish their
+/// work once the call returns.
+///
+/// This is how the VM calls Lox code without running a nested dispatch
+/// loop: whatever needs the call pushes its frame with `call_then` and
+/// returns to the loop, which runs the call like any other. When the frame
+/// returns, its action picks up where the operator left off, with the
+/// operands it needs still on the stack below the frame. A `throw` that
+/// unwinds past the frame drops the action along with it.
+#[derive(Debug)]
+enum ReturnAction {
+    Keep,
+    /// Replace the result by whether it is truthy, for `==`.
+    Truthy,
+    /// Replace the result by whether it is falsey, for `!=`.
+    Falsey,
+    /// The result is an instance key's `hash()`; finish the map operation
+    /// that needed it instead of pushing it.
+    Map(MapOp),
+    /// Drop the result, leaving the value the caller tucked under the
+    /// receiver, for `obj[key] = value`.
+    Discard,
+    /// The result is a `toString()`; store it `depth` slots below the top,
+    /// over the instance it describes, for the retried instruction to use.
+    Stringify(usize),
+    /// Pass the result on to stepped natives; see `run_native`.
+    Resume(Waiting),
+}
+
+/// The continuations of stepped natives waiting for a call to return,
+/// innermost last. Each native's own callee slot is still on the stack,
+/// in the same order, to receive its result.
+struct Waiting(Vec<Continuation>);
+
+impl fmt::Debug for Waiting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} waiting natives>", self.0.len())
+    }
+}
+
+/// A map operation whose key is found `key_depth` slots below the top of
+/// the stack, with the map right below the key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MapOp {
+    /// `map[key]`: replaces map and key by the value.
+    Index,
+    /// `map[key] = value`: replaces all three by the value.
+    IndexSet,
+    /// One entry of a map literal: leaves the map.
+    Entry,
+}
+
+impl MapOp {
+    fn key_depth(self) -> usize {
+        match self {
+            MapOp::Index => 0,
+            MapOp::IndexSet | MapOp::Entry => 1,
+        }
+    }
+}
+
+/// An active `try` block: where to resume and how much of the frame and
+/// value stacks to keep when a value is thrown inside it.
+#[derive(Debug)]
+struct ExceptionHandler {
+    frame_count: usize,
+    stack_height: usize,
+    catch_ip: usize,
+}
+
+/// Global variables, addressed by slot.
+///
+/// The compiler assigns a slot the first time it sees a global name, so a
+/// function can reference a global that is only defined later; the slot
+/// reads as undefined until its `DefineGlobalSlot` runs. The table is owned
+/// by the VM and shared by every compile, so globals introduced by one REPL
+/// line keep their slots for the next.
+#[derive(Debug, Default)]
+pub struct Globals {
+    slots: HashMap<Rc<str>, usize>,
+    names: Vec<Rc<str>>,
+    values: Vec<Option<Value>>,
+    /// Slots whose assignments `VM::take_global_change` reports.
+    watched: HashSet<usize>,
+    /// Per slot, whether its latest definition was a `const` one, which
+    /// can't be assigned until a `var` redefines it. Every global assignment
+    /// checks this, so it is indexed rather than hashed.
+    consts: Vec<bool>,
+}
+
+impl Globals {
+    /// Returns the slot for `name`, allocating an undefined one if needed.
+    pub fn resolve(&mut self, name: Rc<str>) -> usize {
+        if let Some(&slot) = self.slots.get(&name) {
+            return slot;
+        }
+
+        let slot = self.names.len();
+        self.slots.insert(Rc::clone(&name), slot);
+        self.names.push(name);
+        self.values.push(None);
+        self.consts.push(false);
+        slot
+    }
+
+    /// Global names in slot order, as compiled code refers to them.
+    pub fn names(&self) -> &[Rc<str>] {
+        &self.names
+    }
+
+    fn name(&self, slot: usize) -> &Rc<str> {
+        &self.names[slot]
+    }
+
+    fn get(&self, slot: usize) -> Option<&Value> {
+        self.values[slot].as_ref()
+    }
+
+    fn define(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+
+    /// Whether the latest definition of `slot` that has run was a `const`
+    /// one. The compiler checks this for slots its own source doesn't
+    /// declare, such as those of earlier REPL lines.
+    pub fn is_const(&self, slot: usize) -> bool {
+        self.consts[slot]
+    }
+
+    fn set_const(&mut self, slot: usize, is_const: bool) {
+        self.consts[slot] = is_const;
+    }
+
+    fn is_watched(&self, slot: usize) -> bool {
+        !self.watched.is_empty() && self.watched.contains(&slot)
+    }
+
+    fn is_defined(&self, slot: usize) -> bool {
+        self.values[slot].is_some()
+    }
+
+    fn defined_count(&self) -> usize {
+        self.values.iter().filter(|value| value.is_some()).count()
+    }
+
+    /// Names of the globals holding a value, in slot order.
+    fn defined_names(&self) -> impl Iterator<Item = &Rc<str>> {
+        self.names
+            .iter()
+            .zip(&self.values)
+            .filter(|(_, value)| value.is_some())
+            .map(|(name, _)| name)
+    }
+
+    /// Whether `name` holds a value, without allocating a slot for it.
+    fn is_name_defined(&self, name: &str) -> bool {
+        self.slots
+            .get(name)
+            .is_some_and(|&slot| self.is_defined(slot))
+    }
 }
 
 #[derive(Debug)]
 pub struct VM {
     frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    globals: HashMap<Rc<str>, Value>,
-    open_upvalues: HashMap<usize, Rc<RefCell<Upvalue>>>,
+    globals: Globals,
+    /// Upvalues still pointing into the stack, sorted by stack location.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    handlers: Vec<ExceptionHandler>,
+    pending_exception: Option<Value>,
+    /// The last assignment of a watched global, until the host takes it.
+    global_change: Option<GlobalChange>,
+    /// Source lines `continue_run` halts at.
+    breakpoints: HashSet<usize>,
+    /// The frame depth and instruction `continue_run` last halted at, so
+    /// that resuming runs it rather than halting there again.
+    halted_at: Option<(usize, usize)>,
     init_string: Rc<str>,
     interner: StringInterner,
+    compile_options: CompileOptions,
+    /// Call depth at which a call raises "Stack overflow.".
+    frames_max: usize,
+    /// Value stack size past which execution raises "Stack overflow.".
+    stack_max: usize,
+    /// Instructions left to run, when a budget was set with `with_fuel`.
+    fuel: Option<u64>,
+    /// Command-line arguments after the script path, as `argv()` returns.
+    script_args: Vec<String>,
+    /// When the VM was created, which `monotonic()` counts from.
+    start_time: Instant,
+    /// Significant digits numbers are printed and converted to strings with.
+    number_precision: usize,
+    /// Where `print` and the output natives write. It is flushed by
+    /// `flush()`, when a script finishes and before an error is reported.
+    output: BufWriter<io::Stdout>,
+}
+
+/// An assignment to a global passed to `VM::watch_global`.
+#[derive(Debug)]
+pub struct GlobalChange {
+    pub name: Rc<str>,
+    /// `None` when the assignment defined the global.
+    pub old: Option<Value>,
+    pub new: Value,
+    /// The line of the assignment.
+    pub line: usize,
+}
+
+/// What happened when `VM::step` ran an instruction.
+#[derive(Debug)]
+pub enum StepResult {
+    /// The script can go on, at offset `ip` in the innermost of `depth`
+    /// call frames.
+    Continue { depth: usize, ip: usize },
+    /// The script returned this value.
+    Finished(Value),
+    /// The script stopped with a runtime error, which has been reported.
+    Error,
+    /// There was nothing to run: no script has been started with `begin`
+    /// since the last one finished or failed.
+    NotRunning,
 }
 
 #[derive(Debug)]
 pub enum InterpretResult {
     Ok,
-    CompileError,
+    /// The source didn't compile, for the reasons given, and nothing ran.
+    CompileError(Vec<CompileDiagnostic>),
     RuntimeError,
 }
 
 impl VM {
     pub fn new() -> Self {
+        Self::with_limits(FRAMES_MAX, STACK_MAX)
+    }
+
+    /// Creates a VM that allows at most `frames_max` nested calls and
+    /// `stack_max` values on its stack, so a host can bound how deep and how
+    /// much memory an untrusted script uses. Going past either limit is a
+    /// "Stack overflow." runtime error.
+    pub fn with_limits(frames_max: usize, stack_max: usize) -> Self {
         let mut interner = StringInterner::new();
         let init_string = interner.intern("init");
         let mut vm = Self {
-            frames: Vec::with_capacity(FRAMES_MAX),
-            stack: Vec::with_capacity(STACK_MAX),
-            globals: HashMap::new(),
-            open_upvalues: HashMap::new(),
+            frames: Vec::with_capacity(frames_max.min(FRAMES_MAX)),
+            stack: Vec::with_capacity(stack_max.min(STACK_MAX)),
+            globals: Globals::default(),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            pending_exception: None,
+            global_change: None,
+            breakpoints: HashSet::new(),
+            halted_at: None,
             init_string,
             interner,
+            compile_options: CompileOptions::default(),
+            frames_max,
+            stack_max,
+            fuel: None,
+            script_args: Vec::new(),
+            start_time: Instant::now(),
+            number_precision: value::DEFAULT_PRECISION,
+            output: BufWriter::new(io::stdout()),
         };
-        vm.define_native("clock", native::clock);
+        vm.define_natives();
         vm
     }
 
-    fn define_native(&mut self, name: &str, function: fn(usize, &[Value]) -> Value) {
+    fn define_natives(&mut self) {
+        self.define_native("clock", 0, native::clock);
+        self.define_native("monotonic", 0, native::monotonic);
+        self.define_native("now_millis", 0, native::now_millis);
+        self.define_native("idiv", 2, native::idiv);
+        self.define_native("mod", 2, native::modulo);
+        self.define_native("hash", 1, native::hash);
+        self.define_native("print_", 1, native::print_value);
+        self.define_native("write", 1, native::write);
+        self.define_native("eprint", 1, native::eprint);
+        self.define_native("version", 0, native::version);
+        self.define_native("getenv", 1, native::getenv);
+        self.define_native("argv", 0, native::argv);
+        self.define_native("read_file", 1, native::read_file);
+        self.define_native("write_file", 2, native::write_file);
+        self.define_native("globals", 0, native::globals);
+        self.define_native("defined", 1, native::defined);
+        self.define_native_with_optional("sort", 1, 2, NativeFunction::Stepped(native::sort));
+        self.define_stepped_native("map", 2, native::map);
+        self.define_stepped_native("filter", 2, native::filter);
+        self.define_stepped_native("reduce", 3, native::reduce);
+        self.define_native("set_precision", 1, native::set_precision);
+        self.define_stepped_native("str", 1, native::str);
+        self.define_native("repr", 1, native::repr);
+        self.define_native("flush", 0, native::flush);
+        self.define_native("doc", 1, native::doc);
+    }
+
+    fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.define_native_with_optional(name, arity, arity, NativeFunction::Plain(function));
+    }
+
+    fn define_stepped_native(&mut self, name: &str, arity: usize, function: SteppedNativeFn) {
+        self.define_native_with_optional(name, arity, arity, NativeFunction::Stepped(function));
+    }
+
+    fn define_native_with_optional(
+        &mut self,
+        name: &str,
+        arity: usize,
+        max_arity: usize,
+        function: NativeFunction,
+    ) {
         let name_obj = self.interner.intern(name);
-        let native = Rc::new(Obj::Native(Rc::new(Native { function })));
-        self.globals.insert(name_obj, Value::Obj(native));
+        let native = Rc::new(Obj::Native(Rc::new(Native {
+            arity,
+            max_arity,
+            function,
+        })));
+        let slot = self.globals.resolve(name_obj);
+        self.globals.define(slot, Value::Obj(native));
+    }
+
+    /// Discards every global along with whatever a previous run left on the
+    /// stack, so one VM can run unrelated scripts in turn. The natives are
+    /// defined again and the number precision goes back to the default;
+    /// functions compiled before the reset must not be run after it.
+    /// Settings such as the limits, the instruction budget left and the
+    /// compile options are kept. This differs from `reset_stack`,
+    /// which recovers from a runtime error but keeps the globals so the
+    /// next script, like the next REPL line, can still use them.
+    pub fn reset(&mut self) {
+        self.reset_stack();
+        self.globals = Globals::default();
+        self.number_precision = value::DEFAULT_PRECISION;
+        self.define_natives();
+    }
+
+    /// Lets the VM run at most `max_instructions` instructions over its
+    /// lifetime, after which execution stops with "Instruction budget
+    /// exhausted.". This bounds how long an untrusted script can run, even
+    /// one stuck in an infinite loop.
+    pub fn with_fuel(mut self, max_instructions: u64) -> Self {
+        self.fuel = Some(max_instructions);
+        self
+    }
+
+    pub fn set_script_args(&mut self, args: Vec<String>) {
+        self.script_args = args;
+    }
+
+    pub fn script_args(&self) -> &[String] {
+        &self.script_args
+    }
+
+    /// The buffered stdout that `print` writes to.
+    pub fn output(&mut self) -> &mut BufWriter<io::Stdout> {
+        &mut self.output
+    }
+
+    /// Writes out what `print` has buffered so far. A host printing to
+    /// stdout itself between steps should call this first.
+    pub fn flush_output(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    /// Significant digits numbers are printed with, as `set_precision`
+    /// last set them.
+    pub fn number_precision(&self) -> usize {
+        self.number_precision
+    }
+
+    /// Changes how many significant digits numbers are printed and
+    /// converted to strings with. `precision` must be at least one.
+    pub fn set_number_precision(&mut self, precision: usize) {
+        self.number_precision = precision.max(1);
+    }
+
+    pub fn start_time(&self) -> Instant {
+        self.start_time
+    }
+
+    /// Interns `s` as a string value, for natives that return text.
+    pub fn new_string(&mut self, s: &str) -> Value {
+        Value::Obj(Rc::new(Obj::String(self.interner.intern(s))))
+    }
+
+    pub fn set_compile_options(&mut self, options: CompileOptions) {
+        self.compile_options = options;
+    }
+
+    pub fn compile(&mut self, source: &str) -> Result<Rc<Function>, Vec<CompileDiagnostic>> {
+        Compiler::compile(
+            source,
+            &mut self.interner,
+            &mut self.globals,
+            self.compile_options,
+        )
+    }
+
+    pub fn stack_size(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Globals holding a value, natives included.
+    pub fn defined_global_count(&self) -> usize {
+        self.globals.defined_count()
+    }
+
+    pub fn global_names(&self) -> &[Rc<str>] {
+        self.globals.names()
+    }
+
+    /// Names of the globals holding a value, natives included, sorted.
+    pub fn defined_global_names(&self) -> Vec<Rc<str>> {
+        let mut names: Vec<_> = self.globals.defined_names().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn is_global_defined(&self, name: &str) -> bool {
+        self.globals.is_name_defined(name)
+    }
+
+    /// Loads a script written by `bytecode::serialize`, rebuilding the
+    /// global slot table its instructions were compiled against.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<Rc<Function>, String> {
+        let program = bytecode::deserialize(bytes, &mut self.interner)?;
+
+        let mismatch = program
+            .globals
+            .into_iter()
+            .enumerate()
+            .any(|(slot, name)| self.globals.resolve(name) != slot);
+        if mismatch {
+            return Err("Compiled global table does not match this VM.".to_string());
+        }
+
+        Ok(Rc::new(program.function))
     }
 
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let function = match Compiler::compile(source) {
-            Ok(func) => func,
-            Err(_) => return InterpretResult::CompileError,
-        };
+        match self.eval(source) {
+            Ok(_) => InterpretResult::Ok,
+            Err(result) => result,
+        }
+    }
+
+    /// Compiles and runs `source` like `interpret`, returning the value the
+    /// script gave to a top-level `return`, or `nil` if it ran to the end.
+    /// Fails with `InterpretResult::CompileError` or `RuntimeError`.
+    pub fn eval(&mut self, source: &str) -> Result<Value, InterpretResult> {
+        let function = self.compile(source).map_err(InterpretResult::CompileError)?;
+        self.run_function(function)
+    }
+
+    /// Runs an already compiled script like `eval`.
+    pub fn eval_function(&mut self, function: Rc<Function>) -> Result<Value, InterpretResult> {
+        self.run_function(function)
+    }
+
+    pub fn interpret_function(&mut self, function: Rc<Function>) -> InterpretResult {
+        match self.run_function(function) {
+            Ok(_) => InterpretResult::Ok,
+            Err(result) => result,
+        }
+    }
+
+    fn run_function(&mut self, function: Rc<Function>) -> Result<Value, InterpretResult> {
+        if !self.begin(function) {
+            return Err(InterpretResult::RuntimeError);
+        }
+        self.run().map_err(|_| InterpretResult::RuntimeError)
+    }
 
+    /// Sets `function` up to run as a script without running any of it, for
+    /// a host that then drives it one instruction at a time with `step`.
+    /// Returns false if the script couldn't be called, such as when it
+    /// takes parameters, after reporting why; there is then nothing to step.
+    pub fn begin(&mut self, function: Rc<Function>) -> bool {
+        self.halted_at = None;
+        self.global_change = None;
         let upvalue_count = function.upvalue_count;
-        let closure = Closure {
-            function,
-            upvalues: Vec::with_capacity(upvalue_count),
-        };
+        let closure = Closure::new(function, Vec::with_capacity(upvalue_count));
 
         let closure_rc = Rc::new(Obj::Closure(Rc::new(closure)));
         self.push(Value::Obj(Rc::clone(&closure_rc)));
-        self.call_value(Value::Obj(closure_rc), 0);
+        self.call_value(Value::Obj(closure_rc), 0)
+    }
 
-        match self.run() {
-            Ok(_) => InterpretResult::Ok,
-            Err(_) => InterpretResult::RuntimeError,
+    /// Runs instructions until the script returns, giving its result.
+    fn run(&mut self) -> Result<Value, ()> {
+        loop {
+            match self.step() {
+                StepResult::Continue { .. } => {}
+                StepResult::Finished(result) => return Ok(result),
+                StepResult::Error | StepResult::NotRunning => return Err(()),
+            }
         }
     }
 
-    fn run(&mut self) -> Result<(), ()> {
-        loop {
-            let (_ip, instruction) = {
-                let frame = self.frames.last().unwrap();
-                let ip = frame.ip;
-                let chunk = &frame.closure.function.chunk;
+    /// Runs exactly one instruction of the script started by `begin`. A
+    /// runtime error inside a `try` block resumes at its `catch`, so the
+    /// script can go on; an error nothing catches has been reported by the
+    /// time this returns `StepResult::Error`, and the VM is ready for the
+    /// next script. The frames and stack persist between steps. Without a
+    /// running script this does nothing and returns `StepResult::NotRunning`.
+    #[inline(always)]
+    pub fn step(&mut self) -> StepResult {
+        if self.frames.is_empty() {
+            return StepResult::NotRunning;
+        }
 
-                #[cfg(feature = "debug_trace")]
-                {
-                    print!("          ");
-                    (&self.stack).iter().for_each(|slot| {
-                        print!("[ {} ]", slot);
-                    });
-                    println!();
-                    crate::debug::disassemble_instruction(chunk, ip);
+        match self.execute_instruction() {
+            Ok(None) => {}
+            Ok(Some(result)) => {
+                self.output.flush().ok();
+                return StepResult::Finished(result);
+            }
+            Err(()) => {
+                let Some(exception) = self.pending_exception.take() else {
+                    return StepResult::Error;
+                };
+                if !self.throw_value(exception) {
+                    return StepResult::Error;
                 }
+            }
+        }
+        match self.frames.last() {
+            Some(frame) => StepResult::Continue {
+                depth: self.frames.len(),
+                ip: frame.ip,
+            },
+            None => StepResult::NotRunning,
+        }
+    }
 
-                let instruction = chunk.code[ip];
-                (ip, instruction)
+    /// Makes `continue_run` halt when execution reaches `line`.
+    pub fn set_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    /// Makes every assignment to the global `name`, including the one that
+    /// defines it, available from `take_global_change`.
+    pub fn watch_global(&mut self, name: &str) {
+        let name = self.interner.intern(name);
+        let slot = self.globals.resolve(name);
+        self.globals.watched.insert(slot);
+    }
+
+    /// The latest assignment to a watched global since the last call.
+    pub fn take_global_change(&mut self) -> Option<GlobalChange> {
+        self.global_change.take()
+    }
+
+    /// Runs the script started by `begin` until it finishes, fails, or
+    /// halts, returning `StepResult::Continue` when it halts. It halts
+    /// right after an assignment to a watched global, which
+    /// `take_global_change` then returns, and when about to start a
+    /// breakpoint's line. A line starts at the first instruction compiled
+    /// for it, so a loop over it halts once per pass and returning from a
+    /// call made on it doesn't halt again. Calling this again resumes from
+    /// the halt, and like `step` it returns `StepResult::NotRunning` when
+    /// there is no script to run.
+    pub fn continue_run(&mut self) -> StepResult {
+        let mut resuming_from = self.halted_at.take();
+        loop {
+            let Some(frame) = self.frames.last() else {
+                return StepResult::NotRunning;
             };
-            self.frames.last_mut().unwrap().ip += 1;
+            let here = (self.frames.len(), frame.ip);
+            let lines = &frame.closure.function.chunk.lines;
+            let line = lines.get(frame.ip);
+            let line_start = frame.ip == 0 || lines.get(frame.ip - 1) != line;
+            if line_start
+                && resuming_from != Some(here)
+                && line.is_some_and(|line| self.breakpoints.contains(line))
+            {
+                self.halted_at = Some(here);
+                return StepResult::Continue {
+                    depth: here.0,
+                    ip: here.1,
+                };
+            }
+            resuming_from = None;
 
-            match instruction.try_into().ok() {
-                Some(OpCode::Constant) => {
-                    let constant = self.read_constant();
-                    self.push(constant);
-                }
-                Some(OpCode::Nil) => self.push(Value::Nil),
-                Some(OpCode::True) => self.push(Value::Bool(true)),
-                Some(OpCode::False) => self.push(Value::Bool(false)),
-                Some(OpCode::Pop) => {
-                    self.pop();
-                }
-                Some(OpCode::GetLocal) => {
-                    let slot = self.read_byte() as usize;
-                    let frame = self.frames.last().unwrap();
-                    let value = self.stack[frame.slot_offset + slot].clone();
-                    self.push(value);
-                }
-                Some(OpCode::SetLocal) => {
-                    let slot = self.read_byte() as usize;
-                    let frame = self.frames.last().unwrap();
-                    let offset = frame.slot_offset + slot;
-                    let value = self.peek(0).clone();
-                    self.stack[offset] = value;
-                }
-                Some(OpCode::GetGlobal) => {
-                    let name = self.read_string();
-                    match self.globals.get(name.as_ref()) {
-                        Some(value) => self.push(value.clone()),
-                        None => {
-                            self.runtime_error(&format!("Undefined variable '{}'.", name));
-                            return Err(());
-                        }
-                    }
+            match self.step() {
+                StepResult::Continue { depth, ip } if self.global_change.is_some() => {
+                    return StepResult::Continue { depth, ip };
                 }
-                Some(OpCode::DefineGlobal) => {
-                    let name = self.read_string();
-                    let value = self.pop();
-                    self.globals.insert(name, value);
-                }
-                Some(OpCode::SetGlobal) => {
-                    let name = self.read_string();
-                    if !self.globals.contains_key(name.as_ref()) {
+                StepResult::Continue { .. } => {}
+                done => return done,
+            }
+        }
+    }
+
+    /// The source line of the instruction `step` runs next, or `None` when
+    /// no script is running.
+    pub fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        frame.closure.function.chunk.lines.get(frame.ip).copied()
+    }
+
+    /// The function whose code `step` runs next, or `None` when no script
+    /// is running.
+    pub fn current_function(&self) -> Option<&Function> {
+        Some(&self.frames.last()?.closure.function)
+    }
+
+    /// A copy of the value stack, bottom first, for a host to display.
+    pub fn stack_snapshot(&self) -> Vec<Value> {
+        self.stack.clone()
+    }
+
+    /// Runs the next instruction, returning the script's result if it was
+    /// the `Return` that ends the innermost `run`.
+    #[inline(always)]
+    fn execute_instruction(&mut self) -> Result<Option<Value>, ()> {
+        // No instruction pushes more than a handful of values, so
+        // checking between instructions bounds the stack as well as a
+        // check on every push would.
+        if self.stack.len() > self.stack_max {
+            self.runtime_error("Stack overflow.");
+            return Err(());
+        }
+
+        // Loaded files have been through `bytecode::check_function`, so
+        // this trusts their code just as it does the compiler's: operands
+        // are in range and every instruction has the values it pops.
+        let (start, instruction) = {
+            let frame = self.frames.last().unwrap();
+            let ip = frame.ip;
+            let chunk = &frame.closure.function.chunk;
+
+            #[cfg(feature = "debug_trace")]
+            {
+                self.output.flush().ok();
+                crate::debug::print_stack(&self.stack);
+                crate::debug::disassemble_instruction(chunk, ip);
+            }
+
+            let instruction = chunk.code[ip];
+            (ip, instruction)
+        };
+        self.frames.last_mut().unwrap().ip += 1;
+
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                // Not an exception: a script mustn't catch it and go on.
+                self.handlers.clear();
+                self.runtime_error("Instruction budget exhausted.");
+                return Err(());
+            }
+            *fuel -= 1;
+        }
+
+        match instruction.try_into().ok() {
+            Some(OpCode::Constant) => {
+                let constant = self.read_constant()?;
+                self.push(constant);
+            }
+            Some(OpCode::Nil) => self.push(Value::Nil),
+            Some(OpCode::True) => self.push(Value::Bool(true)),
+            Some(OpCode::False) => self.push(Value::Bool(false)),
+            Some(OpCode::Pop) => {
+                self.pop();
+            }
+            Some(OpCode::PopN) => {
+                let count = self.read_byte() as usize;
+                self.stack.truncate(self.stack.len() - count);
+            }
+            Some(OpCode::GetLocal) => {
+                let slot = self.read_byte() as usize;
+                let frame = self.frames.last().unwrap();
+                let value = self.stack[frame.slot_offset + slot].clone();
+                self.push(value);
+            }
+            Some(OpCode::SetLocal) => {
+                let slot = self.read_byte() as usize;
+                let frame = self.frames.last().unwrap();
+                let offset = frame.slot_offset + slot;
+                let value = self.peek(0).clone();
+                self.stack[offset] = value;
+            }
+            Some(OpCode::SetLocalPop) => {
+                let slot = self.read_byte() as usize;
+                let offset = self.frames.last().unwrap().slot_offset + slot;
+                self.stack[offset] = self.pop();
+            }
+            Some(OpCode::GetGlobalSlot) => {
+                let slot = self.read_short() as usize;
+                match self.globals.get(slot) {
+                    Some(value) => self.push(value.clone()),
+                    None => {
+                        let name = Rc::clone(self.globals.name(slot));
                         self.runtime_error(&format!("Undefined variable '{}'.", name));
                         return Err(());
                     }
-                    let value = self.peek(0).clone();
-                    self.globals.insert(name, value);
-                }
-                Some(OpCode::GetUpvalue) => {
-                    let slot = self.read_byte() as usize;
-                    let frame = self.frames.last().unwrap();
-                    let value = frame.closure.upvalues[slot].borrow().get_value(&self.stack);
-                    self.push(value);
-                }
-                Some(OpCode::SetUpvalue) => {
-                    let slot = self.read_byte() as usize;
-                    let value = self.peek(0).clone();
-                    let frame = self.frames.last().unwrap();
-                    frame.closure.upvalues[slot]
-                        .borrow_mut()
-                        .set_value(value, &mut self.stack);
-                }
-                Some(OpCode::GetProperty) => {
-                    if !self.peek(0).is_instance() {
-                        self.runtime_error("Only instances have properties.");
+                }
+            }
+            Some(op @ (OpCode::DefineGlobalSlot | OpCode::DefineConstGlobalSlot)) => {
+                let slot = self.read_short() as usize;
+                let value = self.pop();
+                self.assign_global(slot, value);
+                self.globals
+                    .set_const(slot, op == OpCode::DefineConstGlobalSlot);
+            }
+            Some(OpCode::SetGlobalSlot) => {
+                let slot = self.read_short() as usize;
+                self.check_global_assignable(slot)?;
+                let value = self.peek(0).clone();
+                self.assign_global(slot, value);
+            }
+            Some(OpCode::SetGlobalSlotPop) => {
+                let slot = self.read_short() as usize;
+                self.check_global_assignable(slot)?;
+                let value = self.pop();
+                self.assign_global(slot, value);
+            }
+            Some(OpCode::GetLocalLong) => {
+                let slot = self.read_short() as usize;
+                let frame = self.frames.last().unwrap();
+                let value = self.stack[frame.slot_offset + slot].clone();
+                self.push(value);
+            }
+            Some(OpCode::SetLocalLong) => {
+                let slot = self.read_short() as usize;
+                let frame = self.frames.last().unwrap();
+                let offset = frame.slot_offset + slot;
+                let value = self.peek(0).clone();
+                self.stack[offset] = value;
+            }
+            Some(OpCode::GetUpvalue) => {
+                let slot = self.read_byte() as usize;
+                let frame = self.frames.last().unwrap();
+                let value = frame.closure.upvalues[slot].borrow().get_value(&self.stack);
+                match value {
+                    Some(value) => self.push(value),
+                    None => {
+                        self.runtime_error("Captured variable is no longer on the stack.");
                         return Err(());
                     }
-
-                    let instance = match self.peek(0) {
-                        Value::Obj(obj) => match &**obj {
-                            Obj::Instance(inst) => Rc::clone(inst),
-                            _ => unreachable!(),
-                        },
-                        _ => unreachable!(),
+                }
+            }
+            Some(OpCode::SetUpvalue) => {
+                let slot = self.read_byte() as usize;
+                let value = self.peek(0).clone();
+                let frame = self.frames.last().unwrap();
+                let upvalue = &frame.closure.upvalues[slot];
+                if !upvalue.borrow_mut().set_value(value, &mut self.stack) {
+                    self.runtime_error("Captured variable is no longer on the stack.");
+                    return Err(());
+                }
+            }
+            Some(OpCode::GetProperty) => {
+                if let Value::Obj(obj) = self.peek(0)
+                    && let Obj::Class(class) = &**obj
+                {
+                    let class = Rc::clone(class);
+                    let name = self.read_string()?;
+                    self.read_short();
+                    let Some(method) = self.static_method(&class, &name) else {
+                        return Err(());
                     };
+                    self.pop();
+                    self.push(method);
+                    return Ok(None);
+                }
 
-                    let name = self.read_string();
-                    let field_value = instance.fields.borrow().get(name.as_ref()).cloned();
-                    if let Some(value) = field_value {
-                        self.pop();
-                        self.push(value);
-                    } else {
-                        let class = match instance.class.upgrade() {
-                            Some(c) => c,
-                            None => {
-                                self.runtime_error("Instance's class has been deallocated.");
-                                return Err(());
-                            }
-                        };
-                        if !self.bind_method(&class, name.as_ref()) {
+                if builtin::has_builtin_methods(self.peek(0)) {
+                    let name = self.read_string()?;
+                    let message = builtin::builtin_property_error(self.peek(0), &name);
+                    self.runtime_error(&message);
+                    return Err(());
+                }
+
+                let name = self.read_string()?;
+                let cache_index = self.read_short() as usize;
+                let Some(instance) = Self::as_instance(self.peek(0)) else {
+                    self.runtime_error("Only instances have properties.");
+                    return Err(());
+                };
+                let field_value = instance.fields.borrow().get(name.as_ref()).cloned();
+                if let Some(value) = field_value {
+                    *self.peek_mut() = value;
+                } else {
+                    let class = Rc::clone(&instance.class);
+                    if let Some(getter) = class.find_getter(&name) {
+                        if !self.call(getter, 0) {
                             return Err(());
                         }
-                    }
-                }
-                Some(OpCode::SetProperty) => {
-                    if !self.peek(1).is_instance() {
-                        self.runtime_error("Only instances have fields.");
+                    } else if !self.bind_cached_method(&class, name.as_ref(), cache_index) {
                         return Err(());
                     }
-
-                    let name = self.read_string();
-                    let value = self.pop();
-
-                    let instance_rc = match self.peek(0) {
-                        Value::Obj(obj) => match &**obj {
-                            Obj::Instance(inst) => inst,
-                            _ => unreachable!(),
-                        },
-                        _ => unreachable!(),
-                    };
-
-                    instance_rc.fields.borrow_mut().insert(name, value.clone());
-                    self.pop();
-                    self.push(value);
-                }
-                Some(OpCode::GetSuper) => {
-                    let name = self.read_string();
-                    let superclass = match self.pop() {
-                        Value::Obj(obj) => match &*obj {
-                            Obj::Class(class) => Rc::clone(class),
-                            _ => {
-                                self.runtime_error("Superclass must be a class.");
-                                return Err(());
-                            }
-                        },
+                }
+            }
+            Some(OpCode::SetProperty) => {
+                let name = self.read_string()?;
+                let Some(instance) = Self::as_instance(self.peek(1)) else {
+                    self.runtime_error("Only instances have fields.");
+                    return Err(());
+                };
+
+                instance
+                    .fields
+                    .borrow_mut()
+                    .insert(name, self.peek(0).clone());
+                let value = self.pop();
+                *self.peek_mut() = value;
+            }
+            Some(OpCode::GetSuper) => {
+                let name = self.read_string()?;
+                let superclass = match self.pop() {
+                    Value::Obj(obj) => match &*obj {
+                        Obj::Class(class) => Rc::clone(class),
                         _ => {
                             self.runtime_error("Superclass must be a class.");
                             return Err(());
                         }
-                    };
+                    },
+                    _ => {
+                        self.runtime_error("Superclass must be a class.");
+                        return Err(());
+                    }
+                };
 
-                    if !self.bind_method(&superclass, &name) {
+                if let Some(getter) = superclass.find_getter(&name) {
+                    if !self.call(getter, 0) {
                         return Err(());
                     }
+                } else if !self.bind_method(&superclass, &name, true) {
+                    return Err(());
                 }
-                Some(OpCode::Equal) => {
+            }
+            Some(OpCode::Equal) => {
+                if let Some(equals) = Self::equals_method(self.peek(1), self.peek(0)) {
+                    self.call_then(equals, 1, ReturnAction::Truthy)?;
+                } else {
                     let b = self.pop();
                     let a = self.pop();
                     self.push(Value::Bool(a == b));
                 }
-                Some(OpCode::Greater) => {
-                    self.binary_op(|a, b| Value::Bool(a > b))?;
-                }
-                Some(OpCode::Less) => {
-                    self.binary_op(|a, b| Value::Bool(a < b))?;
+            }
+            Some(OpCode::NotEqual) => {
+                if let Some(equals) = Self::equals_method(self.peek(1), self.peek(0)) {
+                    self.call_then(equals, 1, ReturnAction::Falsey)?;
+                } else {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::Bool(a != b));
                 }
-                Some(OpCode::Add) => {
-                    let b = self.peek(0);
-                    let a = self.peek(1);
+            }
+            Some(OpCode::Greater) => {
+                self.comparison(Ordering::is_gt)?;
+            }
+            Some(OpCode::GreaterEqual) => {
+                self.comparison(Ordering::is_ge)?;
+            }
+            Some(OpCode::Less) => {
+                self.comparison(Ordering::is_lt)?;
+            }
+            Some(OpCode::LessEqual) => {
+                self.comparison(Ordering::is_le)?;
+            }
+            Some(OpCode::Add) => {
+                let b = self.peek(0);
+                let a = self.peek(1);
 
-                    match (a, b) {
-                        (Value::Number(_), Value::Number(_)) => {
-                            self.binary_op(|a, b| Value::Number(a + b))?;
+                match value::add(a, b) {
+                    Some(sum) => {
+                        self.pop();
+                        self.pop();
+                        self.push(sum);
+                    }
+                    None => match Self::concatenate(a, b, self.number_precision) {
+                        Some(result) => {
+                            self.pop();
+                            self.pop();
+                            let interned = self.interner.intern(&result);
+                            self.push(Value::Obj(Rc::new(Obj::String(interned))));
                         }
-                        (Value::Obj(a_obj), Value::Obj(b_obj)) => match (&**a_obj, &**b_obj) {
-                            (Obj::String(a_str), Obj::String(b_str)) => {
-                                let mut result = String::with_capacity(a_str.len() + b_str.len());
-                                result.push_str(a_str);
-                                result.push_str(b_str);
-                                self.pop();
-                                self.pop();
-                                let interned = self.interner.intern(&result);
-                                self.push(Value::Obj(Rc::new(Obj::String(interned))));
-                            }
-                            _ => {
-                                self.runtime_error("Operands must be two numbers or two strings.");
-                                return Err(());
+                        None => {
+                            if let Some((to_string, depth)) = Self::to_string_operand(a, b) {
+                                self.stringify_and_retry(to_string, depth, start)?;
+                                return Ok(None);
                             }
-                        },
-                        _ => {
                             self.runtime_error("Operands must be two numbers or two strings.");
                             return Err(());
                         }
-                    }
-                }
-                Some(OpCode::Subtract) => {
-                    self.binary_op(|a, b| Value::Number(a - b))?;
+                    },
                 }
-                Some(OpCode::Multiply) => {
-                    self.binary_op(|a, b| Value::Number(a * b))?;
+            }
+            Some(OpCode::Subtract) => {
+                self.binary_op(value::subtract)?;
+            }
+            Some(OpCode::Multiply) => {
+                self.binary_op(value::multiply)?;
+            }
+            Some(OpCode::Divide) => {
+                self.binary_op(value::divide)?;
+            }
+            // Unary operators replace the top of the stack in place.
+            Some(OpCode::Not) => {
+                let top = self.peek_mut();
+                *top = Value::Bool(top.is_falsey());
+            }
+            Some(OpCode::Negate) => match self.peek(0).negate() {
+                Some(negated) => *self.peek_mut() = negated,
+                None => {
+                    self.runtime_error("Operand must be a number.");
+                    return Err(());
                 }
-                Some(OpCode::Divide) => {
-                    self.binary_op(|a, b| Value::Number(a / b))?;
+            },
+            Some(OpCode::Print) => {
+                if let Some(to_string) = Self::instance_method(self.peek(0), "toString") {
+                    self.stringify_and_retry(to_string, 0, start)?;
+                    return Ok(None);
                 }
-                Some(OpCode::Not) => {
-                    let value = self.pop();
-                    self.push(Value::Bool(value.is_falsey()));
+                let value = self.pop();
+                writeln!(self.output, "{}", value.display(self.number_precision)).ok();
+            }
+            Some(OpCode::BuildString) => {
+                let count = self.read_byte() as usize;
+                // Instances with a `toString` method are converted one
+                // at a time, running this instruction again after each.
+                let to_string = (0..count).find_map(|depth| {
+                    Some((Self::instance_method(self.peek(depth), "toString")?, depth))
+                });
+                if let Some((to_string, depth)) = to_string {
+                    self.stringify_and_retry(to_string, depth, start)?;
+                    return Ok(None);
                 }
-                Some(OpCode::Negate) => {
-                    let value = self.peek(0);
-                    match value {
-                        Value::Number(_) => {
-                            if let Value::Number(num) = self.pop() {
-                                self.push(Value::Number(-num));
-                            }
-                        }
-                        _ => {
-                            self.runtime_error("Operand must be a number.");
-                            return Err(());
-                        }
+
+                let mut result = String::new();
+                let parts = self.stack.split_off(self.stack.len() - count);
+                for part in &parts {
+                    match part.as_str() {
+                        Some(s) => result.push_str(s),
+                        None => result.push_str(&part.display(self.number_precision).to_string()),
                     }
                 }
-                Some(OpCode::Print) => {
-                    use std::io::Write;
-                    println!("{}", self.pop());
-                    std::io::stdout().flush().ok();
+                let interned = self.interner.intern(&result);
+                self.push(Value::Obj(Rc::new(Obj::String(interned))));
+            }
+            Some(OpCode::Jump) => {
+                let offset = self.read_short();
+                self.frames.last_mut().unwrap().ip += offset as usize;
+            }
+            Some(OpCode::JumpIfFalse) => {
+                let offset = self.read_short();
+                if self.peek(0).is_falsey() {
+                    self.frames.last_mut().unwrap().ip += offset as usize;
+                }
+            }
+            Some(OpCode::JumpIfTrue) => {
+                let offset = self.read_short();
+                if !self.peek(0).is_falsey() {
+                    self.frames.last_mut().unwrap().ip += offset as usize;
                 }
-                Some(OpCode::Jump) => {
-                    let offset = self.read_short();
+            }
+            Some(OpCode::JumpIfNil) => {
+                let offset = self.read_short();
+                if matches!(self.peek(0), Value::Nil) {
                     self.frames.last_mut().unwrap().ip += offset as usize;
                 }
-                Some(OpCode::JumpIfFalse) => {
-                    let offset = self.read_short();
-                    if self.peek(0).is_falsey() {
-                        self.frames.last_mut().unwrap().ip += offset as usize;
-                    }
+            }
+            Some(OpCode::JumpIfNotNil) => {
+                let offset = self.read_short();
+                if !matches!(self.peek(0), Value::Nil) {
+                    self.frames.last_mut().unwrap().ip += offset as usize;
                 }
-                Some(OpCode::Loop) => {
-                    let offset = self.read_short();
-                    self.frames.last_mut().unwrap().ip -= offset as usize;
+            }
+            Some(OpCode::Loop) => {
+                let offset = self.read_short();
+                self.frames.last_mut().unwrap().ip -= offset as usize;
+            }
+            Some(OpCode::Call) => {
+                let arg_count = self.read_byte() as usize;
+                let idx = self.stack.len() - 1 - arg_count;
+                // Most calls are of closures, which only need their
+                // `Rc<Closure>` for the new frame.
+                let called = match self.stack[idx].as_closure() {
+                    Some(closure) => self.call(closure, arg_count),
+                    None => self.call_value(self.stack[idx].clone(), arg_count),
+                };
+                if !called {
+                    return Err(());
                 }
-                Some(OpCode::Call) => {
-                    let arg_count = self.read_byte() as usize;
-                    let idx = self.stack.len() - 1 - arg_count;
-                    let callee = self.stack[idx].clone();
-                    if !self.call_value(callee, arg_count) {
-                        return Err(());
-                    }
+            }
+            Some(OpCode::TailCall) => {
+                let arg_count = self.read_byte() as usize;
+                let callee_slot = self.stack.len() - 1 - arg_count;
+                if let Some(closure) = self.tail_call_target(callee_slot, arg_count) {
+                    self.tail_call(closure, callee_slot);
+                } else if !self.call_value(self.stack[callee_slot].clone(), arg_count) {
+                    return Err(());
                 }
-                Some(OpCode::Invoke) => {
-                    let method = self.read_string();
-                    let arg_count = self.read_byte() as usize;
-                    if !self.invoke(&method, arg_count) {
-                        return Err(());
-                    }
+            }
+            Some(OpCode::Invoke) => {
+                let method = self.read_string()?;
+                let arg_count = self.read_byte() as usize;
+                if !self.invoke(&method, arg_count) {
+                    return Err(());
                 }
-                Some(OpCode::SuperInvoke) => {
-                    let method = self.read_string();
-                    let arg_count = self.read_byte() as usize;
-                    let superclass = match self.pop() {
-                        Value::Obj(obj) => match &*obj {
-                            Obj::Class(class) => Rc::clone(class),
-                            _ => {
-                                self.runtime_error("Superclass must be a class.");
-                                return Err(());
-                            }
-                        },
+            }
+            Some(OpCode::SuperInvoke) => {
+                let method = self.read_string()?;
+                let arg_count = self.read_byte() as usize;
+                let superclass = match self.pop() {
+                    Value::Obj(obj) => match &*obj {
+                        Obj::Class(class) => Rc::clone(class),
                         _ => {
                             self.runtime_error("Superclass must be a class.");
                             return Err(());
                         }
-                    };
-
-                    if !self.invoke_from_class(&superclass, &method, arg_count) {
+                    },
+                    _ => {
+                        self.runtime_error("Superclass must be a class.");
                         return Err(());
                     }
+                };
+
+                if !self.invoke_from_class(&superclass, &method, arg_count, true) {
+                    return Err(());
                 }
-                Some(OpCode::Closure) => {
-                    let function = match self.read_constant() {
-                        Value::Obj(obj) => match &*obj {
-                            Obj::Function(func) => Rc::clone(func),
-                            _ => {
-                                self.runtime_error("Expected function.");
-                                return Err(());
-                            }
-                        },
+            }
+            Some(OpCode::Closure) => {
+                let function = match self.read_constant()? {
+                    Value::Obj(obj) => match &*obj {
+                        Obj::Function(func) => Rc::clone(func),
                         _ => {
                             self.runtime_error("Expected function.");
                             return Err(());
                         }
-                    };
-
-                    let upvalue_count = function.upvalue_count;
-                    let mut upvalues = Vec::with_capacity(upvalue_count);
-                    (0..upvalue_count).for_each(|_| {
-                        let is_local = self.read_byte() != 0;
-                        let index = self.read_byte() as usize;
-
-                        if is_local {
-                            let frame = self.frames.last().unwrap();
-                            let stack_index = frame.slot_offset + index;
-                            upvalues.push(self.capture_upvalue(stack_index));
-                        } else {
-                            let frame = self.frames.last().unwrap();
-                            upvalues.push(Rc::clone(&frame.closure.upvalues[index]));
-                        }
-                    });
+                    },
+                    _ => {
+                        self.runtime_error("Expected function.");
+                        return Err(());
+                    }
+                };
+
+                let upvalue_count = function.upvalue_count;
+                let mut upvalues = Vec::with_capacity(upvalue_count);
+                (0..upvalue_count).for_each(|_| {
+                    let is_local = self.read_byte() != 0;
+                    let index = self.read_short() as usize;
+
+                    if is_local {
+                        let frame = self.frames.last().unwrap();
+                        let stack_index = frame.slot_offset + index;
+                        upvalues.push(self.capture_upvalue(stack_index));
+                    } else {
+                        let frame = self.frames.last().unwrap();
+                        upvalues.push(Rc::clone(&frame.closure.upvalues[index]));
+                    }
+                });
 
-                    let closure = Closure { function, upvalues };
-                    self.push(Value::Obj(Rc::new(Obj::Closure(Rc::new(closure)))));
-                }
-                Some(OpCode::CloseUpvalue) => {
-                    self.close_upvalues(self.stack.len() - 1);
-                    self.pop();
+                let closure = Closure::new(function, upvalues);
+                self.push(Value::Obj(Rc::new(Obj::Closure(Rc::new(closure)))));
+            }
+            Some(OpCode::CloseUpvalue) => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.pop();
+            }
+            Some(OpCode::Return) => {
+                let slot_offset = self.frames.last().unwrap().slot_offset;
+                self.close_upvalues(slot_offset);
+
+                let result = self.pop();
+                let frame = self.frames.pop().unwrap();
+                while self
+                    .handlers
+                    .last()
+                    .is_some_and(|handler| handler.frame_count > self.frames.len())
+                {
+                    self.handlers.pop();
                 }
-                Some(OpCode::Return) => {
-                    let slot_offset = self.frames.last().unwrap().slot_offset;
-                    self.close_upvalues(slot_offset);
 
-                    let result = self.pop();
-                    let frame = self.frames.pop().unwrap();
+                self.stack.truncate(frame.slot_offset);
+                if self.frames.is_empty() {
+                    return Ok(Some(result));
+                }
 
-                    if self.frames.is_empty() {
-                        self.pop();
-                        return Ok(());
+                match frame.on_return {
+                    ReturnAction::Keep => self.push(result),
+                    ReturnAction::Truthy => self.push(Value::Bool(!result.is_falsey())),
+                    ReturnAction::Falsey => self.push(Value::Bool(result.is_falsey())),
+                    ReturnAction::Map(op) => {
+                        let Some(hash) = result.as_f64() else {
+                            self.runtime_error("hash() must return a number.");
+                            return Err(());
+                        };
+                        let key = self.peek(op.key_depth()).clone();
+                        self.finish_map_op(op, HashableValue::instance(key, hash))?;
+                    }
+                    ReturnAction::Discard => {}
+                    ReturnAction::Stringify(depth) => {
+                        if !result.is_string() {
+                            self.runtime_error("toString() must return a string.");
+                            return Err(());
+                        }
+                        let slot = self.stack.len() - 1 - depth;
+                        self.stack[slot] = result;
+                    }
+                    ReturnAction::Resume(waiting) => {
+                        self.push(result.clone());
+                        if !self.run_native(Ok(NativeStep::Done(result)), waiting.0) {
+                            return Err(());
+                        }
                     }
-
-                    self.stack.truncate(frame.slot_offset);
-                    self.push(result);
-                }
-                Some(OpCode::Class) => {
-                    let name = self.read_string();
-                    let class = Class {
-                        name,
-                        methods: RefCell::new(HashMap::new()),
-                    };
-                    self.push(Value::Obj(Rc::new(Obj::Class(Rc::new(class)))));
                 }
-                Some(OpCode::Inherit) => {
-                    let superclass = match self.peek(1) {
-                        Value::Obj(obj) => match &**obj {
-                            Obj::Class(class) => Rc::clone(class),
-                            _ => {
-                                self.runtime_error("Superclass must be a class.");
-                                return Err(());
-                            }
-                        },
+            }
+            Some(op @ (OpCode::Class | OpCode::DocumentedClass)) => {
+                let name = self.read_string()?;
+                let doc = match op {
+                    OpCode::DocumentedClass => Some(self.read_string()?),
+                    _ => None,
+                };
+                let class = Class {
+                    name,
+                    methods: RefCell::new(HashMap::new()),
+                    getters: RefCell::new(HashMap::new()),
+                    static_methods: RefCell::new(HashMap::new()),
+                    superclass: RefCell::new(None),
+                    doc,
+                };
+                self.push(Value::Obj(Rc::new(Obj::Class(Rc::new(class)))));
+            }
+            Some(OpCode::Inherit) => {
+                let superclass = match self.peek(1) {
+                    Value::Obj(obj) => match &**obj {
+                        Obj::Class(class) => Rc::clone(class),
                         _ => {
                             self.runtime_error("Superclass must be a class.");
                             return Err(());
                         }
-                    };
+                    },
+                    _ => {
+                        self.runtime_error("Superclass must be a class.");
+                        return Err(());
+                    }
+                };
 
-                    let subclass_rc = match self.peek(0) {
-                        Value::Obj(obj) => match &**obj {
-                            Obj::Class(class) => class,
-                            _ => unreachable!(),
-                        },
-                        _ => unreachable!(),
-                    };
+                let Some(subclass_rc) = Self::as_class(self.peek(0)) else {
+                    self.runtime_error("Expected class.");
+                    return Err(());
+                };
+                // The compiler rules this out, but a loaded file could
+                // otherwise make lookups in the subclass loop forever.
+                if superclass.inherits_from(&subclass_rc) {
+                    self.runtime_error("A class can't inherit from itself.");
+                    return Err(());
+                }
 
-                    superclass.methods.borrow().iter().for_each(|(key, value)| {
-                        subclass_rc
-                            .methods
-                            .borrow_mut()
-                            .insert(key.clone(), value.clone());
-                    });
+                // Nothing is copied: lookups that miss in the subclass
+                // continue in the superclass.
+                *subclass_rc.superclass.borrow_mut() = Some(superclass);
 
-                    self.pop();
+                self.pop();
+            }
+            Some(OpCode::ForIn) => {
+                // Replaces the sequence with its iterator. Lists and
+                // maps have a builtin `iter()`; an instance has to
+                // define its own.
+                let iterable = matches!(
+                    self.peek(0),
+                    Value::Obj(obj)
+                        if matches!(&**obj, Obj::List(_) | Obj::Map(_) | Obj::Instance(_))
+                );
+                if !iterable {
+                    self.runtime_error("Can only iterate over a list, map or instance.");
+                    return Err(());
+                }
+                if !self.invoke("iter", 0) {
+                    return Err(());
+                }
+            }
+            Some(OpCode::IsInstance) => {
+                let Some(class) = Self::as_class(self.peek(0)) else {
+                    self.runtime_error("Right operand of 'is' must be a class.");
+                    return Err(());
+                };
+                let is_instance = match self.peek(1) {
+                    Value::Obj(obj) => match &**obj {
+                        Obj::Instance(instance) => instance.class.inherits_from(&class),
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                self.pop();
+                self.pop();
+                self.push(Value::Bool(is_instance));
+            }
+            Some(OpCode::Method) => {
+                let name = self.read_string()?;
+                self.define_method(&name)?;
+            }
+            Some(OpCode::Getter) => {
+                let name = self.read_string()?;
+                let getter = self.pop();
+                if let Value::Obj(obj) = self.peek(0)
+                    && let Obj::Class(class) = &**obj
+                {
+                    // Whichever kind of member is declared last wins,
+                    // and either kind hides one inherited by that name.
+                    class.methods.borrow_mut().remove(&name);
+                    class.getters.borrow_mut().insert(name, getter);
+                }
+            }
+            Some(OpCode::StaticMethod) => {
+                let name = self.read_string()?;
+                let method = self.pop();
+                if let Value::Obj(obj) = self.peek(0)
+                    && let Obj::Class(class) = &**obj
+                {
+                    class.static_methods.borrow_mut().insert(name, method);
+                }
+            }
+            Some(OpCode::Dup) => {
+                let value = self.peek(0).clone();
+                self.push(value);
+            }
+            Some(OpCode::Swap) => {
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 2);
+            }
+            Some(OpCode::Over) => {
+                let value = self.peek(1).clone();
+                self.push(value);
+            }
+            Some(OpCode::Map) => {
+                self.push(Value::Obj(Rc::new(Obj::Map(Rc::new(Map::default())))));
+            }
+            Some(OpCode::MapEntry) => self.begin_map_op(MapOp::Entry)?,
+            Some(OpCode::Index) => self.index_op(MapOp::Index)?,
+            Some(OpCode::IndexSet) => self.index_op(MapOp::IndexSet)?,
+            Some(OpCode::List) => {
+                self.push(Value::Obj(Rc::new(Obj::List(Rc::new(List::default())))));
+            }
+            Some(OpCode::ListAppend) => {
+                let item = self.pop();
+                if let Value::Obj(obj) = self.peek(0)
+                    && let Obj::List(list) = &**obj
+                {
+                    list.items.borrow_mut().push(item);
+                }
+            }
+            Some(OpCode::Throw) => {
+                let value = self.pop();
+                if !self.throw_value(value) {
+                    return Err(());
                 }
-                Some(OpCode::Method) => {
-                    let name = self.read_string();
-                    self.define_method(&name);
+            }
+            Some(OpCode::PushHandler) => {
+                let offset = self.read_short();
+                let catch_ip = self.frames.last().unwrap().ip + offset as usize;
+                self.handlers.push(ExceptionHandler {
+                    frame_count: self.frames.len(),
+                    stack_height: self.stack.len(),
+                    catch_ip,
+                });
+            }
+            Some(OpCode::PopHandler) => {
+                self.handlers.pop();
+            }
+            Some(OpCode::BitAnd) => {
+                self.bitwise_op(|a, b| a & b)?;
+            }
+            Some(OpCode::BitOr) => {
+                self.bitwise_op(|a, b| a | b)?;
+            }
+            Some(OpCode::BitXor) => {
+                self.bitwise_op(|a, b| a ^ b)?;
+            }
+            Some(OpCode::BitNot) => match Self::bitwise_operand(self.peek(0)) {
+                Some(operand) => *self.peek_mut() = Value::Int(!operand),
+                None if self.peek(0).as_f64().is_some() => {
+                    self.runtime_error("Operand must be a finite number.");
+                    return Err(());
                 }
                 None => {
-                    self.runtime_error(&format!("Unknown opcode: {}", instruction));
+                    self.runtime_error("Operand must be a number.");
                     return Err(());
                 }
+            },
+            Some(OpCode::ShiftLeft) => {
+                self.bitwise_op(|a, b| a.wrapping_shl(b as u32))?;
+            }
+            Some(OpCode::ShiftRight) => {
+                self.bitwise_op(|a, b| a.wrapping_shr(b as u32))?;
+            }
+            None => {
+                self.runtime_error(&format!("Unknown opcode: {}", instruction));
+                return Err(());
             }
         }
+        Ok(None)
     }
 
     fn read_byte(&mut self) -> u8 {
@@ -480,41 +1349,98 @@ impl VM {
         value
     }
 
-    fn read_constant(&mut self) -> Value {
+    fn read_constant(&mut self) -> Result<Value, ()> {
         let idx = self.read_byte() as usize;
         let frame = self.frames.last().unwrap();
-        frame.closure.function.chunk.constants[idx].clone()
+        match frame.closure.function.chunk.constants.get(idx).cloned() {
+            Some(constant) => Ok(constant),
+            None => {
+                self.runtime_error("Constant index out of range.");
+                Err(())
+            }
+        }
     }
 
-    fn read_string(&mut self) -> Rc<str> {
-        match self.read_constant() {
-            Value::Obj(obj) => match &*obj {
-                Obj::String(s) => Rc::clone(s),
-                _ => panic!("Expected string"),
-            },
-            _ => panic!("Expected string"),
+    /// Reads a name operand. Only the string is cloned, not the constant
+    /// holding it, since the name is all the instruction needs.
+    fn read_string(&mut self) -> Result<Rc<str>, ()> {
+        let idx = self.read_byte() as usize;
+        let frame = self.frames.last().unwrap();
+        if let Some(Value::Obj(obj)) = frame.closure.function.chunk.constants.get(idx)
+            && let Obj::String(s) = &**obj
+        {
+            return Ok(Rc::clone(s));
+        }
+        self.runtime_error("Expected string.");
+        Err(())
+    }
+
+    fn binary_op(&mut self, op: fn(&Value, &Value) -> Option<Value>) -> Result<(), ()> {
+        let b = self.pop();
+        let a = self.pop();
+
+        match op(&a, &b) {
+            Some(result) => {
+                self.push(result);
+                Ok(())
+            }
+            None => {
+                self.runtime_error("Operands must be numbers.");
+                Err(())
+            }
         }
     }
 
-    fn binary_op<F>(&mut self, op: F) -> Result<(), ()>
+    /// Compares two numbers or two strings with `Value::compare`. NaN fails
+    /// every test.
+    fn comparison(&mut self, test: fn(Ordering) -> bool) -> Result<(), ()> {
+        let b = self.pop();
+        let a = self.pop();
+
+        let Some(ordering) = a.compare(&b) else {
+            self.runtime_error("Operands must be numbers.");
+            return Err(());
+        };
+        self.push(Value::Bool(ordering.is_some_and(test)));
+        Ok(())
+    }
+
+    /// Applies an integer operation to two numbers as `i64`s.
+    ///
+    /// Floats have their fractional parts dropped, and magnitudes beyond the
+    /// `i64` range saturate. Shift counts wrap modulo 64.
+    fn bitwise_op<F>(&mut self, op: F) -> Result<(), ()>
     where
-        F: FnOnce(f64, f64) -> Value,
+        F: FnOnce(i64, i64) -> i64,
     {
         let b = self.pop();
         let a = self.pop();
 
-        match (a, b) {
-            (Value::Number(a_num), Value::Number(b_num)) => {
-                self.push(op(a_num, b_num));
+        if a.as_f64().is_none() || b.as_f64().is_none() {
+            self.runtime_error("Operands must be numbers.");
+            return Err(());
+        }
+        match (Self::bitwise_operand(&a), Self::bitwise_operand(&b)) {
+            (Some(a), Some(b)) => {
+                self.push(Value::Int(op(a, b)));
                 Ok(())
             }
             _ => {
-                self.runtime_error("Operands must be numbers.");
+                self.runtime_error("Operands must be finite numbers.");
                 Err(())
             }
         }
     }
 
+    /// A number as a bitwise operand, or `None` for infinities and NaN.
+    fn bitwise_operand(value: &Value) -> Option<i64> {
+        match value {
+            Value::Int(i) => Some(*i),
+            Value::Number(n) if n.is_finite() => Some(*n as i64),
+            _ => None,
+        }
+    }
+
     fn call_value(&mut self, callee: Value, arg_count: usize) -> bool {
         match callee {
             Value::Obj(obj) => match &*obj {
@@ -522,36 +1448,26 @@ impl VM {
                     let receiver = bound.receiver.clone();
                     let stack_len = self.stack.len();
                     self.stack[stack_len - arg_count - 1] = receiver;
-                    self.call(&bound.method, arg_count)
+                    self.call(Rc::clone(&bound.method), arg_count)
                 }
                 Obj::Class(class) => {
-                    let instance = Instance {
-                        class: Rc::downgrade(class),
-                        fields: RefCell::new(HashMap::new()),
-                    };
+                    let instance = Instance::new(class);
                     let stack_len = self.stack.len();
                     self.stack[stack_len - arg_count - 1] =
                         Value::Obj(Rc::new(Obj::Instance(Rc::new(instance))));
 
-                    if let Some(initializer) = class.methods.borrow().get(&self.init_string) {
-                        if let Value::Obj(obj) = initializer
-                            && let Obj::Closure(closure) = &**obj
-                        {
-                            return self.call(closure, arg_count);
-                        }
+                    if let Some(initializer) = class.find_method(&self.init_string) {
+                        return self.call(initializer, arg_count);
                     } else if arg_count != 0 {
                         self.runtime_error(&format!("Expected 0 arguments but got {}.", arg_count));
                         return false;
                     }
                     true
                 }
-                Obj::Closure(closure) => self.call(closure, arg_count),
+                Obj::Closure(closure) => self.call(Rc::clone(closure), arg_count),
                 Obj::Native(native) => {
-                    let args_start = self.stack.len() - arg_count;
-                    let result = (native.function)(arg_count, &self.stack[args_start..]);
-                    self.stack.truncate(args_start - 1);
-                    self.push(result);
-                    true
+                    let step = self.start_native(native, arg_count);
+                    self.run_native(step, Vec::new())
                 }
                 _ => {
                     self.runtime_error("Can only call functions and classes.");
@@ -565,7 +1481,149 @@ impl VM {
         }
     }
 
-    fn call(&mut self, closure: &Rc<Closure>, arg_count: usize) -> bool {
+    /// Stores `value` in a global slot, first recording the change if the
+    /// global is watched.
+    /// Reports an assignment to a global that is undefined, or whose latest
+    /// definition was a `const` one. The compiler catches the latter when
+    /// it already knows, but not when the function assigning it was
+    /// compiled before the `const` declaration.
+    fn check_global_assignable(&mut self, slot: usize) -> Result<(), ()> {
+        let message = if !self.globals.is_defined(slot) {
+            "Undefined variable"
+        } else if self.globals.is_const(slot) {
+            "Cannot assign to constant"
+        } else {
+            return Ok(());
+        };
+        let name = Rc::clone(self.globals.name(slot));
+        self.runtime_error(&format!("{} '{}'.", message, name));
+        Err(())
+    }
+
+    fn assign_global(&mut self, slot: usize, value: Value) {
+        if self.globals.is_watched(slot) {
+            let frame = self.frames.last().unwrap();
+            self.global_change = Some(GlobalChange {
+                name: Rc::clone(self.globals.name(slot)),
+                old: self.globals.get(slot).cloned(),
+                new: value.clone(),
+                line: frame.closure.function.chunk.lines[frame.ip - 1],
+            });
+        }
+        self.globals.define(slot, value);
+    }
+
+    /// Converts `value` to a string the way `print` shows it, calling the
+    /// `toString()` method of an instance that has one.
+    pub fn stringify(&mut self, value: &Value) -> Result<NativeStep, NativeError> {
+        if let Some(method) = Self::instance_method(value, "toString") {
+            let bound = Rc::new(BoundMethod {
+                receiver: value.clone(),
+                method,
+            });
+            let callee = Value::Obj(Rc::new(Obj::BoundMethod(bound)));
+            return Ok(NativeStep::call(callee, Vec::new(), |_, result| {
+                if !result.is_string() {
+                    return Err("toString() must return a string.".to_string().into());
+                }
+                Ok(NativeStep::Done(result))
+            }));
+        }
+        let text = value.display(self.number_precision).to_string();
+        Ok(NativeStep::Done(self.new_string(&text)))
+    }
+
+    /// Calls `native` with the arguments on top of the stack, leaving its
+    /// callee slot for `run_native` to put the result in.
+    fn start_native(
+        &mut self,
+        native: &Native,
+        arg_count: usize,
+    ) -> Result<NativeStep, NativeError> {
+        if !(native.arity..=native.max_arity).contains(&arg_count) {
+            let expected = if native.max_arity == native.arity {
+                native.arity.to_string()
+            } else {
+                format!("{} to {}", native.arity, native.max_arity)
+            };
+            return Err(format!("Expected {} arguments but got {}.", expected, arg_count).into());
+        }
+
+        // The arguments leave the stack so the native can borrow the VM
+        // along with them.
+        let args_start = self.stack.len() - arg_count;
+        let args: Vec<Value> = self.stack.drain(args_start..).collect();
+        match native.function {
+            NativeFunction::Plain(function) => function(self, &args).map(NativeStep::Done),
+            NativeFunction::Stepped(function) => function(self, &args),
+        }
+    }
+
+    /// Carries out `step` of the innermost of a chain of natives, whose
+    /// callee slot is on top of the stack. `waiting` holds the
+    /// continuations of the natives that called it, innermost last.
+    ///
+    /// A finished native's result replaces its callee slot; if another
+    /// native is waiting on it, the slot is popped again and that native
+    /// goes on with the result. A call to another native starts it right
+    /// here, as the new innermost. A call to Lox code pushes a frame whose
+    /// `ReturnAction::Resume` brings the result back to this function once
+    /// the run loop has run it, so no Lox code runs on the Rust stack
+    /// while a native is in progress, however deeply callbacks nest.
+    fn run_native(
+        &mut self,
+        mut step: Result<NativeStep, NativeError>,
+        mut waiting: Vec<Continuation>,
+    ) -> bool {
+        loop {
+            step = match step {
+                Ok(NativeStep::Done(result)) => {
+                    let Some(then) = waiting.pop() else {
+                        *self.stack.last_mut().unwrap() = result;
+                        return true;
+                    };
+                    self.pop();
+                    then(self, result)
+                }
+                Ok(NativeStep::Call { callee, args, then }) => {
+                    waiting.push(then);
+                    let arg_count = args.len();
+                    self.push(callee.clone());
+                    self.stack.extend(args);
+
+                    let native = match &callee {
+                        Value::Obj(obj) => match &**obj {
+                            Obj::Native(native) => Some(Rc::clone(native)),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    if let Some(native) = native {
+                        self.start_native(&native, arg_count)
+                    } else {
+                        let frame_count = self.frames.len();
+                        if !self.call_value(callee, arg_count) {
+                            return false;
+                        }
+                        // A class without an initializer is done already.
+                        if self.frames.len() == frame_count {
+                            Ok(NativeStep::Done(self.peek(0).clone()))
+                        } else {
+                            let frame = self.frames.last_mut().unwrap();
+                            frame.on_return = ReturnAction::Resume(Waiting(waiting));
+                            return true;
+                        }
+                    }
+                }
+                Err(NativeError::Message(message)) => {
+                    self.runtime_error(&message);
+                    return false;
+                }
+            };
+        }
+    }
+
+    fn call(&mut self, closure: Rc<Closure>, arg_count: usize) -> bool {
         if arg_count != closure.function.arity {
             self.runtime_error(&format!(
                 "Expected {} arguments but got {}.",
@@ -574,94 +1632,398 @@ impl VM {
             return false;
         }
 
-        if self.frames.len() >= FRAMES_MAX {
+        if self.frames.len() >= self.frames_max || self.stack.len() > self.stack_max {
             self.runtime_error("Stack overflow.");
             return false;
         }
 
         self.frames.push(CallFrame {
-            closure: Rc::clone(closure),
+            closure,
             ip: 0,
             slot_offset: self.stack.len() - arg_count - 1,
+            on_return: ReturnAction::Keep,
         });
 
         true
     }
 
+    /// The closure a `TailCall` can run in place of the current frame: one
+    /// called with the right number of arguments, directly or as a bound
+    /// method, from a frame without an exception handler of its own. Other
+    /// calls go through `call_value`, and the `Return` after them runs.
+    fn tail_call_target(&mut self, callee_slot: usize, arg_count: usize) -> Option<Rc<Closure>> {
+        if self
+            .handlers
+            .last()
+            .is_some_and(|handler| handler.frame_count == self.frames.len())
+        {
+            return None;
+        }
+
+        let Value::Obj(obj) = &self.stack[callee_slot] else {
+            return None;
+        };
+        let (closure, receiver) = match &**obj {
+            Obj::Closure(closure) => (Rc::clone(closure), None),
+            Obj::BoundMethod(bound) => (Rc::clone(&bound.method), Some(bound.receiver.clone())),
+            _ => return None,
+        };
+        if closure.function.arity != arg_count {
+            return None;
+        }
+        if let Some(receiver) = receiver {
+            self.stack[callee_slot] = receiver;
+        }
+        Some(closure)
+    }
+
+    /// Replaces the current frame with a call of `closure`, whose receiver
+    /// and arguments start at `callee_slot`. They move down into the
+    /// frame's window once its captured locals are closed, so the call
+    /// needs neither a new frame nor more stack.
+    fn tail_call(&mut self, closure: Rc<Closure>, callee_slot: usize) {
+        let frame = self.frames.pop().unwrap();
+        self.close_upvalues(frame.slot_offset);
+        self.stack.drain(frame.slot_offset..callee_slot);
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot_offset: frame.slot_offset,
+            on_return: frame.on_return,
+        });
+    }
+
     fn invoke(&mut self, name: &str, arg_count: usize) -> bool {
         let receiver = self.peek(arg_count);
 
-        if !receiver.is_instance() {
-            self.runtime_error("Only instances have methods.");
-            return false;
+        if let Value::Obj(obj) = receiver
+            && let Obj::Class(class) = &**obj
+        {
+            let class = Rc::clone(class);
+            return match self.static_method(&class, name) {
+                Some(method) => self.call_value(method, arg_count),
+                None => false,
+            };
         }
 
-        let instance = match receiver {
-            Value::Obj(obj) => match &**obj {
-                Obj::Instance(inst) => Rc::clone(inst),
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
+        let (receiver, args) = self.stack[self.stack.len() - arg_count - 1..]
+            .split_first()
+            .unwrap();
+        match builtin::builtin_method(receiver, name, args, &mut self.interner) {
+            Some(Ok(result)) => {
+                self.stack.truncate(self.stack.len() - arg_count - 1);
+                self.push(result);
+                return true;
+            }
+            Some(Err(message)) => {
+                self.runtime_error(&message);
+                return false;
+            }
+            None => {}
+        }
+
+        let Some(instance) = Self::as_instance(self.peek(arg_count)) else {
+            self.runtime_error("Only instances have methods.");
+            return false;
         };
 
-        if let Some(value) = instance.fields.borrow().get(name).cloned() {
+        let field_value = instance.fields.borrow().get(name).cloned();
+        if let Some(value) = field_value {
             let idx = self.stack.len() - arg_count - 1;
             self.stack[idx] = value.clone();
             return self.call_value(value, arg_count);
         }
 
-        let class = match instance.class.upgrade() {
-            Some(c) => c,
-            None => {
-                self.runtime_error("Instance's class has been deallocated.");
-                return false;
-            }
+        let class = Rc::clone(&instance.class);
+        self.invoke_from_class(&class, name, arg_count, false)
+    }
+
+    /// Looks up `name` among `class`'s static methods, reporting an error if
+    /// it has none by that name.
+    fn static_method(&mut self, class: &Class, name: &str) -> Option<Value> {
+        let method = class.find_static_method(name);
+        if method.is_none() {
+            self.runtime_error(&format!("Undefined static method '{}'.", name));
+        }
+        method
+    }
+
+    /// Returns the `equals` method to compare `a` and `b` with, when both
+    /// are instances of the same class and that class defines one.
+    fn equals_method(a: &Value, b: &Value) -> Option<Rc<Closure>> {
+        let (Value::Obj(a), Value::Obj(b)) = (a, b) else {
+            return None;
         };
-        self.invoke_from_class(&class, name, arg_count)
+        let (Obj::Instance(a), Obj::Instance(b)) = (&**a, &**b) else {
+            return None;
+        };
+        if !Rc::ptr_eq(&a.class, &b.class) {
+            return None;
+        }
+
+        a.class.find_method("equals")
     }
 
-    fn invoke_from_class(&mut self, class: &Class, name: &str, arg_count: usize) -> bool {
-        match class.methods.borrow().get(name) {
-            Some(Value::Obj(obj)) => match &**obj {
-                Obj::Closure(closure) => self.call(closure, arg_count),
-                _ => {
-                    self.runtime_error(&format!("Undefined property '{}'.", name));
-                    false
+    /// Calls `closure` on the receiver and arguments already on the stack,
+    /// finishing with `on_return` once it returns. Operators implemented in
+    /// Lox code use this to push a frame and let the run loop continue into
+    /// it rather than running the method to completion here.
+    fn call_then(
+        &mut self,
+        closure: Rc<Closure>,
+        arg_count: usize,
+        on_return: ReturnAction,
+    ) -> Result<(), ()> {
+        if !self.call(closure, arg_count) {
+            return Err(());
+        }
+        self.frames.last_mut().unwrap().on_return = on_return;
+        Ok(())
+    }
+
+    /// Runs an index operator on the list, map or instance below its
+    /// operands.
+    fn index_op(&mut self, op: MapOp) -> Result<(), ()> {
+        let list = match self.peek(op.key_depth() + 1) {
+            Value::Obj(obj) => match &**obj {
+                Obj::List(list) => Rc::clone(list),
+                Obj::Instance(instance) => {
+                    let instance = Rc::clone(instance);
+                    return self.index_method(&instance, op);
                 }
+                _ => return self.begin_map_op(op),
             },
-            _ => {
-                self.runtime_error(&format!("Undefined property '{}'.", name));
-                false
+            _ => return self.begin_map_op(op),
+        };
+
+        let value = match op {
+            MapOp::IndexSet => Some(self.pop()),
+            _ => None,
+        };
+        let index = self.pop();
+        let result = match value {
+            Some(value) => builtin::list_index_set(&list, &index, value),
+            None => builtin::list_index(&list, &index),
+        };
+        match result {
+            Ok(value) => {
+                self.pop();
+                self.push(value);
+                Ok(())
+            }
+            Err(message) => {
+                self.runtime_error(&message);
+                Err(())
             }
         }
     }
 
-    fn bind_method(&mut self, class: &Class, name: &str) -> bool {
-        match class.methods.borrow().get(name) {
-            Some(Value::Obj(obj)) => match &**obj {
-                Obj::Closure(closure) => {
-                    let receiver = self.pop();
-                    let bound = BoundMethod {
-                        receiver,
-                        method: Rc::clone(closure),
-                    };
-                    self.push(Value::Obj(Rc::new(Obj::BoundMethod(Rc::new(bound)))));
-                    true
-                }
-                _ => {
-                    self.runtime_error(&format!("Undefined property '{}'.", name));
-                    false
-                }
-            },
-            _ => {
-                self.runtime_error(&format!("Undefined property '{}'.", name));
-                false
+    /// Calls `instance`'s `getIndex(key)` or `setIndex(key, value)` on the
+    /// operands of `op`. `obj[key] = value` evaluates to `value` whatever
+    /// `setIndex` returns, so a copy of it goes under the receiver and the
+    /// method's result is dropped.
+    fn index_method(&mut self, instance: &Instance, op: MapOp) -> Result<(), ()> {
+        let class = Rc::clone(&instance.class);
+        let (name, arg_count, on_return) = match op {
+            MapOp::IndexSet => ("setIndex", 2, ReturnAction::Discard),
+            _ => ("getIndex", 1, ReturnAction::Keep),
+        };
+        let Some(method) = class.find_method(name) else {
+            self.runtime_error(&format!("Undefined property '{}'.", name));
+            return Err(());
+        };
+
+        if matches!(on_return, ReturnAction::Discard) {
+            let value = self.peek(0).clone();
+            self.stack.insert(self.stack.len() - 3, value);
+        }
+        self.call_then(method, arg_count, on_return)
+    }
+
+    /// Starts `op` on the map and key at the top of the stack. A key that
+    /// hashes itself has its `hash()` called first, and the operation is
+    /// finished when that call returns.
+    fn begin_map_op(&mut self, op: MapOp) -> Result<(), ()> {
+        let is_map = matches!(self.peek(op.key_depth() + 1),
+            Value::Obj(obj) if matches!(**obj, Obj::Map(_)));
+        if !is_map {
+            self.runtime_error("Only lists, maps and instances can be indexed.");
+            return Err(());
+        }
+
+        let key = self.peek(op.key_depth()).clone();
+        if let Some(hash) = Self::instance_method(&key, "hash") {
+            self.push(key);
+            return self.call_then(hash, 0, ReturnAction::Map(op));
+        }
+        match HashableValue::new(key) {
+            Some(key) => self.finish_map_op(op, key),
+            None => {
+                self.runtime_error("Map key must be hashable.");
+                Err(())
+            }
+        }
+    }
+
+    /// Calls `to_string` on the instance `depth` slots below the top, then
+    /// runs the current instruction, which starts at `start`, again with the
+    /// string it returns in the instance's place.
+    fn stringify_and_retry(
+        &mut self,
+        to_string: Rc<Closure>,
+        depth: usize,
+        start: usize,
+    ) -> Result<(), ()> {
+        self.frames.last_mut().unwrap().ip = start;
+        let receiver = self.peek(depth).clone();
+        self.push(receiver);
+        self.call_then(to_string, 0, ReturnAction::Stringify(depth))
+    }
+
+    /// Completes `op` now that its key has been hashed.
+    fn finish_map_op(&mut self, op: MapOp, key: HashableValue) -> Result<(), ()> {
+        let value = match op {
+            MapOp::Index => Value::Nil,
+            MapOp::IndexSet | MapOp::Entry => self.pop(),
+        };
+        self.pop();
+        let Some(map) = Self::as_map(self.peek(0)) else {
+            self.runtime_error("Expected map.");
+            return Err(());
+        };
+
+        match op {
+            MapOp::Index => {
+                let value = map.entries.borrow().get(&key).cloned();
+                self.pop();
+                self.push(value.unwrap_or(Value::Nil));
+            }
+            MapOp::IndexSet => {
+                map.entries.borrow_mut().insert(key, value.clone());
+                self.pop();
+                self.push(value);
+            }
+            MapOp::Entry => {
+                map.entries.borrow_mut().insert(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the method `name` of an instance whose class defines one.
+    fn instance_method(value: &Value, name: &str) -> Option<Rc<Closure>> {
+        let Value::Obj(obj) = value else {
+            return None;
+        };
+        let Obj::Instance(instance) = &**obj else {
+            return None;
+        };
+        instance.class.find_method(name)
+    }
+
+    /// Calls the method `name` of `class`. `via_super` is set for a
+    /// `super.name(...)` call, which only looks for a superclass method.
+    fn invoke_from_class(
+        &mut self,
+        class: &Class,
+        name: &str,
+        arg_count: usize,
+        via_super: bool,
+    ) -> bool {
+        if class.find_getter(name).is_some() {
+            self.runtime_error(&format!("Can't call getter '{}' as a method.", name));
+            return false;
+        }
+
+        match class.find_method(name) {
+            Some(method) => self.call(method, arg_count),
+            None => self.undefined_method(class, name, via_super, arg_count),
+        }
+    }
+
+    fn bind_method(&mut self, class: &Class, name: &str, via_super: bool) -> bool {
+        match class.find_method(name) {
+            Some(method) => {
+                self.push_bound_method(method);
+                true
             }
+            None => self.undefined_method(class, name, via_super, 0),
         }
     }
 
+    /// Reports a failed method lookup on an instance of `class` that sits
+    /// `depth` slots below the top of the stack, suggesting a close match
+    /// among its methods, getters and fields. Through `super` only methods
+    /// are looked up, never fields, so the error says so.
+    fn undefined_method(
+        &mut self,
+        class: &Class,
+        name: &str,
+        via_super: bool,
+        depth: usize,
+    ) -> bool {
+        let mut candidates = class.method_names();
+        let message = if via_super {
+            format!("Undefined superclass method '{}'.", name)
+        } else {
+            if let Some(instance) = Self::as_instance(self.peek(depth)) {
+                candidates.extend(instance.fields.borrow().keys().cloned());
+            }
+            format!("Undefined property '{}'.", name)
+        };
+        let candidates = candidates.iter().map(|candidate| candidate.as_ref());
+        let message = builtin::with_suggestion(message, name, candidates);
+        self.runtime_error(&message);
+        false
+    }
+
+    /// Binds like `bind_method`, going through the inline cache of the
+    /// executing `GetProperty` site and refilling it on a miss.
+    fn bind_cached_method(&mut self, class: &Rc<Class>, name: &str, cache_index: usize) -> bool {
+        let cached = {
+            let chunk = &self.frames.last().unwrap().closure.function.chunk;
+            let cache = &chunk.property_caches.borrow()[cache_index];
+            if std::ptr::eq(cache.class.as_ptr(), Rc::as_ptr(class)) {
+                cache.method.clone()
+            } else {
+                None
+            }
+        };
+
+        let method = match cached {
+            Some(method) => method,
+            None => {
+                let Some(method) = class.find_method(name) else {
+                    return self.undefined_method(class, name, false, 0);
+                };
+
+                let chunk = &self.frames.last().unwrap().closure.function.chunk;
+                chunk.property_caches.borrow_mut()[cache_index] = PropertyCache {
+                    class: Rc::downgrade(class),
+                    method: Some(Rc::clone(&method)),
+                };
+                method
+            }
+        };
+
+        self.push_bound_method(method);
+        true
+    }
+
+    fn push_bound_method(&mut self, method: Rc<Closure>) {
+        let receiver = self.pop();
+        let bound = BoundMethod { receiver, method };
+        self.push(Value::Obj(Rc::new(Obj::BoundMethod(Rc::new(bound)))));
+    }
+
     fn capture_upvalue(&mut self, stack_index: usize) -> Rc<RefCell<Upvalue>> {
-        if let Some(upvalue) = self.open_upvalues.get(&stack_index) {
+        let position = self
+            .open_upvalues
+            .partition_point(|upvalue| upvalue.borrow().location < stack_index);
+
+        if let Some(upvalue) = self.open_upvalues.get(position)
+            && upvalue.borrow().location == stack_index
+        {
             return Rc::clone(upvalue);
         }
 
@@ -669,40 +2031,91 @@ impl VM {
             location: stack_index,
             closed: None,
         }));
-        self.open_upvalues.insert(stack_index, Rc::clone(&upvalue));
+        self.open_upvalues.insert(position, Rc::clone(&upvalue));
         upvalue
     }
 
+    /// Closes every open upvalue at or above stack slot `last`. Since the
+    /// list is sorted, those are exactly the ones at its end.
     fn close_upvalues(&mut self, last: usize) {
-        let to_close: Vec<usize> = self
-            .open_upvalues
-            .keys()
-            .filter(|&&location| location >= last)
-            .copied()
-            .collect();
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let location = upvalue.borrow().location;
+            if location < last {
+                break;
+            }
+            // Only corrupt bytecode leaves an upvalue open above the top.
+            let value = self.stack.get(location).cloned().unwrap_or(Value::Nil);
+            upvalue.borrow_mut().closed = Some(value);
+            self.open_upvalues.pop();
+        }
+    }
 
-        to_close.into_iter().for_each(|location| {
-            if let Some(upvalue) = self.open_upvalues.remove(&location) {
-                let mut up = upvalue.borrow_mut();
-                up.closed = Some(self.stack[up.location].clone());
+    /// Unwinds to the innermost handler and resumes at its `catch` block with
+    /// `value` bound to the catch variable.
+    fn throw_value(&mut self, value: Value) -> bool {
+        let handler = match self.handlers.pop() {
+            Some(handler) => handler,
+            None => {
+                self.runtime_error(&format!("Uncaught exception: {}", value));
+                return false;
             }
-        });
+        };
+
+        self.frames.truncate(handler.frame_count);
+        self.close_upvalues(handler.stack_height);
+        // `bytecode::check_function` starts the `catch` block with exactly
+        // this many values, which a loaded file can have popped below.
+        self.stack.resize(handler.stack_height, Value::Nil);
+        self.push(value);
+        self.frames.last_mut().unwrap().ip = handler.catch_ip;
+        true
     }
 
-    fn define_method(&mut self, name: &Rc<str>) {
+    fn define_method(&mut self, name: &Rc<str>) -> Result<(), ()> {
         let method = self.pop();
-        let class_rc = match self.peek(0) {
-            Value::Obj(obj) => match &**obj {
-                Obj::Class(c) => c,
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
+        let Some(class_rc) = Self::as_class(self.peek(0)) else {
+            self.runtime_error("Expected class.");
+            return Err(());
         };
 
+        class_rc.getters.borrow_mut().remove(name);
         class_rc
             .methods
             .borrow_mut()
             .insert(Rc::clone(name), method);
+        Ok(())
+    }
+
+    /// Borrows rather than clones the instance, so an instruction that only
+    /// reads it costs no refcount bump.
+    fn as_instance(value: &Value) -> Option<&Instance> {
+        match value {
+            Value::Obj(obj) => match &**obj {
+                Obj::Instance(instance) => Some(instance),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn as_map(value: &Value) -> Option<Rc<Map>> {
+        match value {
+            Value::Obj(obj) => match &**obj {
+                Obj::Map(map) => Some(Rc::clone(map)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn as_class(value: &Value) -> Option<Rc<Class>> {
+        match value {
+            Value::Obj(obj) => match &**obj {
+                Obj::Class(class) => Some(Rc::clone(class)),
+                _ => None,
+            },
+            _ => None,
+        }
     }
 
     fn push(&mut self, value: Value) {
@@ -713,34 +2126,105 @@ impl VM {
         self.stack.pop().expect("Stack underflow")
     }
 
+    /// The top of the stack, for instructions that replace their operand.
+    fn peek_mut(&mut self) -> &mut Value {
+        self.stack.last_mut().expect("Stack underflow")
+    }
+
     fn peek(&self, distance: usize) -> &Value {
         &self.stack[self.stack.len() - 1 - distance]
     }
 
+    /// For `a + b` where one side is a string and the other an instance with
+    /// a `toString` method, returns that method and the instance's depth.
+    fn to_string_operand(a: &Value, b: &Value) -> Option<(Rc<Closure>, usize)> {
+        if a.is_string() {
+            Some((Self::instance_method(b, "toString")?, 0))
+        } else if b.is_string() {
+            Some((Self::instance_method(a, "toString")?, 1))
+        } else {
+            None
+        }
+    }
+
+    /// Joins the operands of `+` when at least one is a string. A number,
+    /// boolean or nil on the other side is converted the way `print` shows
+    /// it; any other object can't be concatenated.
+    fn concatenate(a: &Value, b: &Value, precision: usize) -> Option<String> {
+        fn text(value: &Value, precision: usize) -> Option<Cow<'_, str>> {
+            match value {
+                Value::Obj(obj) => match &**obj {
+                    Obj::String(s) => Some(Cow::Borrowed(s)),
+                    _ => None,
+                },
+                _ => Some(Cow::Owned(value.display(precision).to_string())),
+            }
+        }
+
+        if !a.is_string() && !b.is_string() {
+            return None;
+        }
+        let (a, b) = (text(a, precision)?, text(b, precision)?);
+        let mut result = String::with_capacity(a.len() + b.len());
+        result.push_str(&a);
+        result.push_str(&b);
+        Some(result)
+    }
+
+    /// Kept out of line: most instructions can fail, and inlining this into
+    /// each of their error paths slows down the run loop.
+    #[cold]
+    #[inline(never)]
     fn runtime_error(&mut self, message: &str) {
-        use std::io::Write;
-        std::io::stdout().flush().ok();
+        if !self.handlers.is_empty() {
+            let message = self.interner.intern(message);
+            self.pending_exception = Some(Value::Obj(Rc::new(Obj::String(message))));
+            return;
+        }
 
+        self.output.flush().ok();
         eprintln!("{}", message);
 
-        self.frames.iter().rev().for_each(|frame| {
-            let function = &frame.closure.function;
-            let instruction = frame.ip - 1;
-            eprint!("[line {}] in ", function.chunk.lines[instruction]);
-            if let Some(name) = &function.name {
-                eprintln!("{}()", name);
-            } else {
-                eprintln!("script");
-            }
-        });
+        let omitted = self
+            .frames
+            .len()
+            .saturating_sub(TRACE_HEAD_FRAMES + TRACE_TAIL_FRAMES);
+        self.frames
+            .iter()
+            .rev()
+            .enumerate()
+            .for_each(|(depth, frame)| {
+                if omitted > 0 && depth == TRACE_HEAD_FRAMES {
+                    eprintln!("... {} frames omitted ...", omitted);
+                }
+                if (TRACE_HEAD_FRAMES..TRACE_HEAD_FRAMES + omitted).contains(&depth) {
+                    return;
+                }
+
+                let function = &frame.closure.function;
+                // A corrupt jump can leave `ip` past the end of the code.
+                let lines = &function.chunk.lines;
+                let line = lines.get(frame.ip.saturating_sub(1)).or(lines.last());
+                eprint!("[line {}] in ", line.copied().unwrap_or(0));
+                if let Some(name) = &function.name {
+                    eprintln!("{}()", name);
+                } else {
+                    eprintln!("script");
+                }
+            });
 
         self.reset_stack();
     }
 
     fn reset_stack(&mut self) {
+        // Closures that escaped into globals outlive the aborted script, so
+        // their upvalues must stop pointing at slots that are about to be
+        // reused by the next one.
+        self.close_upvalues(0);
         self.stack.clear();
         self.frames.clear();
-        self.open_upvalues.clear();
+        self.handlers.clear();
+        self.pending_exception = None;
     }
 }
 
@@ -749,3 +2233,53 @@ impl Default for VM {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{InterpretResult, StepResult, VM};
+    use std::rc::Rc;
+
+    #[test]
+    fn stepping_without_a_running_script_does_nothing() {
+        let mut vm = VM::new();
+        assert!(matches!(vm.step(), StepResult::NotRunning));
+        assert!(matches!(vm.continue_run(), StepResult::NotRunning));
+
+        let script = vm.compile("var x = 1;").expect("the source compiles");
+        assert!(vm.begin(script));
+        while let StepResult::Continue { .. } = vm.step() {}
+        assert!(matches!(vm.step(), StepResult::NotRunning));
+        assert!(matches!(vm.continue_run(), StepResult::NotRunning));
+    }
+
+    #[test]
+    fn begin_fails_for_a_script_that_cannot_be_called() {
+        let mut vm = VM::new();
+        let script = vm.compile("var x = 1;").expect("the source compiles");
+        let mut script = (*script).clone();
+        script.arity = 1;
+
+        assert!(!vm.begin(Rc::new(script)));
+        assert!(matches!(vm.step(), StepResult::NotRunning));
+        assert_eq!(vm.current_line(), None);
+    }
+
+    /// Each REPL line is compiled on its own, so only the shared global
+    /// table can tell a later line that a name is constant.
+    #[test]
+    fn a_const_global_stays_constant_for_later_lines() {
+        let mut vm = VM::new();
+        assert!(matches!(vm.interpret("const X = 1;"), InterpretResult::Ok));
+        assert!(matches!(
+            vm.interpret("X = 2;"),
+            InterpretResult::CompileError(_)
+        ));
+        assert!(matches!(
+            vm.interpret("fun f() { X = 3; }"),
+            InterpretResult::CompileError(_)
+        ));
+
+        assert!(matches!(vm.interpret("var X = 4;"), InterpretResult::Ok));
+        assert!(matches!(vm.interpret("X = 5;"), InterpretResult::Ok));
+    }
+}